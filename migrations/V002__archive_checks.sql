@@ -0,0 +1 @@
+alter table checks add column archived_at integer;
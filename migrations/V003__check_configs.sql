@@ -0,0 +1,11 @@
+create table check_configs (
+    id integer primary key autoincrement,
+    check_id integer not null,
+    hash text not null,
+    config text not null,
+    created_at integer not null default (CAST(strftime('%s', 'now') AS INTEGER)),
+    FOREIGN KEY(check_id) REFERENCES checks(id)
+);
+create unique index idx_check_configs_hash on check_configs(check_id, hash);
+
+alter table results add column config_id integer references check_configs(id);
@@ -0,0 +1,3 @@
+-- the source of results which were imported from another dialer. null for local results.
+alter table results add column source text;
+create index idx_results_check_epoch on results(check_id, epoch);
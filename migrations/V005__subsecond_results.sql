@@ -0,0 +1,21 @@
+-- results are timestamped with the start of the probe in milliseconds and latencies are recorded
+-- in microseconds. existing rows are converted from whole seconds and milliseconds.
+create table results_new (
+    id integer primary key autoincrement,
+    check_id integer not null,
+    config_id integer references check_configs(id),
+    epoch_ms integer not null default (CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)),
+    us integer,
+    err text,
+    source text,
+    FOREIGN KEY(check_id) REFERENCES checks(id)
+);
+
+insert into results_new (id, check_id, config_id, epoch_ms, us, err, source)
+select id, check_id, config_id, epoch * 1000, ms * 1000, err, source from results;
+
+drop table results;
+alter table results_new rename to results;
+
+create index idx_results_epoch_ms on results(epoch_ms);
+create index idx_results_check_epoch_ms on results(check_id, epoch_ms);
@@ -0,0 +1,20 @@
+create table incidents (
+    id integer primary key autoincrement,
+    -- the time of the first failure
+    start_ms integer not null,
+    -- the time at which every affected check recovered, or null while ongoing
+    end_ms integer,
+    -- the most common error during the incident
+    err text,
+    failures integer not null
+);
+create index idx_incidents_start_ms on incidents(start_ms);
+
+create table incident_checks (
+    incident_id integer not null,
+    check_id integer not null,
+    failures integer not null,
+    primary key (incident_id, check_id),
+    FOREIGN KEY(incident_id) REFERENCES incidents(id),
+    FOREIGN KEY(check_id) REFERENCES checks(id)
+);
@@ -0,0 +1,8 @@
+create table check_tags (
+    check_id integer not null,
+    key text not null,
+    value text not null,
+    primary key (check_id, key),
+    FOREIGN KEY(check_id) REFERENCES checks(id)
+);
+create index idx_check_tags_key_value on check_tags(key, value);
@@ -0,0 +1,5 @@
+-- the id of the last result which was pushed to each push target
+create table push_cursors (
+    name text primary key,
+    result_id integer not null
+);
@@ -0,0 +1,7 @@
+create table managed_checks (
+    check_id integer primary key,
+    definition text not null,
+    FOREIGN KEY(check_id) REFERENCES checks(id)
+);
+
+alter table checks add column paused_at integer;
@@ -0,0 +1,6 @@
+-- the time before which every result has been scanned for incidents, so that each pass only reads
+-- the recent results. it has a single row once the first pass has finished.
+create table incident_scan (
+    id integer primary key check (id = 1),
+    scanned_ms integer not null
+);
@@ -15,8 +15,8 @@ if [[ "ok" ]];then
 				r.check_id,
 				c.name,
 				c.kind,
-				r.epoch,
-				r.ms,
+				r.epoch_ms,
+				r.us,
 				r.err
 			FROM results r
 			JOIN checks c on r.check_id = c.id
@@ -40,11 +40,11 @@ if [[ "ok" ]];then
 				r.check_id,
 				c.name,
 				c.kind,
-				r.epoch / p.rollup * p.rollup AS bucket,
-				datetime(r.epoch / p.rollup * p.rollup, 'unixepoch') as time,
-				CAST(MIN(r.ms) as INTEGER) AS min,
-				CAST(AVG(r.ms) as INTEGER) AS avg,
-				CAST(MAX(r.ms) as INTEGER) AS max,
+				r.epoch_ms / 1000 / p.rollup * p.rollup AS bucket,
+				datetime(r.epoch_ms / 1000 / p.rollup * p.rollup, 'unixepoch') as time,
+				MIN(r.us) / 1000.0 AS min,
+				AVG(r.us) / 1000.0 AS avg,
+				MAX(r.us) / 1000.0 AS max,
 				COUNT(*) AS count,
 				COUNT(r.err) AS errs
 			FROM results r
@@ -0,0 +1,30 @@
+#!/usr/bin/env bash
+# downloads the third party js and css into html/vendor so that they are embedded in the binary
+# instead of being loaded from a cdn. keep the urls in sync with VENDOR in src/web/assets.rs.
+#
+# the templates pin some of the files with a subresource integrity digest. a download which does
+# not match its digest fails the script, since browsers would refuse to load it.
+
+set -euo pipefail
+
+cd "$(dirname "$0")/../html"
+mkdir -p vendor
+
+fetch() {
+    curl -fsSL --retry 3 -o "vendor/$1" "$2"
+    local want got
+    want=$(grep -ho "\"$1\"|vendor|safe }}\"[^>]*integrity=\"sha384-[^\"]*\"" ../templates/*.html \
+        | grep -o 'sha384-[^"]*' | sort -u || true)
+    [ -z "$want" ] && return
+    got="sha384-$(openssl dgst -sha384 -binary "vendor/$1" | openssl base64 -A)"
+    if [ "$got" != "$want" ]; then
+        rm "vendor/$1"
+        echo "vendor/$1 has the digest $got but the templates expect $want" >&2
+        exit 1
+    fi
+}
+
+fetch bootstrap.min.css https://cdn.jsdelivr.net/npm/bootstrap@5.3.3/dist/css/bootstrap.min.css
+fetch bootstrap.bundle.min.js https://cdn.jsdelivr.net/npm/bootstrap@5.3.3/dist/js/bootstrap.bundle.min.js
+fetch htmx.min.js https://unpkg.com/htmx.org@2.0.2/dist/htmx.min.js
+fetch d3.min.js https://cdnjs.cloudflare.com/ajax/libs/d3/7.8.5/d3.min.js
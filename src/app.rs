@@ -1,30 +1,63 @@
 use crate::{
+    backup,
     checker::{self, Checker},
     config,
     db::Db,
+    incident, push, reload,
     web::Server,
 };
 use anyhow::{anyhow, bail, Result};
+use std::{collections::HashMap, path::PathBuf};
 use tokio::task::JoinSet;
 
 #[derive(Clone)]
 pub struct App {
     api: Server,
     checker: checker::Checker,
+    db: Db,
+    /// the config file, which is reloaded when it changes
+    config_path: PathBuf,
+    config: config::Config,
+    reload: reload::State,
+    backup: config::Backup,
+    incidents: config::Incidents,
+    push: HashMap<String, config::Push>,
 }
 
 impl App {
-    pub async fn new(config: &config::Config) -> Result<Self> {
+    pub async fn new(config: &config::Config, config_path: PathBuf) -> Result<Self> {
         let db = Db::connect(&config.db_path).await?;
         let checker = Checker::new(db.clone(), config).await?;
-        let api = Server::new(config, db.clone())?;
-        Ok(Self { api, checker })
+        let reload = reload::State::default();
+        let api = Server::new(config, db.clone(), checker.clone(), reload.clone())?;
+        let backup = config.backup.clone();
+        let incidents = config.incidents.clone();
+        let push = config.push.clone();
+        Ok(Self {
+            api,
+            checker,
+            db,
+            config_path,
+            config: config.clone(),
+            reload,
+            backup,
+            incidents,
+            push,
+        })
     }
 
     pub async fn run(&self) -> Result<()> {
         let mut js = JoinSet::new();
         js.spawn(self.clone().run_checker());
         js.spawn(self.clone().run_api());
+        js.spawn(self.clone().run_incidents());
+        js.spawn(self.clone().run_reload());
+        for name in self.push.keys() {
+            js.spawn(self.clone().run_push(name.clone()));
+        }
+        if self.backup.dir.is_some() {
+            js.spawn(self.clone().run_backups());
+        }
         match js.join_next().await {
             Some(Ok(err)) => bail!(err),
             Some(Err(je)) => bail!("panic! {je:#}"),
@@ -39,6 +72,36 @@ impl App {
         }
     }
 
+    async fn run_backups(self) -> anyhow::Error {
+        match backup::run_scheduled(self.db, self.backup).await {
+            Ok(()) => anyhow!("backups quit unexpectedly"),
+            Err(err) => err.context("backups failed"),
+        }
+    }
+
+    async fn run_incidents(self) -> anyhow::Error {
+        match incident::run(self.db, self.incidents).await {
+            Ok(()) => anyhow!("incident detection quit unexpectedly"),
+            Err(err) => err.context("incident detection failed"),
+        }
+    }
+
+    async fn run_reload(self) -> anyhow::Error {
+        match reload::run(self.config_path, self.config, self.checker, self.reload).await {
+            Ok(()) => anyhow!("config reload quit unexpectedly"),
+            Err(err) => err.context("config reload failed"),
+        }
+    }
+
+    async fn run_push(self, name: String) -> anyhow::Error {
+        let config = self.push[&name].clone();
+        let stats = self.checker.stats();
+        match push::run(self.db, stats, name.clone(), config).await {
+            Ok(()) => anyhow!("push to '{name}' quit unexpectedly"),
+            Err(err) => err.context(format!("push to '{name}' failed")),
+        }
+    }
+
     async fn run_checker(self) -> anyhow::Error {
         match self.checker.run().await {
             Ok(()) => anyhow!("checker quit unexpectedly"),
@@ -0,0 +1,221 @@
+//! takes consistent snapshots of the db with the sqlite online backup api while the checker keeps
+//! writing to it.
+use crate::{config, db};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use flate2::{write::GzEncoder, Compression};
+use rusqlite::{backup::StepResult, Connection};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::instrument;
+
+/// the prefix of the files written by scheduled backups. only files with this prefix are rotated.
+const PREFIX: &str = "dialer-";
+
+/// writes a snapshot of the db to the specified path, optionally gzipped. the snapshot is first
+/// written next to the destination and then renamed so that a partial backup is never observed.
+pub fn snapshot(conn: &Connection, path: &Path, compress: bool) -> Result<()> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty());
+    let dir = dir.unwrap_or(Path::new("."));
+    let tmp = tempfile::NamedTempFile::new_in(dir).context("create temp file")?;
+    {
+        let mut dst = Connection::open(tmp.path()).context("open temp db")?;
+        let backup = rusqlite::backup::Backup::new(conn, &mut dst)?;
+        // copy all pages in a single step. this reads from a single transaction so the snapshot
+        // is consistent, whereas copying incrementally restarts whenever the checker writes.
+        loop {
+            match backup.step(-1)? {
+                StepResult::Done => break,
+                // busy or locked by a writer, try again shortly
+                _ => std::thread::sleep(Duration::from_millis(50)),
+            }
+        }
+    }
+    if compress {
+        let compressed = tempfile::NamedTempFile::new_in(dir).context("create temp file")?;
+        let mut reader = BufReader::new(File::open(tmp.path())?);
+        let mut encoder =
+            GzEncoder::new(BufWriter::new(compressed.as_file()), Compression::default());
+        io::copy(&mut reader, &mut encoder).context("compress backup")?;
+        encoder.finish()?.flush()?;
+        compressed
+            .persist(path)
+            .with_context(|| format!("write {}", path.display()))?;
+    } else {
+        tmp.persist(path)
+            .with_context(|| format!("write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// writes a snapshot of the db to the specified path.
+pub async fn to_path(db: &db::Db, path: PathBuf, compress: bool) -> Result<()> {
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || {
+        let conn = db.conn().context("get conn")?;
+        snapshot(&conn, &path, compress)
+    })
+    .await
+    .context("blocking thread panicked")?
+}
+
+/// takes a backup into the configured directory on each interval, keeping only the most recent.
+#[instrument(skip_all)]
+pub async fn run_scheduled(db: db::Db, config: config::Backup) -> Result<()> {
+    let Some(dir) = config.dir.clone() else {
+        bail!("no backup dir configured");
+    };
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("create backup dir {}", dir.display()))?;
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let ext = if config.compress { "db.gz" } else { "db" };
+        let name = format!("{PREFIX}{}.{ext}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+        let path = dir.join(name);
+        match to_path(&db, path.clone(), config.compress).await {
+            Ok(()) => tracing::info!("Wrote backup to {}", path.display()),
+            Err(err) => {
+                tracing::error!("backup failed: {err:?}");
+                continue;
+            }
+        }
+        let (dir, keep) = (dir.clone(), config.keep);
+        match tokio::task::spawn_blocking(move || rotate(&dir, keep)).await? {
+            Ok(()) => {}
+            Err(err) => tracing::error!("backup rotation failed: {err:?}"),
+        }
+    }
+}
+
+/// deletes all but the most recent backups in the dir. backups are named by their timestamp so
+/// the most recent sort last.
+fn rotate(dir: &Path, keep: usize) -> Result<()> {
+    let mut backups = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with(PREFIX) && entry.file_type()?.is_file() {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+    let remove = backups.len().saturating_sub(keep);
+    for path in &backups[..remove] {
+        tracing::info!("Removing old backup {}", path.display());
+        fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+
+    async fn db() -> db::Db {
+        let db = db::Db::in_memory().await.unwrap();
+        db.with_conn(|conn| {
+            conn.execute_batch(
+                "
+                insert into checks (name, kind) values ('google', 'ping');
+                insert into results (check_id, us) values (1, 10000), (1, 20000);
+                ",
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        db
+    }
+
+    fn count(path: &Path) -> u64 {
+        let conn = Connection::open(path).unwrap();
+        conn.query_row("select count(*) from results", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let db = db().await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("backup.db");
+        to_path(&db, path.clone(), false).await.unwrap();
+        assert_eq!(count(&path), 2);
+
+        let gz = dir.path().join("backup.db.gz");
+        to_path(&db, gz.clone(), true).await.unwrap();
+        let unzipped = dir.path().join("unzipped.db");
+        let mut decoder = GzDecoder::new(File::open(&gz).unwrap());
+        io::copy(&mut decoder, &mut File::create(&unzipped).unwrap()).unwrap();
+        assert_eq!(count(&unzipped), 2);
+
+        // no temp files are left behind
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["backup.db", "backup.db.gz", "unzipped.db"]);
+    }
+
+    #[tokio::test]
+    async fn scheduled() {
+        let db = db().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = config::Backup {
+            dir: Some(dir.path().join("backups")),
+            interval: Duration::from_secs(1),
+            keep: 2,
+            compress: false,
+            token: None,
+        };
+        let run = tokio::spawn(run_scheduled(db, config));
+        // backups are taken right away and then after each interval
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        run.abort();
+        let backups: Vec<_> = fs::read_dir(dir.path().join("backups"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(backups.len(), 2);
+        for backup in backups {
+            assert!(backup.to_string_lossy().ends_with(".db"));
+            assert_eq!(count(&backup), 2);
+        }
+    }
+
+    #[test]
+    fn rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "dialer-20240103T000000Z.db.gz",
+            "dialer-20240101T000000Z.db.gz",
+            "dialer-20240102T000000Z.db.gz",
+            "other.db",
+        ] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        super::rotate(dir.path(), 2).unwrap();
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "dialer-20240102T000000Z.db.gz",
+                "dialer-20240103T000000Z.db.gz",
+                "other.db"
+            ]
+        );
+    }
+}
@@ -1,23 +1,247 @@
-use crate::{config, db};
+use crate::{config, db, stats};
 use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
 use reqwest::Method;
-use rusqlite::OptionalExtension;
-use serde::Serialize;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     net::IpAddr,
+    sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
-use tokio::{task::JoinSet, time::error::Elapsed};
+use tokio::{
+    sync::{broadcast, Mutex},
+    task::JoinSet,
+    time::error::Elapsed,
+};
 use tracing::instrument;
 
 #[derive(Clone, Debug)]
 pub struct Checker {
     db: crate::db::Db,
-    config: crate::config::Config,
-    checks: Vec<Check>,
+    /// the config which the checks were last loaded from
+    config: Arc<RwLock<config::Config>>,
+    /// every check, including the paused ones. clones share the checks so that the changes made
+    /// through the api are picked up by the running check loop.
+    checks: Arc<RwLock<Vec<Entry>>>,
+    /// held while the checks are changed so that concurrent changes cannot conflict
+    changes: Arc<Mutex<()>>,
+    stats: stats::Registry,
+    events: broadcast::Sender<Event>,
+}
+
+/// where a check is defined
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// the config file. these checks can only be paused and resumed through the api.
+    Config,
+    /// the api, which stores them in the db
+    Api,
+}
+
+/// the config of a check of either kind, as accepted by the api
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Definition {
+    Http(config::Http),
+    Ping(config::Ping),
+}
+
+impl Definition {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Definition::Http(_) => Kind::Http,
+            Definition::Ping(_) => Kind::Ping,
+        }
+    }
+
+    fn previous_name(&self) -> Option<&str> {
+        match self {
+            Definition::Http(http) => http.previous_name.as_deref(),
+            Definition::Ping(ping) => ping.previous_name.as_deref(),
+        }
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        match self {
+            Definition::Http(http) => &http.tags,
+            Definition::Ping(ping) => &ping.tags,
+        }
+    }
+
+    fn snapshot(&self, interval: Duration) -> Snapshot<'_> {
+        let interval_ms = interval.as_millis() as u64;
+        match self {
+            Definition::Http(http) => Snapshot::Http {
+                url: &http.url,
+                code: http.code,
+                interval_ms,
+            },
+            Definition::Ping(ping) => Snapshot::Ping {
+                host: &ping.host,
+                interval_ms,
+            },
+        }
+    }
+
+    /// rejects the definitions which could not be probed, before anything is recorded
+    fn validate(&self, name: &str) -> Result<(), ManageError> {
+        if name.trim().is_empty() {
+            return Err(ManageError::Invalid(String::from(
+                "the name must not be empty",
+            )));
+        }
+        match self {
+            Definition::Http(http) => {
+                reqwest::Url::parse(&http.url)
+                    .map_err(|err| ManageError::Invalid(format!("invalid url: {err}")))?;
+            }
+            Definition::Ping(ping) if ping.host.trim().is_empty() => {
+                return Err(ManageError::Invalid(String::from(
+                    "the host must not be empty",
+                )));
+            }
+            Definition::Ping(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// the checks of the config file, by name
+fn definitions(config: &config::Config) -> impl Iterator<Item = (String, Definition)> + '_ {
+    let http = config
+        .http
+        .iter()
+        .map(|(name, http)| (name.clone(), Definition::Http(http.clone())));
+    let ping = config
+        .ping
+        .iter()
+        .map(|(name, ping)| (name.clone(), Definition::Ping(ping.clone())));
+    http.chain(ping)
+}
+
+/// rejects a previous name which names a check that is still running, either in the checker or as
+/// a check created through the api which is not loaded yet. following it would rename that check,
+/// which would then share its history with the new one.
+fn check_previous_name(
+    conn: &Connection,
+    name: &str,
+    kind: Kind,
+    previous_name: Option<&str>,
+    running: &[u64],
+) -> Result<(), ManageError> {
+    let Some(previous_name) = previous_name else {
+        return Ok(());
+    };
+    // the previous name is only followed when the check does not exist yet
+    if db::find_check(conn, name, kind.as_str())?.is_some() {
+        return Ok(());
+    }
+    let Some(id) = db::find_check(conn, previous_name, kind.as_str())? else {
+        return Ok(());
+    };
+    let managed: bool = conn
+        .query_row(
+            "select exists(select 1 from managed_checks where check_id=?1)",
+            [id],
+            |row| row.get(0),
+        )
+        .map_err(anyhow::Error::from)?;
+    if running.contains(&id) || managed {
+        return Err(ManageError::Conflict(format!(
+            "the {kind} check '{previous_name}' is still running, so '{name}' cannot carry over \
+            its history"
+        )));
+    }
+    Ok(())
+}
+
+/// the names of the checks which were changed by a reload of the config file
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub restarted: Vec<String>,
+}
+
+/// the checks which a reload has archived and built but not yet swapped in. the built checks are
+/// paired with whether to probe them straight away.
+#[derive(Default)]
+struct Reloaded {
+    archived: Vec<u64>,
+    built: Vec<(Entry, bool)>,
+    /// the checks which were added to the config file
+    created: Vec<u64>,
+    /// the checks created through the api which are now defined in the config file
+    unmanaged: Vec<u64>,
+    changes: Changes,
+    log: Vec<(tracing::Level, String)>,
+}
+
+/// a check as listed by the api
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Summary {
+    pub name: String,
+    pub source: Source,
+    pub paused: bool,
+    #[serde(flatten)]
+    pub definition: Definition,
+}
+
+/// why a change to the checks was rejected
+#[derive(Debug, thiserror::Error)]
+pub enum ManageError {
+    #[error("no {0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    check: Check,
+    definition: Definition,
+    source: Source,
+    paused: bool,
+}
+
+impl Entry {
+    fn summary(&self) -> Summary {
+        Summary {
+            name: self.check.name().to_string(),
+            source: self.source,
+            paused: self.paused,
+            definition: self.definition.clone(),
+        }
+    }
+}
+
+/// the number of events buffered for each subscriber before the slowest ones start to miss events
+const EVENT_CAPACITY: usize = 1024;
+
+/// the outcome of a single probe, published as soon as it is recorded
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Event {
+    pub name: String,
+    pub kind: Kind,
+    /// the time at which the probe started
+    pub ts: DateTime<Utc>,
+    /// the latency in milliseconds with microsecond precision
+    pub latency: Option<f64>,
+    pub err: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -26,26 +250,454 @@ enum Check {
     Ping(Ping),
 }
 
+impl Check {
+    fn id(&self) -> u64 {
+        match self {
+            Check::Http(http) => http.id,
+            Check::Ping(ping) => ping.id,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Check::Http(http) => &http.name,
+            Check::Ping(ping) => &ping.name,
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        match self {
+            Check::Http(_) => Kind::Http,
+            Check::Ping(_) => Kind::Ping,
+        }
+    }
+}
+
 impl Checker {
     pub async fn new(db: db::Db, config: &config::Config) -> Result<Self> {
-        let mut checker = Self {
+        let checker = Self {
             db,
-            config: config.clone(),
-            checks: vec![],
+            config: Arc::new(RwLock::new(config.clone())),
+            checks: Arc::default(),
+            changes: Arc::default(),
+            stats: stats::Registry::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
         };
-        for (name, http) in &config.http {
-            let id = checker.materialize(name, Kind::Http).await?;
-            let http = Http::build(name, http, id).await?;
-            checker.checks.push(Check::Http(http));
-        }
-        for (name, ping) in &config.ping {
-            let id = checker.materialize(name, Kind::Ping).await?;
-            let ping = Ping::build(name, ping, id).await?;
-            checker.checks.push(Check::Ping(ping));
+        for (name, definition) in definitions(config) {
+            let id = checker
+                .materialize(&name, definition.kind(), definition.previous_name(), &[])
+                .await?;
+            let entry = checker.build(id, &name, definition, Source::Config).await?;
+            checker.checks.write().unwrap().push(entry);
         }
+        checker.load_managed().await?;
+        checker.archive_removed().await?;
         Ok(checker)
     }
 
+    /// the stats of the checks, which are updated as they run
+    pub fn stats(&self) -> stats::Registry {
+        self.stats.clone()
+    }
+
+    /// publishes an event for every probe. subscribers only see the events which are published
+    /// after they subscribe.
+    pub fn events(&self) -> broadcast::Sender<Event> {
+        self.events.clone()
+    }
+
+    fn interval(&self) -> Duration {
+        self.config.read().unwrap().interval
+    }
+
+    /// the checks from both the config file and the api, sorted by name and kind
+    pub fn list(&self) -> Vec<Summary> {
+        let mut checks: Vec<Summary> = {
+            let checks = self.checks.read().unwrap();
+            checks.iter().map(Entry::summary).collect()
+        };
+        checks.sort_by(|a, b| {
+            (&a.name, a.definition.kind().as_str()).cmp(&(&b.name, b.definition.kind().as_str()))
+        });
+        checks
+    }
+
+    /// the check with the name. the kind is only needed when checks of different kinds share the
+    /// name.
+    pub fn get(&self, name: &str, kind: Option<Kind>) -> Result<Summary, ManageError> {
+        self.find(name, kind).map(|entry| entry.summary())
+    }
+
+    /// adds a check which is stored in the db and probed right away. the history of an archived
+    /// check with the same name and kind is carried over.
+    pub async fn create(&self, name: &str, definition: Definition) -> Result<Summary, ManageError> {
+        let _changes = self.changes.lock().await;
+        definition.validate(name)?;
+        let kind = definition.kind();
+        if self.find(name, Some(kind)).is_ok() {
+            return Err(ManageError::Conflict(format!(
+                "there is already a {kind} check named '{name}'"
+            )));
+        }
+        let id = self
+            .materialize(name, kind, definition.previous_name(), &[])
+            .await?;
+        let entry = self.build(id, name, definition, Source::Api).await?;
+        self.store(id, &entry.definition).await?;
+        tracing::info!("Created {kind} check '{name}'");
+        self.checks.write().unwrap().push(entry.clone());
+        if !entry.paused {
+            self.probe(entry.check.clone());
+        }
+        Ok(entry.summary())
+    }
+
+    /// replaces the config of a check which was created through the api. the check may be
+    /// renamed but its kind cannot change.
+    pub async fn update(
+        &self,
+        name: &str,
+        kind: Option<Kind>,
+        new_name: &str,
+        definition: Definition,
+    ) -> Result<Summary, ManageError> {
+        let _changes = self.changes.lock().await;
+        definition.validate(new_name)?;
+        let entry = self.find(name, kind)?;
+        let (id, kind) = (entry.check.id(), entry.check.kind());
+        if entry.source == Source::Config {
+            return Err(ManageError::Conflict(format!(
+                "the {kind} check '{name}' is defined in the config file"
+            )));
+        }
+        if definition.kind() != kind {
+            return Err(ManageError::Invalid(String::from(
+                "the kind of a check cannot be changed",
+            )));
+        }
+        if new_name != name {
+            let taken = {
+                let new_name = new_name.to_string();
+                self.with_conn(move |conn| db::find_check(&conn, &new_name, kind.as_str()))
+                    .await?
+            };
+            if taken.is_some() {
+                return Err(ManageError::Conflict(format!(
+                    "there is already a {kind} check named '{new_name}', which may be archived"
+                )));
+            }
+            let new_name = new_name.to_string();
+            self.with_conn(move |conn| {
+                conn.execute("update checks set name=?1 where id=?2", (&new_name, id))?;
+                Ok(())
+            })
+            .await?;
+        }
+        let updated = self.build(id, new_name, definition, Source::Api).await?;
+        self.store(id, &updated.definition).await?;
+        tracing::info!("Updated {kind} check '{new_name}'");
+        self.replace(updated.clone());
+        if !updated.paused {
+            self.probe(updated.check.clone());
+        }
+        Ok(updated.summary())
+    }
+
+    /// stops probing a check until it is resumed, including across restarts
+    pub async fn pause(&self, name: &str, kind: Option<Kind>) -> Result<Summary, ManageError> {
+        self.set_paused(name, kind, true).await
+    }
+
+    /// probes a paused check again, starting right away
+    pub async fn resume(&self, name: &str, kind: Option<Kind>) -> Result<Summary, ManageError> {
+        self.set_paused(name, kind, false).await
+    }
+
+    async fn set_paused(
+        &self,
+        name: &str,
+        kind: Option<Kind>,
+        paused: bool,
+    ) -> Result<Summary, ManageError> {
+        let _changes = self.changes.lock().await;
+        let mut entry = self.find(name, kind)?;
+        let id = entry.check.id();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "update checks set paused_at=(
+                    case when ?2 then coalesce(paused_at, CAST(strftime('%s', 'now') AS INTEGER))
+                    end
+                ) where id=?1",
+                (id, paused),
+            )?;
+            Ok(())
+        })
+        .await?;
+        if entry.paused != paused {
+            let verb = if paused { "Paused" } else { "Resumed" };
+            tracing::info!("{verb} {} check '{name}'", entry.check.kind());
+        }
+        {
+            let mut checks = self.checks.write().unwrap();
+            if let Some(entry) = checks.iter_mut().find(|entry| entry.check.id() == id) {
+                entry.paused = paused;
+            }
+        }
+        if entry.paused && !paused {
+            self.probe(entry.check.clone());
+        }
+        entry.paused = paused;
+        Ok(entry.summary())
+    }
+
+    /// stops probing a check which was created through the api and archives it, so that its
+    /// results are kept
+    pub async fn delete(&self, name: &str, kind: Option<Kind>) -> Result<(), ManageError> {
+        let _changes = self.changes.lock().await;
+        let entry = self.find(name, kind)?;
+        let (id, kind) = (entry.check.id(), entry.check.kind());
+        if entry.source == Source::Config {
+            return Err(ManageError::Conflict(format!(
+                "the {kind} check '{name}' is defined in the config file"
+            )));
+        }
+        self.retire(id).await?;
+        tracing::info!("Deleted {kind} check '{name}'");
+        Ok(())
+    }
+
+    /// applies a new config file to the running checks. the checks which were added to the file
+    /// are started, the removed ones are archived and the changed ones are restarted, while the
+    /// others keep running undisturbed. nothing is changed when any of the checks is invalid or
+    /// cannot be built.
+    pub async fn reload(&self, config: &config::Config) -> Result<Changes> {
+        let _changes = self.changes.lock().await;
+        let definitions: BTreeMap<(String, Kind), Definition> = definitions(config)
+            .map(|(name, definition)| ((name, definition.kind()), definition))
+            .collect();
+        for ((name, kind), definition) in &definitions {
+            definition
+                .validate(name)
+                .map_err(|err| anyhow!("{kind} check '{name}': {err}"))?;
+        }
+        // the checks which are removed from the file are archived before the added ones are
+        // created, so only the others can conflict with a previous name
+        let (running, added): (Vec<u64>, Vec<(String, Kind, String)>) = {
+            let checks = self.checks.read().unwrap();
+            let running = checks
+                .iter()
+                .filter(|entry| {
+                    let key = (entry.check.name().to_string(), entry.check.kind());
+                    entry.source == Source::Api || definitions.contains_key(&key)
+                })
+                .map(|entry| entry.check.id())
+                .collect();
+            let added = definitions
+                .iter()
+                .filter(|((name, kind), _)| {
+                    !checks
+                        .iter()
+                        .any(|entry| entry.check.name() == name && entry.check.kind() == *kind)
+                })
+                .filter_map(|((name, kind), definition)| {
+                    let previous_name = definition.previous_name()?.to_string();
+                    Some((name.clone(), *kind, previous_name))
+                })
+                .collect();
+            (running, added)
+        };
+        self.with_conn(move |conn| {
+            for (name, kind, previous_name) in &added {
+                check_previous_name(&conn, name, *kind, Some(previous_name), &running)
+                    .map_err(|err| anyhow!("{kind} check '{name}': {err}"))?;
+            }
+            Ok(())
+        })
+        .await?;
+        let entries: Vec<Entry> = self.checks.read().unwrap().clone();
+        let mut reloaded = Reloaded::default();
+        let rebuilt = self
+            .rebuild(config, definitions, &entries, &mut reloaded)
+            .await;
+        let rebuilt = match rebuilt {
+            // checks which were created through the api are only handed over to the config file
+            // once everything else has succeeded
+            Ok(()) => {
+                let unmanaged = reloaded.unmanaged.clone();
+                self.with_conn(move |conn| {
+                    for id in &unmanaged {
+                        conn.execute("delete from managed_checks where check_id=?1", [id])?;
+                    }
+                    Ok(())
+                })
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = rebuilt {
+            self.roll_back(&entries, &reloaded)
+                .await
+                .context("roll back the reload")?;
+            return Err(err);
+        }
+
+        // every check has been built, so the new config replaces the running one at once
+        *self.config.write().unwrap() = config.clone();
+        {
+            let mut checks = self.checks.write().unwrap();
+            checks.retain(|entry| !reloaded.archived.contains(&entry.check.id()));
+            for (entry, _) in &reloaded.built {
+                let id = entry.check.id();
+                match checks.iter_mut().find(|e| e.check.id() == id) {
+                    Some(found) => *found = entry.clone(),
+                    None => checks.push(entry.clone()),
+                }
+            }
+        }
+        for id in &reloaded.archived {
+            if !reloaded.built.iter().any(|(e, _)| e.check.id() == *id) {
+                self.stats.unregister(*id);
+            }
+        }
+        for (entry, probe) in reloaded.built {
+            let kind = entry.check.kind();
+            self.stats
+                .register(entry.check.id(), entry.check.name(), kind);
+            if probe && !entry.paused {
+                self.probe(entry.check.clone());
+            }
+        }
+        for (level, msg) in &reloaded.log {
+            match *level {
+                tracing::Level::WARN => tracing::warn!("{msg}"),
+                _ => tracing::info!("{msg}"),
+            }
+        }
+        Ok(reloaded.changes)
+    }
+
+    /// undoes the changes to the db of a reload which failed part way. the running checks were
+    /// not touched, so the db is returned to match them.
+    async fn roll_back(&self, entries: &[Entry], reloaded: &Reloaded) -> Result<()> {
+        let archived: Vec<(u64, String)> = entries
+            .iter()
+            .filter(|entry| reloaded.archived.contains(&entry.check.id()))
+            .map(|entry| (entry.check.id(), entry.check.name().to_string()))
+            .collect();
+        // an archived check may have been renamed to a check which carried over its history
+        self.with_conn(move |conn| {
+            for (id, name) in &archived {
+                conn.execute(
+                    "update checks set archived_at=null, name=?2 where id=?1",
+                    (id, name),
+                )?;
+            }
+            Ok(())
+        })
+        .await?;
+        for (entry, _) in &reloaded.built {
+            let id = entry.check.id();
+            let running = entries.iter().find(|e| e.check.id() == id);
+            if let Some(running) = running.filter(|_| !reloaded.archived.contains(&id)) {
+                self.set_tags(id, running.definition.tags()).await?;
+            }
+        }
+        for id in &reloaded.created {
+            if !entries.iter().any(|e| e.check.id() == *id) {
+                self.archive(*id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// archives the checks which were removed from the config and builds the others as they will
+    /// run, without changing the running checks
+    async fn rebuild(
+        &self,
+        config: &config::Config,
+        definitions: BTreeMap<(String, Kind), Definition>,
+        entries: &[Entry],
+        reloaded: &mut Reloaded,
+    ) -> Result<()> {
+        let interval = config.interval;
+        let interval_changed = self.interval() != interval;
+
+        // removed first so that a renamed check can carry over the history of its previous name
+        for entry in entries {
+            let key = (entry.check.name().to_string(), entry.check.kind());
+            if entry.source == Source::Config && !definitions.contains_key(&key) {
+                self.archive(entry.check.id()).await?;
+                reloaded.archived.push(entry.check.id());
+                let msg = format!("Removed {} check '{}'", key.1, key.0);
+                reloaded.log.push((tracing::Level::INFO, msg));
+                reloaded.changes.removed.push(key.0);
+            }
+        }
+        for ((name, kind), definition) in definitions {
+            let existing = entries
+                .iter()
+                .find(|entry| entry.check.name() == name && entry.check.kind() == kind);
+            let entry = match existing {
+                Some(entry) if entry.source == Source::Config => {
+                    if entry.definition == definition && !interval_changed {
+                        continue;
+                    }
+                    let id = entry.check.id();
+                    let entry = self
+                        .build_at(id, &name, definition, Source::Config, interval)
+                        .await?;
+                    let msg = format!("Restarted {kind} check '{name}'");
+                    reloaded.log.push((tracing::Level::INFO, msg));
+                    reloaded.changes.restarted.push(name);
+                    entry
+                }
+                Some(entry) => {
+                    let id = entry.check.id();
+                    let entry = self
+                        .build_at(id, &name, definition, Source::Config, interval)
+                        .await?;
+                    reloaded.unmanaged.push(id);
+                    reloaded.log.push((
+                        tracing::Level::WARN,
+                        format!("The {kind} check '{name}' is now defined in the config file"),
+                    ));
+                    reloaded.changes.restarted.push(name);
+                    entry
+                }
+                None => {
+                    let id = self
+                        .materialize(&name, kind, definition.previous_name(), &reloaded.archived)
+                        .await?;
+                    reloaded.created.push(id);
+                    let entry = self
+                        .build_at(id, &name, definition, Source::Config, interval)
+                        .await?;
+                    let msg = format!("Added {kind} check '{name}'");
+                    reloaded.log.push((tracing::Level::INFO, msg));
+                    reloaded.changes.added.push(name);
+                    entry
+                }
+            };
+            reloaded.built.push((entry, true));
+        }
+
+        // the interval is part of the config version of every check
+        if interval_changed {
+            for entry in entries.iter().filter(|entry| entry.source == Source::Api) {
+                let id = entry.check.id();
+                if reloaded.built.iter().any(|(e, _)| e.check.id() == id) {
+                    continue;
+                }
+                let definition = entry.definition.clone();
+                let entry = self
+                    .build_at(id, entry.check.name(), definition, Source::Api, interval)
+                    .await?;
+                reloaded.built.push((entry, false));
+            }
+        }
+        Ok(())
+    }
+
     #[instrument(skip_all)]
     pub async fn run(&self) -> anyhow::Result<()> {
         self.check_loop().await
@@ -55,7 +707,7 @@ impl Checker {
         loop {
             let now = Instant::now();
             self.check_all().await?;
-            let sleep = self.config.interval.saturating_sub(now.elapsed());
+            let sleep = self.interval().saturating_sub(now.elapsed());
             tokio::time::sleep(sleep).await;
         }
     }
@@ -63,9 +715,16 @@ impl Checker {
     /// runs all of the checks once.
     async fn check_all(&self) -> Result<()> {
         let mut tasks = JoinSet::default();
-        for check in &self.checks {
+        let checks: Vec<Check> = {
+            let checks = self.checks.read().unwrap();
+            checks
+                .iter()
+                .filter(|entry| !entry.paused)
+                .map(|entry| entry.check.clone())
+                .collect()
+        };
+        for check in checks {
             let checker = self.clone();
-            let check = check.clone();
             tasks.spawn(async move { checker.check(&check).await });
         }
         while let Some(res) = tasks.join_next().await {
@@ -90,7 +749,8 @@ impl Checker {
     }
 
     async fn check_http(&self, http: &Http) -> anyhow::Result<()> {
-        let timeout = self.config.interval;
+        let timeout = self.interval();
+        let started = Utc::now();
         let res = tokio::time::timeout(timeout, async move {
             let client: reqwest::Client = reqwest::Client::builder()
                 .redirect(reqwest::redirect::Policy::none())
@@ -108,22 +768,26 @@ impl Checker {
         .await;
         match res {
             Ok(Ok((_, latency))) => {
-                self.mark_ok(http.id, latency).await?;
+                self.mark_ok(http.id, http.config_id, started, latency)
+                    .await?;
             }
             Ok(Err(err)) => {
                 tracing::error!("http: {err:?}");
-                self.mark_err(http.id, format!("{err:?}")).await?;
+                self.mark_err(http.id, http.config_id, started, format!("{err:?}"))
+                    .await?;
             }
             Err(elapsed) => {
                 tracing::error!("http timeout after {elapsed:?}");
-                self.mark_err(http.id, "timeout").await?;
+                self.mark_err(http.id, http.config_id, started, "timeout")
+                    .await?;
             }
         };
         Ok(())
     }
 
     async fn check_ping(&self, ping: &Ping) -> anyhow::Result<()> {
-        let timeout = self.config.interval;
+        let timeout = self.interval();
+        let started = Utc::now();
         let res = tokio::time::timeout(timeout, async move {
             let data = [1, 2, 3, 4];
             let addr: IpAddr = {
@@ -132,12 +796,9 @@ impl Checker {
                     Err(_) => {
                         // resolve it as a hostname
                         let ips = dns_lookup::lookup_host(&ping.host).context("lookup host")?;
-                        let addr = ips
-                            .into_iter()
-                            .filter(|ip| ip.is_ipv4())
-                            .next()
-                            .ok_or_else(|| anyhow!("no ip for host"))?;
-                        addr
+                        ips.into_iter()
+                            .find(|ip| ip.is_ipv4())
+                            .ok_or_else(|| anyhow!("no ip for host"))?
                     }
                 }
             };
@@ -147,26 +808,38 @@ impl Checker {
         .await;
         match res {
             Ok(Ok((_, latency))) => {
-                self.mark_ok(ping.id, latency).await?;
+                self.mark_ok(ping.id, ping.config_id, started, latency)
+                    .await?;
             }
             Ok(Err(err)) => {
                 tracing::error!("ping: {err:?}");
-                self.mark_err(ping.id, format!("{err:?}")).await?;
+                self.mark_err(ping.id, ping.config_id, started, format!("{err:?}"))
+                    .await?;
             }
             Err(elapsed) => {
                 tracing::error!("ping: timeout after {elapsed:?}");
-                self.mark_err(ping.id, "timeout").await?;
+                self.mark_err(ping.id, ping.config_id, started, "timeout")
+                    .await?;
             }
         };
         Ok(())
     }
 
-    async fn mark_err(&self, id: u64, err: impl AsRef<str>) -> anyhow::Result<()> {
+    async fn mark_err(
+        &self,
+        id: u64,
+        config_id: u64,
+        started: DateTime<Utc>,
+        err: impl AsRef<str>,
+    ) -> anyhow::Result<()> {
+        self.stats.observe(id, None);
         let err = err.as_ref().to_string();
+        self.publish(id, started, None, Some(err.clone()));
+        let epoch_ms = started.timestamp_millis();
         self.with_conn(move |conn| {
             conn.execute(
-                "insert into results (check_id, err) values (?1,?2)",
-                (id, format!("{err:?}")),
+                "insert into results (check_id, config_id, epoch_ms, err) values (?1,?2,?3,?4)",
+                (id, config_id, epoch_ms, format!("{err:?}")),
             )?;
             Ok(())
         })
@@ -174,12 +847,22 @@ impl Checker {
         Ok(())
     }
 
-    async fn mark_ok(&self, id: u64, latency: Duration) -> anyhow::Result<()> {
-        let ms = latency.as_millis() as u64;
+    async fn mark_ok(
+        &self,
+        id: u64,
+        config_id: u64,
+        started: DateTime<Utc>,
+        latency: Duration,
+    ) -> anyhow::Result<()> {
+        self.stats.observe(id, Some(latency));
+        let ms = latency.as_micros() as f64 / 1000.0;
+        self.publish(id, started, Some(ms), None);
+        let epoch_ms = started.timestamp_millis();
+        let us = latency.as_micros() as i64;
         self.with_conn(move |conn| {
             conn.execute(
-                "insert into results (check_id, ms) values (?1,?2)",
-                [id, ms],
+                "insert into results (check_id, config_id, epoch_ms, us) values (?1,?2,?3,?4)",
+                (id, config_id, epoch_ms, us),
             )?;
             Ok(())
         })
@@ -187,36 +870,291 @@ impl Checker {
         Ok(())
     }
 
-    async fn materialize(&self, name: &str, kind: Kind) -> Result<u64> {
-        let name = name.to_string();
-        let kind = kind.as_str();
+    fn publish(&self, id: u64, ts: DateTime<Utc>, latency: Option<f64>, err: Option<String>) {
+        let (name, kind) = {
+            let checks = self.checks.read().unwrap();
+            let Some(entry) = checks.iter().find(|entry| entry.check.id() == id) else {
+                return;
+            };
+            (entry.check.name().to_string(), entry.check.kind())
+        };
+        // there is nothing to do when nobody is subscribed
+        let _ = self.events.send(Event {
+            name,
+            kind,
+            ts,
+            latency,
+            err,
+        });
+    }
+
+    /// finds the check with the name. the kind is only needed when checks of different kinds share
+    /// the name.
+    fn find(&self, name: &str, kind: Option<Kind>) -> Result<Entry, ManageError> {
+        let checks = self.checks.read().unwrap();
+        let mut found = checks.iter().filter(|entry| {
+            entry.check.name() == name && kind.is_none_or(|kind| kind == entry.check.kind())
+        });
+        match (found.next(), found.next()) {
+            (Some(entry), None) => Ok(entry.clone()),
+            (Some(_), Some(_)) => Err(ManageError::Invalid(format!(
+                "there are several checks named '{name}', pick one with its kind"
+            ))),
+            (None, _) => Err(ManageError::NotFound(format!("check named '{name}'"))),
+        }
+    }
+
+    /// records the config and tags of the check with the id and builds it
+    async fn build(
+        &self,
+        id: u64,
+        name: &str,
+        definition: Definition,
+        source: Source,
+    ) -> Result<Entry> {
+        let entry = self
+            .build_at(id, name, definition, source, self.interval())
+            .await?;
+        self.stats.register(id, name, entry.definition.kind());
+        Ok(entry)
+    }
+
+    /// builds a check as it runs at the interval, without registering it for stats yet
+    async fn build_at(
+        &self,
+        id: u64,
+        name: &str,
+        definition: Definition,
+        source: Source,
+        interval: Duration,
+    ) -> Result<Entry> {
+        let config_id = self.snapshot(id, &definition.snapshot(interval)).await?;
+        self.set_tags(id, definition.tags()).await?;
+        let check = match &definition {
+            Definition::Http(http) => Check::Http(Http::build(name, http, id, config_id).await?),
+            Definition::Ping(ping) => Check::Ping(Ping::build(name, ping, id, config_id).await?),
+        };
+        let paused = self
+            .with_conn(move |conn| {
+                let paused = conn.query_row(
+                    "select paused_at is not null from checks where id=?1",
+                    [id],
+                    |row| row.get(0),
+                )?;
+                Ok(paused)
+            })
+            .await?;
+        Ok(Entry {
+            check,
+            definition,
+            source,
+            paused,
+        })
+    }
+
+    /// stores the definition of a check which was created through the api
+    async fn store(&self, check_id: u64, definition: &Definition) -> Result<()> {
+        let definition = serde_json::to_string(definition).context("serialize definition")?;
         self.with_conn(move |conn| {
-            let id = conn
-                .query_row(
-                    "select id from checks where name=?1 and kind=?2",
-                    (&name, kind),
-                    |row| {
-                        let id: u64 = row.get(0)?;
-                        Ok(id)
-                    },
-                )
-                .optional()?;
-            let id = match id {
-                Some(id) => id,
-                None => conn.query_row(
-                    "insert into checks (name, kind) values (?1, ?2) returning id",
-                    (&name, kind),
-                    |row| {
-                        let id: u64 = row.get(0)?;
-                        Ok(id)
-                    },
-                )?,
+            conn.execute(
+                "insert into managed_checks (check_id, definition) values (?1, ?2)
+                 on conflict (check_id) do update set definition=excluded.definition",
+                (check_id, &definition),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// adds the checks which were created through the api. a check which has since been added to
+    /// the config file is taken over by the file.
+    async fn load_managed(&self) -> Result<()> {
+        let managed: Vec<(u64, String, String)> = self
+            .with_conn(|conn| {
+                let mut stmt = conn.prepare(
+                    "select m.check_id, c.name, m.definition from managed_checks m
+                     join checks c on c.id = m.check_id
+                     order by m.check_id",
+                )?;
+                let rows = stmt
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+        for (id, name, definition) in managed {
+            let definition: Definition = serde_json::from_str(&definition)
+                .with_context(|| format!("parse the definition of check '{name}'"))?;
+            let defined = {
+                let checks = self.checks.read().unwrap();
+                checks.iter().any(|entry| entry.check.id() == id)
             };
+            if defined {
+                tracing::warn!(
+                    "The {} check '{name}' is now defined in the config file",
+                    definition.kind()
+                );
+                self.with_conn(move |conn| {
+                    conn.execute("delete from managed_checks where check_id=?1", [id])?;
+                    Ok(())
+                })
+                .await?;
+                continue;
+            }
+            let entry = self.build(id, &name, definition, Source::Api).await?;
+            self.checks.write().unwrap().push(entry);
+        }
+        Ok(())
+    }
+
+    /// swaps the check with the same id for the entry
+    fn replace(&self, entry: Entry) {
+        let mut checks = self.checks.write().unwrap();
+        if let Some(found) = checks.iter_mut().find(|e| e.check.id() == entry.check.id()) {
+            *found = entry;
+        }
+    }
+
+    /// stops probing a check and archives it, so that its results are kept
+    async fn retire(&self, id: u64) -> Result<()> {
+        self.archive(id).await?;
+        self.checks
+            .write()
+            .unwrap()
+            .retain(|entry| entry.check.id() != id);
+        self.stats.unregister(id);
+        Ok(())
+    }
+
+    /// archives a check in the db while it may still be running
+    async fn archive(&self, id: u64) -> Result<()> {
+        self.with_conn(move |mut conn| {
+            let tx = conn.transaction()?;
+            tx.execute("delete from managed_checks where check_id=?1", [id])?;
+            tx.execute(
+                "update checks set archived_at=CAST(strftime('%s', 'now') AS INTEGER), paused_at=null
+                 where id=?1",
+                [id],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// probes a check in the background rather than waiting for the next round of checks
+    fn probe(&self, check: Check) {
+        let checker = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = checker.check(&check).await {
+                tracing::error!("task failed: {err:?}");
+            }
+        });
+    }
+
+    /// finds or creates the check with the specified name and kind, returning its id. the check is
+    /// unarchived if it had previously been archived. the history of the previous name is not
+    /// carried over while that check is still running, unless it is one of the archived ids which
+    /// are about to stop.
+    async fn materialize(
+        &self,
+        name: &str,
+        kind: Kind,
+        previous_name: Option<&str>,
+        archived: &[u64],
+    ) -> Result<u64, ManageError> {
+        let name = name.to_string();
+        let previous_name = previous_name.map(ToString::to_string);
+        let running: Vec<u64> = {
+            let checks = self.checks.read().unwrap();
+            checks
+                .iter()
+                .map(|entry| entry.check.id())
+                .filter(|id| !archived.contains(id))
+                .collect()
+        };
+        self.with_conn(move |conn| {
+            let previous_name = previous_name.as_deref();
+            if let Err(err) = check_previous_name(&conn, &name, kind, previous_name, &running) {
+                return Ok(Err(err));
+            }
+            let id = db::materialize(&conn, &name, kind.as_str(), previous_name)?;
+            conn.execute("update checks set archived_at=null where id=?1", [id])?;
+            Ok(Ok(id))
+        })
+        .await?
+    }
+
+    /// archives the checks in the db which are no longer present in the config.
+    async fn archive_removed(&self) -> Result<()> {
+        let ids: Vec<u64> = {
+            let checks = self.checks.read().unwrap();
+            checks.iter().map(|entry| entry.check.id()).collect()
+        };
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("select id, name, kind from checks where archived_at is null")?;
+            let active = stmt
+                .query_map([], |row| {
+                    let id: u64 = row.get(0)?;
+                    let name: String = row.get(1)?;
+                    let kind: String = row.get(2)?;
+                    Ok((id, name, kind))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (id, name, kind) in active {
+                if ids.contains(&id) {
+                    continue;
+                }
+                tracing::info!("Archiving {kind} check '{name}'");
+                conn.execute(
+                    "update checks set archived_at=CAST(strftime('%s', 'now') AS INTEGER) where id=?1",
+                    [id],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// records the config of the check, returning the id of the config version. the version is
+    /// shared with any earlier run which used an identical config.
+    async fn snapshot(&self, check_id: u64, snapshot: &Snapshot<'_>) -> Result<u64> {
+        let config = serde_json::to_string(snapshot).context("serialize config snapshot")?;
+        let hash = format!("{:x}", Sha256::digest(&config));
+        self.with_conn(move |conn| {
+            let id = conn.query_row(
+                "insert into check_configs (check_id, hash, config) values (?1, ?2, ?3)
+                 on conflict (check_id, hash) do update set hash=excluded.hash
+                 returning id",
+                (check_id, &hash, &config),
+                |row| {
+                    let id: u64 = row.get(0)?;
+                    Ok(id)
+                },
+            )?;
             Ok(id)
         })
         .await
     }
 
+    /// replaces the tags of the check with those in its config
+    async fn set_tags(&self, check_id: u64, tags: &BTreeMap<String, String>) -> Result<()> {
+        let tags = tags.clone();
+        self.with_conn(move |mut conn| {
+            let tx = conn.transaction()?;
+            tx.execute("delete from check_tags where check_id=?1", [check_id])?;
+            for (key, value) in &tags {
+                tx.execute(
+                    "insert into check_tags (check_id, key, value) values (?1, ?2, ?3)",
+                    (check_id, key, value),
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
     async fn with_conn<F, R>(&self, f: F) -> anyhow::Result<R>
     where
         F: Fn(PooledConnection<SqliteConnectionManager>) -> anyhow::Result<R>,
@@ -234,7 +1172,23 @@ impl Checker {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+/// the parts of a check's config which affect its results. a change to any of these creates a new
+/// config version for the check.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Snapshot<'a> {
+    Http {
+        url: &'a str,
+        code: Option<u32>,
+        interval_ms: u64,
+    },
+    Ping {
+        host: &'a str,
+        interval_ms: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Kind {
     #[serde(rename = "http")]
     Http,
@@ -274,6 +1228,7 @@ impl Kind {
 #[derive(Debug, Clone)]
 pub struct Http {
     pub id: u64,
+    pub config_id: u64,
     pub name: String,
     pub url: reqwest::Url,
     pub code: Option<u32>,
@@ -299,13 +1254,14 @@ pub enum HttpError {
 }
 
 impl Http {
-    async fn build(name: &str, http: &config::Http, id: u64) -> Result<Self> {
+    async fn build(name: &str, http: &config::Http, id: u64, config_id: u64) -> Result<Self> {
         let url = reqwest::Url::parse(&http.url).context("could not parse http url")?;
         Ok(Self {
             id,
+            config_id,
             name: name.to_string(),
             url,
-            code: http.code.clone(),
+            code: http.code,
         })
     }
 }
@@ -318,16 +1274,451 @@ pub enum CheckResult {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ping {
     pub id: u64,
+    pub config_id: u64,
     pub name: String,
     pub host: String,
 }
 
 impl Ping {
-    async fn build(name: &str, ping: &config::Ping, id: u64) -> Result<Self> {
+    async fn build(name: &str, ping: &config::Ping, id: u64, config_id: u64) -> Result<Self> {
         Ok(Self {
             id,
+            config_id,
             name: name.to_string(),
             host: ping.host.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, path::PathBuf};
+
+    /// serves every path locally so that the http checks of the tests stay off the network
+    async fn listen() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let rtr = axum::Router::new().fallback(|| async { "ok" });
+        tokio::spawn(async move { axum::serve(listener, rtr).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn check_all_in_memory() {
+        let addr = listen().await;
+
+        let config = config::Config {
+            db_path: PathBuf::from(db::MEMORY_PATH),
+            interval: Duration::from_secs(5),
+            http: HashMap::from([(
+                String::from("local"),
+                config::Http {
+                    url: format!("http://{addr}/"),
+                    code: None,
+                    previous_name: None,
+                    tags: Default::default(),
+                },
+            )]),
+            ..Default::default()
+        };
+        let db = db::Db::connect(&config.db_path).await.unwrap();
+        let checker = Checker::new(db.clone(), &config).await.unwrap();
+        let mut events = checker.events().subscribe();
+        let before = Utc::now();
+        checker.check_all().await.unwrap();
+        checker.check_all().await.unwrap();
+
+        for _ in 0..2 {
+            let event = events.try_recv().unwrap();
+            assert_eq!((event.name.as_str(), event.kind), ("local", Kind::Http));
+            assert!(event.latency.is_some() && event.err.is_none());
+            assert!(event.ts >= before);
+        }
+        assert!(events.try_recv().is_err());
+
+        let (count, errs, min_us, min_epoch_ms): (u64, u64, i64, i64) = db
+            .with_conn(|conn| {
+                Ok(conn.query_row(
+                    "select count(*), count(err), min(us), min(epoch_ms) from results r
+                     join checks c on r.check_id = c.id
+                     where c.name = 'local' and c.kind = 'http'",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )?)
+            })
+            .await
+            .unwrap();
+        assert_eq!((count, errs), (2, 0));
+        assert!(min_us > 0);
+        assert!(min_epoch_ms >= before.timestamp_millis());
+    }
+
+    #[tokio::test]
+    async fn rename_and_archive() {
+        let ping = |host: &str, previous_name: Option<&str>| config::Ping {
+            host: host.to_string(),
+            previous_name: previous_name.map(ToString::to_string),
+            tags: Default::default(),
+        };
+        let config = |pings: Vec<(&str, config::Ping)>| config::Config {
+            ping: pings
+                .into_iter()
+                .map(|(name, ping)| (name.to_string(), ping))
+                .collect(),
+            ..Default::default()
+        };
+        let db = db::Db::in_memory().await.unwrap();
+        let checks = |db: db::Db| async move {
+            db.with_conn(|conn| {
+                let mut stmt = conn
+                    .prepare("select id, name, archived_at is not null from checks order by id")?;
+                let rows = stmt
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<Result<Vec<(u64, String, bool)>, _>>()?;
+                Ok(rows)
+            })
+            .await
+            .unwrap()
+        };
+
+        let config1 = config(vec![("router", ping("192.168.0.1", None))]);
+        Checker::new(db.clone(), &config1).await.unwrap();
+        assert_eq!(checks(db.clone()).await, vec![(1, "router".into(), false)]);
+
+        let config2 = config(vec![
+            ("gateway", ping("192.168.0.1", Some("router"))),
+            ("google", ping("google.com", None)),
+        ]);
+        Checker::new(db.clone(), &config2).await.unwrap();
+        assert_eq!(
+            checks(db.clone()).await,
+            vec![(1, "gateway".into(), false), (2, "google".into(), false)]
+        );
+
+        let config3 = config(vec![("google", ping("google.com", None))]);
+        Checker::new(db.clone(), &config3).await.unwrap();
+        assert_eq!(
+            checks(db.clone()).await,
+            vec![(1, "gateway".into(), true), (2, "google".into(), false)]
+        );
+
+        // restoring a check unarchives it
+        Checker::new(db.clone(), &config2).await.unwrap();
+        assert_eq!(
+            checks(db.clone()).await,
+            vec![(1, "gateway".into(), false), (2, "google".into(), false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn config_versions() {
+        let config = |host: &str| config::Config {
+            ping: HashMap::from([(
+                String::from("gateway"),
+                config::Ping {
+                    host: host.to_string(),
+                    previous_name: None,
+                    tags: Default::default(),
+                },
+            )]),
+            ..Default::default()
+        };
+        let db = db::Db::in_memory().await.unwrap();
+        let config_id = |checker: &Checker| match &checker.checks.read().unwrap()[0].check {
+            Check::Ping(ping) => ping.config_id,
+            Check::Http(http) => http.config_id,
+        };
+        let v1 = config_id(
+            &Checker::new(db.clone(), &config("192.168.0.1"))
+                .await
+                .unwrap(),
+        );
+        let v2 = config_id(
+            &Checker::new(db.clone(), &config("192.168.1.1"))
+                .await
+                .unwrap(),
+        );
+        let v3 = config_id(
+            &Checker::new(db.clone(), &config("192.168.0.1"))
+                .await
+                .unwrap(),
+        );
+        assert_ne!(v1, v2);
+        assert_eq!(v1, v3);
+    }
+
+    #[tokio::test]
+    async fn manage() {
+        let config = config::Config {
+            ping: HashMap::from([(
+                String::from("gateway"),
+                config::Ping {
+                    host: String::from("127.0.0.1"),
+                    previous_name: None,
+                    tags: Default::default(),
+                },
+            )]),
+            ..Default::default()
+        };
+        let http = |url: &str| {
+            Definition::Http(config::Http {
+                url: url.to_string(),
+                code: None,
+                previous_name: None,
+                tags: Default::default(),
+            })
+        };
+        let addr = listen().await;
+        let url = |path: &str| format!("http://{addr}/{path}");
+        let db = db::Db::in_memory().await.unwrap();
+        let checker = Checker::new(db.clone(), &config).await.unwrap();
+
+        let nas = checker.create("nas", http(&url("nas"))).await.unwrap();
+        assert_eq!((nas.source, nas.paused), (Source::Api, false));
+        let err = checker.create("nas", http(&url("nas"))).await;
+        assert!(matches!(err, Err(ManageError::Conflict(_))));
+        let err = checker.create("router", http("not a url")).await;
+        assert!(matches!(err, Err(ManageError::Invalid(_))));
+        let err = checker.delete("gateway", None).await;
+        assert!(matches!(err, Err(ManageError::Conflict(_))));
+
+        // the history of a check which is still running cannot be carried over
+        let renamed = |previous_name: &str| {
+            Definition::Ping(config::Ping {
+                host: String::from("127.0.0.1"),
+                previous_name: Some(previous_name.to_string()),
+                tags: Default::default(),
+            })
+        };
+        let err = checker.create("gw", renamed("gateway")).await;
+        assert!(matches!(err, Err(ManageError::Conflict(_))));
+        let err = checker.create("gw", renamed("nas")).await;
+        assert!(err.is_ok(), "{err:?}");
+        checker.delete("gw", None).await.unwrap();
+        assert_eq!(
+            checker.get("gateway", Some(Kind::Ping)).unwrap().source,
+            Source::Config
+        );
+
+        let storage = checker
+            .update("nas", None, "storage", http(&url("storage")))
+            .await
+            .unwrap();
+        assert_eq!(storage.definition, http(&url("storage")));
+        checker.pause("storage", None).await.unwrap();
+
+        // checks of different kinds may share a name, which is then ambiguous
+        checker.create("gateway", http(&url(""))).await.unwrap();
+        let err = checker.pause("gateway", None).await;
+        assert!(matches!(err, Err(ManageError::Invalid(_))));
+        checker.pause("gateway", Some(Kind::Ping)).await.unwrap();
+        checker.resume("gateway", Some(Kind::Ping)).await.unwrap();
+
+        // the checks and whether they are paused are kept across restarts
+        let checker = Checker::new(db.clone(), &config).await.unwrap();
+        let checks: Vec<(String, Kind, Source, bool)> = checker
+            .list()
+            .into_iter()
+            .map(|c| (c.name, c.definition.kind(), c.source, c.paused))
+            .collect();
+        assert_eq!(
+            checks,
+            vec![
+                (String::from("gateway"), Kind::Http, Source::Api, false),
+                (String::from("gateway"), Kind::Ping, Source::Config, false),
+                (String::from("storage"), Kind::Http, Source::Api, true),
+            ]
+        );
+
+        checker.delete("storage", None).await.unwrap();
+        let err = checker.delete("storage", None).await;
+        assert!(matches!(err, Err(ManageError::NotFound(_))));
+        let checker = Checker::new(db.clone(), &config).await.unwrap();
+        assert_eq!(checker.list().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reload_failed() {
+        let ping = |host: &str, previous_name: Option<&str>| config::Ping {
+            host: host.to_string(),
+            previous_name: previous_name.map(ToString::to_string),
+            tags: Default::default(),
+        };
+        let db = db::Db::in_memory().await.unwrap();
+        let config1 = config::Config {
+            ping: HashMap::from([
+                (String::from("gateway"), ping("127.0.0.1", None)),
+                (String::from("nas"), ping("127.0.0.2", None)),
+            ]),
+            ..Default::default()
+        };
+        let checker = Checker::new(db.clone(), &config1).await.unwrap();
+        let gateway = checker.get("gateway", None).unwrap();
+
+        // the gateway is renamed, and building the router fails once its history is carried over
+        let config2 = config::Config {
+            listen: String::from(":3000"),
+            ping: HashMap::from([
+                (String::from("router"), ping("127.0.0.1", Some("gateway"))),
+                (String::from("nas"), ping("127.0.0.2", None)),
+            ]),
+            ..Default::default()
+        };
+        db.conn()
+            .unwrap()
+            .execute_batch("alter table check_tags rename to moved_tags")
+            .unwrap();
+        assert!(checker.reload(&config2).await.is_err());
+        db.conn()
+            .unwrap()
+            .execute_batch("alter table moved_tags rename to check_tags")
+            .unwrap();
+
+        // the checks, the config and the db are as they were before
+        let names: Vec<String> = checker.list().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, vec!["gateway", "nas"]);
+        assert_eq!(checker.get("gateway", None).unwrap(), gateway);
+        assert_eq!(checker.config.read().unwrap().listen, "");
+        let rows: Vec<(String, bool)> = db
+            .conn()
+            .unwrap()
+            .prepare("select name, archived_at is not null from checks order by name")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (String::from("gateway"), false),
+                (String::from("nas"), false)
+            ]
+        );
+
+        // a check which was added for the failed reload is archived again
+        let mut config3 = config1.clone();
+        config3
+            .ping
+            .insert(String::from("printer"), ping("127.0.0.3", None));
+        db.conn()
+            .unwrap()
+            .execute_batch("alter table check_tags rename to moved_tags")
+            .unwrap();
+        assert!(checker.reload(&config3).await.is_err());
+        db.conn()
+            .unwrap()
+            .execute_batch("alter table moved_tags rename to check_tags")
+            .unwrap();
+        let archived: bool = db
+            .conn()
+            .unwrap()
+            .query_row(
+                "select archived_at is not null from checks where name = 'printer'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(archived);
+        assert_eq!(checker.list().len(), 2);
+
+        // and the same reload succeeds once nothing fails
+        let changes = checker.reload(&config2).await.unwrap();
+        assert_eq!(changes.added, vec!["router"]);
+        assert_eq!(changes.removed, vec!["gateway"]);
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let ping = |host: &str| config::Ping {
+            host: host.to_string(),
+            previous_name: None,
+            tags: Default::default(),
+        };
+        let config = |pings: Vec<(&str, config::Ping)>| config::Config {
+            ping: pings
+                .into_iter()
+                .map(|(name, ping)| (name.to_string(), ping))
+                .collect(),
+            ..Default::default()
+        };
+        let db = db::Db::in_memory().await.unwrap();
+        let config1 = config(vec![
+            ("gateway", ping("127.0.0.1")),
+            ("google", ping("127.0.0.8")),
+            ("nas", ping("127.0.0.2")),
+        ]);
+        let checker = Checker::new(db.clone(), &config1).await.unwrap();
+        let printer = Definition::Ping(ping("127.0.0.3"));
+        checker.create("printer", printer).await.unwrap();
+        let config_id = |name: &str| {
+            let checks = checker.checks.read().unwrap();
+            match &checks
+                .iter()
+                .find(|e| e.check.name() == name)
+                .unwrap()
+                .check
+            {
+                Check::Ping(ping) => ping.config_id,
+                Check::Http(http) => http.config_id,
+            }
+        };
+        let (gateway, google) = (config_id("gateway"), config_id("google"));
+
+        // the printer, which was created through the api, is taken over by the file
+        let config2 = config(vec![
+            ("gateway", ping("127.0.1.1")),
+            ("google", ping("127.0.0.8")),
+            ("printer", ping("127.0.0.3")),
+            ("router", ping("127.0.0.254")),
+        ]);
+        let changes = checker.reload(&config2).await.unwrap();
+        assert_eq!(
+            changes,
+            Changes {
+                added: vec![String::from("router")],
+                removed: vec![String::from("nas")],
+                restarted: vec![String::from("gateway"), String::from("printer")],
+            }
+        );
+        assert_ne!(config_id("gateway"), gateway);
+        assert_eq!(config_id("google"), google);
+        let checks: Vec<(String, Source)> = checker
+            .list()
+            .into_iter()
+            .map(|c| (c.name, c.source))
+            .collect();
+        assert_eq!(
+            checks,
+            vec![
+                (String::from("gateway"), Source::Config),
+                (String::from("google"), Source::Config),
+                (String::from("printer"), Source::Config),
+                (String::from("router"), Source::Config),
+            ]
+        );
+        assert_eq!(checker.reload(&config2).await.unwrap(), Changes::default());
+
+        // an invalid check rejects the whole config
+        let mut config3 = config2.clone();
+        config3.ping.insert(String::from("router"), ping(" "));
+        config3.ping.remove("google");
+        assert!(checker.reload(&config3).await.is_err());
+        assert_eq!(checker.list().len(), 4);
+
+        // so does a previous name which would take over a check created through the api
+        checker
+            .create("scanner", Definition::Ping(ping("127.0.0.4")))
+            .await
+            .unwrap();
+        let mut config4 = config2.clone();
+        let mut copier = ping("127.0.0.4");
+        copier.previous_name = Some(String::from("scanner"));
+        config4.ping.insert(String::from("copier"), copier);
+        let err = checker.reload(&config4).await.unwrap_err();
+        assert!(format!("{err:#}").contains("'scanner' is still running"));
+        assert_eq!(checker.list().len(), 5);
+        assert!(checker.get("copier", None).is_err());
+    }
+}
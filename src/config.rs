@@ -1,7 +1,7 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
     time::Duration,
 };
@@ -11,12 +11,21 @@ use std::{
 pub struct Config {
     pub db_path: PathBuf,
     pub live_reload: bool,
+    /// serves files from this directory in place of the assets embedded in the binary, so that
+    /// they can be edited without rebuilding
+    pub assets_dir: Option<PathBuf>,
     #[serde(with = "humantime_serde")]
     pub interval: Duration,
     #[serde(default = "default_listen")]
     pub listen: String,
     pub ping: HashMap<String, Ping>,
     pub http: HashMap<String, Http>,
+    pub backup: Backup,
+    pub uptime: Uptime,
+    pub incidents: Incidents,
+    pub push: HashMap<String, Push>,
+    pub status: Status,
+    pub auth: Auth,
 }
 
 impl Default for Config {
@@ -24,14 +33,215 @@ impl Default for Config {
         Self {
             db_path: PathBuf::default(),
             live_reload: cfg!(debug_assertions),
+            assets_dir: None,
             interval: Duration::default(),
             listen: String::default(),
             ping: HashMap::default(),
             http: HashMap::default(),
+            backup: Backup::default(),
+            uptime: Uptime::default(),
+            incidents: Incidents::default(),
+            push: HashMap::default(),
+            status: Status::default(),
+            auth: Auth::default(),
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Backup {
+    /// the directory that scheduled backups are written to. scheduled backups are disabled unless
+    /// this is set.
+    pub dir: Option<PathBuf>,
+    /// how often scheduled backups are taken
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// the number of scheduled backups to keep. older backups are deleted.
+    pub keep: usize,
+    /// gzip scheduled backups
+    pub compress: bool,
+    /// the bearer token required by the /backup endpoint. the endpoint is disabled unless this is
+    /// set.
+    pub token: Option<String>,
+}
+
+impl Default for Backup {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            interval: Duration::from_secs(60 * 60 * 24),
+            keep: 7,
+            compress: true,
+            token: None,
+        }
+    }
+}
+
+/// how availability is computed by the /uptime report
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Uptime {
+    /// results are grouped into buckets of this size, each of which is either up or down. must be
+    /// a whole number of seconds, and at least 1s.
+    #[serde(with = "humantime_serde")]
+    pub bucket: Duration,
+    /// a bucket is down when at least this percentage of its probes failed, from 1 to 100
+    pub down_percent: u8,
+}
+
+impl Default for Uptime {
+    fn default() -> Self {
+        Self {
+            bucket: Duration::from_secs(60),
+            down_percent: 50,
+        }
+    }
+}
+
+/// how incidents are derived from the results
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Incidents {
+    /// failures closer together than this belong to the same incident
+    #[serde(with = "humantime_serde")]
+    pub merge_gap: Duration,
+    /// incidents with fewer failures than this are ignored
+    pub min_failures: u64,
+    /// how often the results are scanned for incidents
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for Incidents {
+    fn default() -> Self {
+        Self {
+            merge_gap: Duration::from_secs(60),
+            min_failures: 3,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// the public status page
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Status {
+    /// also serves the status page on its own address, e.g. "0.0.0.0:3001". nothing else is
+    /// served there.
+    pub listen: Option<String>,
+    pub title: String,
+    /// the number of days of uptime to show
+    pub days: u32,
+    pub components: Vec<Component>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            title: String::from("Status"),
+            days: 90,
+            components: vec![],
+        }
+    }
+}
+
+/// who may use the web ui and api. everything is open unless tokens or users are configured. the
+/// status page is always public.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Auth {
+    /// static tokens for machine clients, which send them as `Authorization: Bearer <token>`
+    pub tokens: Vec<Token>,
+    /// people who sign in to the ui with http basic auth
+    pub users: Vec<User>,
+    /// serves the badges without auth, so that they can be embedded elsewhere
+    pub public_badges: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Token {
+    /// identifies the client in the logs
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub password: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// what a token or user may do. roles are ordered by what they allow.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// may view everything but change nothing
+    #[default]
+    Read,
+    /// may also manage the checks and take backups
+    Admin,
+}
+
+/// a group of checks which is shown as one on the status page
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Component {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// the names of the checks, which may be glob patterns such as "isp-*"
+    pub checks: Vec<String>,
+}
+
+/// pushes results to a metrics backend which cannot scrape dialer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Push {
+    pub format: PushFormat,
+    pub url: String,
+    /// how often new results are pushed
+    #[serde(default = "default_push_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    /// the most results sent in a single request
+    #[serde(default = "default_push_batch_size")]
+    pub batch_size: usize,
+    /// the most results which are kept for the target while it is unreachable. older results
+    /// are skipped.
+    #[serde(default = "default_push_max_backlog")]
+    pub max_backlog: usize,
+    /// headers added to each request, e.g. for authorization
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// labels added to every series, e.g. `{ instance = "cabin" }`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PushFormat {
+    /// the prometheus remote write protocol
+    RemoteWrite,
+    /// otlp over http with json encoding
+    Otlp,
+}
+
+fn default_push_interval() -> Duration {
+    Duration::from_secs(15)
+}
+
+fn default_push_batch_size() -> usize {
+    1000
+}
+
+fn default_push_max_backlog() -> usize {
+    100_000
+}
+
 fn default_listen() -> String {
     String::from("0.0.0.0:3000")
 }
@@ -39,12 +249,22 @@ fn default_listen() -> String {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Ping {
     pub host: String,
+    /// the name this check was previously known by. its history is carried over to the new name.
+    pub previous_name: Option<String>,
+    /// labels which queries can select the check by, e.g. `{ site = "home" }`
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Http {
     pub url: String,
     pub code: Option<u32>,
+    /// the name this check was previously known by. its history is carried over to the new name.
+    pub previous_name: Option<String>,
+    /// labels which queries can select the check by, e.g. `{ site = "home" }`
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
 }
 
 impl Config {
@@ -61,7 +281,62 @@ impl Config {
 impl TryFrom<&str> for Config {
     type Error = anyhow::Error;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        toml::from_str(value).context("unmarshal toml")
+        let config: Self = toml::from_str(value).context("unmarshal toml")?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// rejects the settings which parse but which dialer cannot run with
+    fn validate(&self) -> anyhow::Result<()> {
+        // the history of a check which is still configured cannot be carried over to another
+        let renamed = |kind: &str, name: &str, previous_name: Option<&str>, configured: bool| {
+            match previous_name {
+                Some(previous_name) if configured => anyhow::bail!(
+                    "{kind} check '{name}' has the previous name '{previous_name}', which is still \
+                    configured"
+                ),
+                _ => Ok(()),
+            }
+        };
+        for (name, http) in &self.http {
+            let previous_name = http.previous_name.as_deref();
+            let configured = previous_name.is_some_and(|p| self.http.contains_key(p));
+            renamed("http", name, previous_name, configured)?;
+        }
+        for (name, ping) in &self.ping {
+            let previous_name = ping.previous_name.as_deref();
+            let configured = previous_name.is_some_and(|p| self.ping.contains_key(p));
+            renamed("ping", name, previous_name, configured)?;
+        }
+        if self.backup.interval.is_zero() {
+            anyhow::bail!("backup.interval must be more than zero");
+        }
+        if self.backup.keep == 0 {
+            anyhow::bail!("backup.keep must be at least 1");
+        }
+        if self.uptime.bucket < Duration::from_secs(1) {
+            anyhow::bail!("uptime.bucket must be at least 1s");
+        }
+        if self.uptime.bucket.subsec_nanos() != 0 {
+            anyhow::bail!("uptime.bucket must be a whole number of seconds");
+        }
+        if !(1..=100).contains(&self.uptime.down_percent) {
+            anyhow::bail!("uptime.down_percent must be between 1 and 100");
+        }
+        if self.incidents.interval.is_zero() {
+            anyhow::bail!("incidents.interval must be more than zero");
+        }
+        for (name, push) in &self.push {
+            if push.interval.is_zero() {
+                anyhow::bail!("push.{name}.interval must be more than zero");
+            }
+            if push.batch_size == 0 {
+                anyhow::bail!("push.{name}.batch_size must be at least 1");
+            }
+        }
+        Ok(())
     }
 }
 
@@ -106,13 +381,17 @@ mod tests {
                     (
                         String::from("google"),
                         Ping {
-                            host: String::from("google.com")
+                            host: String::from("google.com"),
+                            previous_name: None,
+                            tags: BTreeMap::new(),
                         }
                     ),
                     (
                         String::from("yahoo"),
                         Ping {
-                            host: String::from("yahoo.com")
+                            host: String::from("yahoo.com"),
+                            previous_name: None,
+                            tags: BTreeMap::new(),
                         }
                     ),
                 ]),
@@ -120,7 +399,9 @@ mod tests {
                     String::from("google"),
                     Http {
                         url: String::from("https://google.com"),
-                        code: None
+                        code: None,
+                        previous_name: None,
+                        tags: BTreeMap::new(),
                     }
                 )]),
                 ..Default::default()
@@ -128,6 +409,122 @@ mod tests {
         );
     }
 
+    #[test]
+    fn validate() {
+        for (config, err) in [
+            (
+                "[backup]\ninterval = \"0s\"",
+                "backup.interval must be more than zero",
+            ),
+            ("[backup]\nkeep = 0", "backup.keep must be at least 1"),
+            (
+                "[uptime]\nbucket = \"500ms\"",
+                "uptime.bucket must be at least 1s",
+            ),
+            (
+                "[uptime]\nbucket = \"1s 500ms\"",
+                "uptime.bucket must be a whole number of seconds",
+            ),
+            (
+                "[incidents]\ninterval = \"0s\"",
+                "incidents.interval must be more than zero",
+            ),
+            (
+                "[uptime]\ndown_percent = 0",
+                "uptime.down_percent must be between 1 and 100",
+            ),
+            (
+                "[uptime]\ndown_percent = 101",
+                "uptime.down_percent must be between 1 and 100",
+            ),
+            (
+                "[push.grafana]\nformat = \"remote_write\"\nurl = \"http://grafana/\"\ninterval = \"0s\"",
+                "push.grafana.interval must be more than zero",
+            ),
+            (
+                "[push.grafana]\nformat = \"remote_write\"\nurl = \"http://grafana/\"\nbatch_size = 0",
+                "push.grafana.batch_size must be at least 1",
+            ),
+        ] {
+            assert_eq!(Config::try_from(config).unwrap_err().to_string(), err);
+        }
+    }
+
+    #[test]
+    fn previous_name() {
+        let config = r#"
+            [ping]
+            gateway = { host = "192.168.0.1", previous_name = "router" }
+            "#;
+        let config = Config::try_from(config).unwrap();
+        assert_eq!(
+            config.ping.get("gateway").unwrap().previous_name.as_deref(),
+            Some("router")
+        );
+
+        // the history of a check which is still configured cannot be taken over
+        let config = r#"
+            [ping]
+            router = { host = "192.168.0.1" }
+            gateway = { host = "192.168.0.1", previous_name = "router" }
+            "#;
+        let err = Config::try_from(config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ping check 'gateway' has the previous name 'router', which is still configured"
+        );
+        let config = r#"
+            [http]
+            router = { url = "http://192.168.0.1/" }
+
+            [ping]
+            gateway = { host = "192.168.0.1", previous_name = "router" }
+            "#;
+        assert!(Config::try_from(config).is_ok());
+    }
+
+    #[test]
+    fn tags() {
+        let config = r#"
+            [ping]
+            gateway = { host = "192.168.0.1", tags = { site = "home", isp = "sonic" } }
+            "#;
+        let config = Config::try_from(config).unwrap();
+        assert_eq!(
+            config.ping.get("gateway").unwrap().tags,
+            BTreeMap::from([
+                (String::from("isp"), String::from("sonic")),
+                (String::from("site"), String::from("home")),
+            ])
+        );
+    }
+
+    #[test]
+    fn push() {
+        let config = r#"
+            [push.grafana]
+            format = "remote_write"
+            url = "https://prometheus.example.com/api/v1/write"
+            headers = { Authorization = "Bearer secret" }
+            "#;
+        let config = Config::try_from(config).unwrap();
+        assert_eq!(
+            config.push.get("grafana").unwrap(),
+            &Push {
+                format: PushFormat::RemoteWrite,
+                url: String::from("https://prometheus.example.com/api/v1/write"),
+                interval: default_push_interval(),
+                batch_size: default_push_batch_size(),
+                max_backlog: default_push_max_backlog(),
+                headers: BTreeMap::from([(
+                    String::from("Authorization"),
+                    String::from("Bearer secret")
+                )]),
+                labels: BTreeMap::new(),
+            }
+        );
+    }
+
     #[test]
     fn config_serde() {
         let config = r#"
@@ -146,6 +543,7 @@ mod tests {
             config,
             Config {
                 live_reload: true,
+                assets_dir: None,
                 db_path: PathBuf::from("checks.db"),
                 interval: Duration::from_secs(1),
                 listen: default_listen(),
@@ -153,13 +551,17 @@ mod tests {
                     (
                         String::from("google"),
                         Ping {
-                            host: String::from("google.com")
+                            host: String::from("google.com"),
+                            previous_name: None,
+                            tags: BTreeMap::new(),
                         }
                     ),
                     (
                         String::from("yahoo"),
                         Ping {
-                            host: String::from("yahoo.com")
+                            host: String::from("yahoo.com"),
+                            previous_name: None,
+                            tags: BTreeMap::new(),
                         }
                     ),
                 ]),
@@ -167,9 +569,35 @@ mod tests {
                     String::from("google"),
                     Http {
                         url: String::from("https://google.com"),
-                        code: None
+                        code: None,
+                        previous_name: None,
+                        tags: BTreeMap::new(),
                     }
-                )])
+                )]),
+                backup: Backup::default(),
+                uptime: Uptime::default(),
+                incidents: Incidents::default(),
+                status: Status::default(),
+                auth: Auth::default(),
+                push: HashMap::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn backup() {
+        let config = r#"
+            [backup]
+            dir = "/var/lib/dialer/backups"
+            interval = "6h"
+            "#;
+        let config = Config::try_from(config).unwrap();
+        assert_eq!(
+            config.backup,
+            Backup {
+                dir: Some(PathBuf::from("/var/lib/dialer/backups")),
+                interval: Duration::from_secs(60 * 60 * 6),
+                ..Default::default()
             }
         );
     }
@@ -1,10 +1,18 @@
 use anyhow::{Context, Result};
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Connection;
-use std::{path::Path, sync::Arc};
+use regex::Regex;
+use rusqlite::{functions::FunctionFlags, Connection, OptionalExtension};
+use std::{path::Path, sync::Arc, time::Duration};
 
 type DbPool = r2d2::Pool<SqliteConnectionManager>;
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// the value of `db_path` which selects an in-memory database instead of a file.
+pub const MEMORY_PATH: &str = ":memory:";
+
+/// how long to wait for the single connection of an in-memory database
+const MEMORY_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone, Debug)]
 pub struct Db {
@@ -16,16 +24,19 @@ pub struct Db {
 pub struct Record {
     pub name: String,
     pub kind: String,
-    pub epoch: u64,
-    pub ms: u64,
+    pub epoch_ms: i64,
+    pub us: Option<i64>,
 }
 
 impl Db {
     pub async fn connect(path: &Path) -> Result<Self> {
+        if path == Path::new(MEMORY_PATH) {
+            return Self::in_memory().await;
+        }
         let path = path.to_path_buf();
         tokio::task::spawn_blocking(move || {
             Self::migrate(&path)?;
-            let mgr = SqliteConnectionManager::file(path);
+            let mgr = SqliteConnectionManager::file(path).with_init(init);
             let pool = r2d2::Pool::new(mgr).context("could not create db pool")?;
             let pool = Arc::new(pool);
             Ok(Db { pool })
@@ -33,10 +44,39 @@ impl Db {
         .await?
     }
 
+    /// creates an in-memory db which is dropped along with the last clone of the returned handle.
+    ///
+    /// the connection manager keeps a shared-cache connection open so that every connection in the
+    /// pool sees the same database. the pool is limited to a single connection because concurrent
+    /// writers on a shared cache fail with SQLITE_LOCKED instead of waiting on the busy handler.
+    /// code must not take a second connection while it holds one, which fails after
+    /// `MEMORY_TIMEOUT` rather than r2d2's default of 30s.
+    pub async fn in_memory() -> Result<Self> {
+        tokio::task::spawn_blocking(move || {
+            let mgr = SqliteConnectionManager::memory().with_init(init);
+            let pool = r2d2::Pool::builder()
+                .max_size(1)
+                .connection_timeout(MEMORY_TIMEOUT)
+                .max_lifetime(None)
+                .idle_timeout(None)
+                .build(mgr)
+                .context("could not create db pool")?;
+            let mut conn = pool.get()?;
+            migrate::migrations::runner().run(&mut *conn)?;
+            drop(conn);
+            let pool = Arc::new(pool);
+            Ok(Db { pool })
+        })
+        .await?
+    }
+
     /// migrates the db at the specified path. is not compatible with the sqlite pool so we open a
     /// connection manually.
     fn migrate(path: &Path) -> Result<()> {
         let mut conn = Connection::open(path)?;
+        // the journal mode is persistent. wal lets backups read a consistent snapshot without
+        // blocking the checker's writes.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
         migrate::migrations::runner().run(&mut conn)?;
         Ok(())
     }
@@ -62,6 +102,79 @@ impl Db {
     }
 }
 
+/// prepares each new connection in the pool. registers the `regexp` function which backs sqlite's
+/// `REGEXP` operator.
+fn init(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            // the pattern is compiled once per statement
+            let re = ctx.get_or_create_aux(0, |pattern| -> Result<Regex, BoxError> {
+                Ok(Regex::new(pattern.as_str()?)?)
+            })?;
+            let text = ctx.get_raw(1).as_str().unwrap_or_default();
+            Ok(re.is_match(text))
+        },
+    )
+}
+
+/// returns the id of the check with the specified name and kind, if it exists.
+pub fn find_check(conn: &Connection, name: &str, kind: &str) -> Result<Option<u64>> {
+    let id = conn
+        .query_row(
+            "select id from checks where name=?1 and kind=?2",
+            (name, kind),
+            |row| {
+                let id: u64 = row.get(0)?;
+                Ok(id)
+            },
+        )
+        .optional()?;
+    Ok(id)
+}
+
+/// finds or creates the check with the specified name and kind, returning its id. if the check
+/// does not exist but a check with the previous name does, that check is renamed so that its
+/// history is preserved.
+pub fn materialize(
+    conn: &Connection,
+    name: &str,
+    kind: &str,
+    previous_name: Option<&str>,
+) -> Result<u64> {
+    let mut id = find_check(conn, name, kind)?;
+    if let (None, Some(previous_name)) = (id, previous_name) {
+        id = find_check(conn, previous_name, kind)?;
+        if let Some(id) = id {
+            tracing::info!("Renaming {kind} check '{previous_name}' to '{name}'");
+            conn.execute("update checks set name=?1 where id=?2", (name, id))?;
+        }
+    }
+    let id = match id {
+        Some(id) => id,
+        None => conn.query_row(
+            "insert into checks (name, kind) values (?1, ?2) returning id",
+            (name, kind),
+            |row| {
+                let id: u64 = row.get(0)?;
+                Ok(id)
+            },
+        )?,
+    };
+    Ok(id)
+}
+
+/// undoes the debug formatting of a stored error. its causes are kept, but not its backtrace.
+pub fn unquote(err: &str) -> String {
+    let err = serde_json::from_str::<String>(err).unwrap_or_else(|_| err.to_string());
+    match err.split_once("\n\nStack backtrace:") {
+        Some((err, _)) => err.to_string(),
+        None => err,
+    }
+}
+
 mod migrate {
     refinery::embed_migrations!("./migrations");
 }
@@ -76,4 +189,84 @@ mod tests {
         let mut conn = Connection::open_in_memory().unwrap();
         migrate::migrations::runner().run(&mut conn).unwrap();
     }
+
+    #[test]
+    fn subsecond_migration() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate::migrations::runner()
+            .set_target(refinery::Target::Version(4))
+            .run(&mut conn)
+            .unwrap();
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('google', 'ping');
+            insert into results (check_id, epoch, ms, err) values (1, 60, 12, null), (1, 61, null, 'timeout');
+            ",
+        )
+        .unwrap();
+        migrate::migrations::runner().run(&mut conn).unwrap();
+        let mut stmt = conn
+            .prepare("select id, epoch_ms, us, err from results order by id")
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<(i64, i64, Option<i64>, Option<String>)>, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (1, 60_000, Some(12_000), None),
+                (2, 61_000, None, Some(String::from("timeout")))
+            ]
+        );
+        // new rows continue the id sequence and default to the current time
+        conn.execute("insert into results (check_id, us) values (1, 5)", [])
+            .unwrap();
+        let (id, epoch_ms): (i64, i64) = conn
+            .query_row("select id, epoch_ms from results where us = 5", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(id, 3);
+        assert!(epoch_ms > 1_700_000_000_000);
+    }
+
+    #[tokio::test]
+    async fn in_memory() {
+        let db = Db::connect(Path::new(MEMORY_PATH)).await.unwrap();
+        db.with_conn(|conn| {
+            conn.execute("insert into checks (name, kind) values ('foo', 'ping')", [])?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let count: u64 = db
+            .with_conn(|conn| {
+                Ok(conn.query_row("select count(*) from checks", [], |row| row.get(0))?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // a second in-memory db must not share state with the first
+        let other = Db::in_memory().await.unwrap();
+        let count: u64 = other
+            .with_conn(|conn| {
+                Ok(conn.query_row("select count(*) from checks", [], |row| row.get(0))?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+
+        // a second connection while the only one is held fails rather than waiting for long
+        let conn = other.conn().unwrap();
+        let started = std::time::Instant::now();
+        assert!(other.conn().is_err());
+        assert!(started.elapsed() < MEMORY_TIMEOUT * 2);
+        drop(conn);
+        assert!(other.conn().is_ok());
+    }
 }
@@ -0,0 +1,699 @@
+//! exports raw results or rollups as csv, ndjson or parquet. rows are written to the output as
+//! they are read from the db so that large exports do not need to be buffered in memory.
+use crate::{db, uptime};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use rusqlite::{types::Value, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+/// the most results read from the db at once
+const CHUNK_ROWS: usize = 10_000;
+
+/// the number of rows buffered in memory before a parquet row group is written
+const PARQUET_ROW_GROUP_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// describes which results to export and how
+#[derive(Clone, Debug, Default, Deserialize, clap::Args)]
+#[serde(default)]
+pub struct Request {
+    /// the start of the range to export. defaults to the first result.
+    #[arg(long)]
+    pub start: Option<DateTime<Utc>>,
+
+    /// the end of the range to export. defaults to now.
+    #[arg(long)]
+    pub end: Option<DateTime<Utc>>,
+
+    /// exports the most recent results, e.g. "24h". overrides start and end.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    pub last: Option<Duration>,
+
+    /// the names of the checks to export. may be repeated. defaults to all checks.
+    #[arg(long = "check")]
+    #[serde(rename = "check")]
+    pub checks: Vec<String>,
+
+    /// exports rollups with this resolution, e.g. "1m", instead of raw results.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    #[serde(with = "humantime_serde")]
+    pub rollup: Option<Duration>,
+
+    /// include checks which have been removed from the config
+    #[arg(long)]
+    pub archived: bool,
+
+    #[arg(long, value_enum, default_value = "csv")]
+    pub format: Format,
+}
+
+impl Request {
+    /// the start and end of the range to export
+    pub fn range(&self, now: DateTime<Utc>) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+        if let Some(last) = self.last {
+            return Ok((uptime::ago(now, last)?, now));
+        }
+        let start = self.start.unwrap_or(DateTime::UNIX_EPOCH);
+        let end = self.end.unwrap_or(now);
+        Ok((start, end))
+    }
+
+    /// the where clause and its params shared by the raw and rollup queries
+    fn filter(&self, start: i64, end: i64) -> (String, Vec<Value>) {
+        let mut sql = String::from(
+            "WHERE r.epoch_ms >= ? AND r.epoch_ms <= ? AND (? OR c.archived_at IS NULL)",
+        );
+        let mut params = vec![
+            Value::Integer(start),
+            Value::Integer(end),
+            Value::Integer(self.archived.into()),
+        ];
+        if !self.checks.is_empty() {
+            let placeholders = vec!["?"; self.checks.len()].join(",");
+            sql.push_str(&format!(" AND c.name IN ({placeholders})"));
+            params.extend(self.checks.iter().cloned().map(Value::Text));
+        }
+        (sql, params)
+    }
+}
+
+/// a single result
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Raw {
+    /// the time at which the probe started
+    pub ts: DateTime<Utc>,
+    pub name: String,
+    pub kind: String,
+    /// the latency in microseconds
+    pub us: Option<i64>,
+    pub err: Option<String>,
+}
+
+/// the results of a single check over one bucket of time
+#[derive(Debug, Serialize)]
+pub struct Rollup {
+    pub ts: DateTime<Utc>,
+    pub name: String,
+    pub kind: String,
+    pub count: i64,
+    pub errs: i64,
+    /// latencies in microseconds
+    pub min_us: Option<i64>,
+    pub avg_us: Option<i64>,
+    pub max_us: Option<i64>,
+}
+
+/// writes the results described by the request to the output. the results are read in chunks and
+/// the connection is returned to the pool between them, so that a slow output does not keep the
+/// checker from recording results.
+pub fn export(db: &db::Db, req: &Request, out: impl Write + Send) -> Result<()> {
+    let (start, end) = req.range(Utc::now())?;
+    if end <= start {
+        bail!("end date must be after start date");
+    }
+    let (start, end) = (start.timestamp_millis(), end.timestamp_millis());
+    let (filter, params) = req.filter(start, end);
+    match req.rollup {
+        None => {
+            let mut sink = Sink::<Raw, _>::new(req.format, out)?;
+            let sql = format!(
+                "
+                SELECT r.id, r.epoch_ms, c.name, c.kind, r.us, r.err
+                FROM results r
+                JOIN checks c on r.check_id = c.id
+                {filter}
+                AND r.epoch_ms >= ? AND (r.epoch_ms > ? OR r.id > ?)
+                ORDER BY r.epoch_ms, r.id
+                LIMIT {CHUNK_ROWS}
+                "
+            );
+            // the results are paged by their time and id, which is the order of the index
+            let mut after = (i64::MIN, i64::MIN);
+            loop {
+                let chunk = {
+                    let conn = db.conn()?;
+                    let mut stmt = conn.prepare_cached(&sql)?;
+                    let params = params
+                        .iter()
+                        .cloned()
+                        .chain([after.0, after.0, after.1].map(Value::Integer));
+                    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+                        let id: i64 = row.get(0)?;
+                        let epoch_ms: i64 = row.get(1)?;
+                        let err: Option<String> = row.get(5)?;
+                        Ok((id, epoch_ms, row.get(2)?, row.get(3)?, row.get(4)?, err))
+                    })?;
+                    rows.collect::<rusqlite::Result<Vec<_>>>()?
+                };
+                let done = chunk.len() < CHUNK_ROWS;
+                for (id, epoch_ms, name, kind, us, err) in chunk {
+                    after = (epoch_ms, id);
+                    sink.write(Raw {
+                        ts: timestamp(epoch_ms)?,
+                        name,
+                        kind,
+                        us,
+                        err: err.as_deref().map(db::unquote),
+                    })?;
+                }
+                if done {
+                    break;
+                }
+            }
+            sink.finish()
+        }
+        Some(rollup) => {
+            let rollup = rollup.as_millis().max(1) as i64;
+            let mut sink = Sink::<Rollup, _>::new(req.format, out)?;
+            let sql = format!(
+                "
+                SELECT
+                    r.epoch_ms / {rollup} * {rollup} AS bucket,
+                    c.name,
+                    c.kind,
+                    COUNT(*) AS count,
+                    COUNT(r.err) AS errs,
+                    MIN(r.us) AS min,
+                    CAST(AVG(r.us) as INTEGER) AS avg,
+                    MAX(r.us) AS max
+                FROM results r
+                JOIN checks c on r.check_id = c.id
+                {filter}
+                AND r.epoch_ms >= ? AND r.epoch_ms < ?
+                GROUP BY r.check_id, c.name, c.kind, bucket
+                ORDER BY bucket, c.name, c.kind
+                "
+            );
+            // each chunk ends with the bucket of the matching result CHUNK_ROWS after its start,
+            // so that no bucket is split across chunks
+            let next_sql = format!(
+                "
+                SELECT r.epoch_ms
+                FROM results r
+                JOIN checks c on r.check_id = c.id
+                {filter}
+                AND r.epoch_ms >= ?
+                ORDER BY r.epoch_ms
+                LIMIT 1 OFFSET {CHUNK_ROWS}
+                "
+            );
+            let mut from = start;
+            loop {
+                let (chunk, until) = {
+                    let conn = db.conn()?;
+                    let next_params = params.iter().cloned().chain([Value::Integer(from)]);
+                    let next: Option<i64> = conn
+                        .prepare_cached(&next_sql)?
+                        .query_row(rusqlite::params_from_iter(next_params), |row| row.get(0))
+                        .optional()?;
+                    // the last chunk runs to the end of the range
+                    let until = next
+                        .map(|ms| (ms / rollup + 1) * rollup)
+                        .filter(|until| *until <= end);
+                    let mut stmt = conn.prepare_cached(&sql)?;
+                    let params = params
+                        .iter()
+                        .cloned()
+                        .chain([from, until.unwrap_or(i64::MAX)].map(Value::Integer));
+                    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+                        let bucket: i64 = row.get(0)?;
+                        Ok((
+                            bucket,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                            row.get(5)?,
+                            row.get(6)?,
+                            row.get(7)?,
+                        ))
+                    })?;
+                    (rows.collect::<rusqlite::Result<Vec<_>>>()?, until)
+                };
+                for (bucket, name, kind, count, errs, min_us, avg_us, max_us) in chunk {
+                    sink.write(Rollup {
+                        ts: timestamp(bucket)?,
+                        name,
+                        kind,
+                        count,
+                        errs,
+                        min_us,
+                        avg_us,
+                        max_us,
+                    })?;
+                }
+                match until {
+                    Some(until) => from = until,
+                    None => break,
+                }
+            }
+            sink.finish()
+        }
+    }
+}
+
+/// exports to the file at the specified path, or to stdout if there is no path.
+pub async fn to_path(db: &db::Db, req: Request, path: Option<PathBuf>) -> Result<()> {
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || match path {
+        Some(path) => {
+            let file = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+            export(&db, &req, BufWriter::new(file))
+        }
+        None => export(&db, &req, BufWriter::new(io::stdout())),
+    })
+    .await
+    .context("blocking thread panicked")?
+}
+
+fn timestamp(epoch_ms: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(epoch_ms).context("could not convert epoch to timestamp")
+}
+
+/// a record which can be written as a row in a parquet file
+trait Record: Serialize {
+    /// the parquet message type of the record
+    const SCHEMA: &'static str;
+
+    /// appends the fields of the record to the columns, in schema order
+    fn append(self, columns: &mut [Column]);
+}
+
+impl Record for Raw {
+    const SCHEMA: &'static str = "
+        message result {
+            required int64 ts (TIMESTAMP(MILLIS,true));
+            required binary name (UTF8);
+            required binary kind (UTF8);
+            optional int64 us;
+            optional binary err (UTF8);
+        }";
+
+    fn append(self, columns: &mut [Column]) {
+        columns[0].push_i64(Some(self.ts.timestamp_millis()));
+        columns[1].push_str(Some(self.name));
+        columns[2].push_str(Some(self.kind));
+        columns[3].push_i64(self.us);
+        columns[4].push_str(self.err);
+    }
+}
+
+impl Record for Rollup {
+    const SCHEMA: &'static str = "
+        message rollup {
+            required int64 ts (TIMESTAMP(MILLIS,true));
+            required binary name (UTF8);
+            required binary kind (UTF8);
+            required int64 count;
+            required int64 errs;
+            optional int64 min_us;
+            optional int64 avg_us;
+            optional int64 max_us;
+        }";
+
+    fn append(self, columns: &mut [Column]) {
+        columns[0].push_i64(Some(self.ts.timestamp_millis()));
+        columns[1].push_str(Some(self.name));
+        columns[2].push_str(Some(self.kind));
+        columns[3].push_i64(Some(self.count));
+        columns[4].push_i64(Some(self.errs));
+        columns[5].push_i64(self.min_us);
+        columns[6].push_i64(self.avg_us);
+        columns[7].push_i64(self.max_us);
+    }
+}
+
+/// a buffered parquet column. definition levels are only written for optional columns.
+enum Column {
+    Int64 {
+        values: Vec<i64>,
+        defs: Vec<i16>,
+        optional: bool,
+    },
+    Str {
+        values: Vec<ByteArray>,
+        defs: Vec<i16>,
+        optional: bool,
+    },
+}
+
+impl Column {
+    fn push_i64(&mut self, value: Option<i64>) {
+        let Column::Int64 { values, defs, .. } = self else {
+            panic!("column is not an int64");
+        };
+        defs.push(value.is_some().into());
+        values.extend(value);
+    }
+
+    fn push_str(&mut self, value: Option<String>) {
+        let Column::Str { values, defs, .. } = self else {
+            panic!("column is not a string");
+        };
+        defs.push(value.is_some().into());
+        values.extend(value.map(|v| ByteArray::from(v.into_bytes())));
+    }
+}
+
+enum Sink<R, W: Write + Send> {
+    Csv(csv::Writer<W>),
+    Ndjson(W),
+    Parquet {
+        writer: SerializedFileWriter<W>,
+        columns: Vec<Column>,
+        rows: usize,
+        _record: std::marker::PhantomData<R>,
+    },
+}
+
+impl<R: Record, W: Write + Send> Sink<R, W> {
+    fn new(format: Format, out: W) -> Result<Self> {
+        Ok(match format {
+            Format::Csv => Sink::Csv(csv::Writer::from_writer(out)),
+            Format::Ndjson => Sink::Ndjson(out),
+            Format::Parquet => {
+                let schema = Arc::new(parse_message_type(R::SCHEMA).context("parse schema")?);
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let writer = SerializedFileWriter::new(out, schema, Arc::new(props))?;
+                let columns = writer
+                    .schema_descr()
+                    .columns()
+                    .iter()
+                    .map(|col| {
+                        let optional = col.max_def_level() > 0;
+                        match col.physical_type() {
+                            parquet::basic::Type::INT64 => Ok(Column::Int64 {
+                                values: vec![],
+                                defs: vec![],
+                                optional,
+                            }),
+                            parquet::basic::Type::BYTE_ARRAY => Ok(Column::Str {
+                                values: vec![],
+                                defs: vec![],
+                                optional,
+                            }),
+                            typ => bail!("unsupported column type: {typ}"),
+                        }
+                    })
+                    .collect::<Result<_>>()?;
+                Sink::Parquet {
+                    writer,
+                    columns,
+                    rows: 0,
+                    _record: std::marker::PhantomData,
+                }
+            }
+        })
+    }
+
+    fn write(&mut self, record: R) -> Result<()> {
+        match self {
+            Sink::Csv(writer) => writer.serialize(record)?,
+            Sink::Ndjson(out) => {
+                serde_json::to_writer(&mut *out, &record)?;
+                out.write_all(b"\n")?;
+            }
+            Sink::Parquet { columns, rows, .. } => {
+                record.append(columns);
+                *rows += 1;
+                if *rows >= PARQUET_ROW_GROUP_SIZE {
+                    self.flush_row_group()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_row_group(&mut self) -> Result<()> {
+        let Sink::Parquet {
+            writer,
+            columns,
+            rows,
+            ..
+        } = self
+        else {
+            return Ok(());
+        };
+        let mut row_group = writer.next_row_group()?;
+        for column in columns.iter_mut() {
+            let mut col = row_group
+                .next_column()?
+                .context("schema has fewer columns than the record")?;
+            match column {
+                Column::Int64 {
+                    values,
+                    defs,
+                    optional,
+                } => {
+                    let defs = optional.then_some(defs.as_slice());
+                    col.typed::<Int64Type>().write_batch(values, defs, None)?;
+                }
+                Column::Str {
+                    values,
+                    defs,
+                    optional,
+                } => {
+                    let defs = optional.then_some(defs.as_slice());
+                    col.typed::<ByteArrayType>()
+                        .write_batch(values, defs, None)?;
+                }
+            }
+            col.close()?;
+            match column {
+                Column::Int64 { values, defs, .. } => {
+                    values.clear();
+                    defs.clear();
+                }
+                Column::Str { values, defs, .. } => {
+                    values.clear();
+                    defs.clear();
+                }
+            }
+        }
+        row_group.close()?;
+        *rows = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Sink::Parquet { rows, .. } = &self {
+            if *rows > 0 {
+                self.flush_row_group()?;
+            }
+        }
+        let mut out = match self {
+            Sink::Csv(writer) => writer.into_inner().map_err(|err| err.into_error())?,
+            Sink::Ndjson(out) => out,
+            Sink::Parquet { writer, .. } => writer.into_inner()?,
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn db() -> db::Db {
+        let db = db::Db::in_memory().await.unwrap();
+        db.with_conn(|conn| {
+            conn.execute_batch(
+                "
+                insert into checks (name, kind) values ('google', 'ping'), ('yahoo', 'http');
+                insert into results (check_id, epoch_ms, us, err) values
+                    (1, 60000, 10000, null),
+                    (1, 61250, 20500, null),
+                    (2, 61250, null, '\"timeout\"'),
+                    (1, 125000, 30000, null);
+                ",
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        db
+    }
+
+    fn run(db: &db::Db, req: &Request) -> String {
+        let mut out = vec![];
+        export(db, req, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn csv() {
+        let db = db().await;
+        let req = Request {
+            checks: vec![String::from("google")],
+            ..Default::default()
+        };
+        assert_eq!(
+            run(&db, &req),
+            "ts,name,kind,us,err\n\
+             1970-01-01T00:01:00Z,google,ping,10000,\n\
+             1970-01-01T00:01:01.250Z,google,ping,20500,\n\
+             1970-01-01T00:02:05Z,google,ping,30000,\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn ndjson_rollup() {
+        let db = db().await;
+        let req = Request {
+            rollup: Some(Duration::from_secs(60)),
+            format: Format::Ndjson,
+            ..Default::default()
+        };
+        let out = run(&db, &req);
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["name"], "google");
+        assert_eq!(lines[0]["count"], 2);
+        assert_eq!(lines[0]["avg_us"], 15250);
+        assert_eq!(lines[1]["name"], "yahoo");
+        assert_eq!(lines[1]["errs"], 1);
+        assert_eq!(lines[1]["avg_us"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn chunks() {
+        let db = db::Db::in_memory().await.unwrap();
+        db.with_conn(|conn| {
+            conn.execute_batch(
+                "
+                insert into checks (name, kind) values ('google', 'ping');
+                with recursive n(i) as (select 0 union all select i + 1 from n where i < 24999)
+                insert into results (check_id, epoch_ms, us) select 1, i, 1000 from n;
+                ",
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let out = run(&db, &Request::default());
+        assert_eq!(out.lines().count(), 25_001);
+
+        // buckets which span the chunks are not split
+        let req = Request {
+            rollup: Some(Duration::from_millis(3000)),
+            format: Format::Ndjson,
+            ..Default::default()
+        };
+        let counts: Vec<i64> = run(&db, &req)
+            .lines()
+            .map(|line| {
+                let rollup: serde_json::Value = serde_json::from_str(line).unwrap();
+                rollup["count"].as_i64().unwrap()
+            })
+            .collect();
+        assert_eq!(counts.len(), 9);
+        assert!(counts[..8].iter().all(|count| *count == 3000));
+        assert_eq!(counts[8], 1000);
+
+        // the chunks follow the range and the checks of the request
+        db.with_conn(|conn| {
+            conn.execute_batch(
+                "
+                insert into checks (name, kind) values ('yahoo', 'http');
+                with recursive n(i) as (select 0 union all select i + 1 from n where i < 24999)
+                insert into results (check_id, epoch_ms, us) select 2, i, 1000 from n;
+                ",
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let req = Request {
+            start: DateTime::from_timestamp_millis(1500),
+            end: DateTime::from_timestamp_millis(22_499),
+            checks: vec![String::from("yahoo")],
+            ..req
+        };
+        let rollups: Vec<(String, i64)> = run(&db, &req)
+            .lines()
+            .map(|line| {
+                let rollup: serde_json::Value = serde_json::from_str(line).unwrap();
+                let name = rollup["name"].as_str().unwrap().to_string();
+                (name, rollup["count"].as_i64().unwrap())
+            })
+            .collect();
+        assert_eq!(rollups.len(), 8);
+        assert!(rollups.iter().all(|(name, _)| name == "yahoo"));
+        assert_eq!(rollups[0].1, 1500);
+        assert!(rollups[1..7].iter().all(|(_, count)| *count == 3000));
+        assert_eq!(rollups[7].1, 1500);
+    }
+
+    #[tokio::test]
+    async fn parquet() {
+        use parquet::{
+            file::reader::{FileReader, SerializedFileReader},
+            record::RowAccessor,
+        };
+        let db = db().await;
+        let req = Request {
+            format: Format::Parquet,
+            ..Default::default()
+        };
+        let mut out = vec![];
+        export(&db, &req, &mut out).unwrap();
+        let reader = SerializedFileReader::new(axum::body::Bytes::from(out)).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 4);
+        let rows: Vec<parquet::record::Row> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(
+            rows[2].to_string(),
+            r#"{ts: 1970-01-01 00:01:01 +00:00, name: "yahoo", kind: "http", us: null, err: "timeout"}"#
+        );
+        assert_eq!(rows[2].get_timestamp_millis(0).unwrap(), 61250);
+    }
+}
@@ -0,0 +1,435 @@
+//! imports results from another dialer db or from an ndjson export. checks are mapped onto the
+//! local checks by name and kind, and results which already exist locally are skipped so that the
+//! same source can be imported more than once.
+use crate::{db, export};
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Sqlite,
+    Ndjson,
+}
+
+impl Format {
+    /// guesses the format from the extension of the path, defaulting to sqlite
+    fn detect(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ndjson" | "jsonl") => Format::Ndjson,
+            _ => Format::Sqlite,
+        }
+    }
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Request {
+    /// the dialer db or ndjson export to import
+    pub path: PathBuf,
+
+    /// the format of the file. detected from the file extension by default.
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+
+    /// tags the imported results. defaults to the file name.
+    #[arg(long)]
+    pub source: Option<String>,
+}
+
+impl Request {
+    fn source(&self) -> String {
+        self.source.clone().unwrap_or_else(|| {
+            self.path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.path.display().to_string())
+        })
+    }
+}
+
+/// the outcome of an import
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+/// imports the file described by the request into the db.
+pub async fn import(db: &db::Db, req: Request) -> Result<Summary> {
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = db.conn().context("get conn")?;
+        let source = req.source();
+        let format = req.format.unwrap_or_else(|| Format::detect(&req.path));
+        let summary = match format {
+            Format::Sqlite => import_sqlite(&mut conn, &req.path, &source)?,
+            Format::Ndjson => {
+                let file = File::open(&req.path)
+                    .with_context(|| format!("open {}", req.path.display()))?;
+                import_ndjson(&mut conn, BufReader::new(file), &source)?
+            }
+        };
+        tracing::info!(
+            "Imported {} results from '{source}', skipped {} duplicates",
+            summary.imported,
+            summary.skipped
+        );
+        Ok(summary)
+    })
+    .await
+    .context("blocking thread panicked")?
+}
+
+/// maps remote checks onto local ones, creating them if they do not exist. checks which are
+/// created by an import are archived since they are not part of the local config.
+#[derive(Default)]
+struct Checks(HashMap<(String, String), u64>);
+
+impl Checks {
+    fn get(&mut self, conn: &Connection, name: &str, kind: &str) -> Result<u64> {
+        if let Some(id) = self.0.get(&(name.to_string(), kind.to_string())) {
+            return Ok(*id);
+        }
+        let existing = db::find_check(conn, name, kind)?;
+        let id = db::materialize(conn, name, kind, None)?;
+        if existing.is_none() {
+            conn.execute(
+                "update checks set archived_at=CAST(strftime('%s', 'now') AS INTEGER) where id=?1",
+                [id],
+            )?;
+        }
+        self.0.insert((name.to_string(), kind.to_string()), id);
+        Ok(id)
+    }
+}
+
+fn import_sqlite(conn: &mut Connection, path: &Path, source: &str) -> Result<Summary> {
+    if !path.exists() {
+        bail!("no such file: {}", path.display());
+    }
+    let uri = format!("file:{}?mode=ro", uri_path(path));
+    conn.execute("attach database ?1 as src", [&uri])
+        .context("attach source db")?;
+    let res = (|| {
+        let tx = conn.transaction()?;
+        let mut checks = Checks::default();
+        let remote = {
+            let mut stmt = tx.prepare("select id, name, kind from src.checks")?;
+            let rows = stmt.query_map([], |row| {
+                let id: u64 = row.get(0)?;
+                let name: String = row.get(1)?;
+                let kind: String = row.get(2)?;
+                Ok((id, name, kind))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        // dbs from before sub-second timestamps record whole seconds and milliseconds
+        let subsecond: bool = tx.query_row(
+            "select count(*) > 0 from pragma_table_info('results', 'src') where name = 'epoch_ms'",
+            [],
+            |row| row.get(0),
+        )?;
+        let (epoch_ms, us) = if subsecond {
+            ("r.epoch_ms", "r.us")
+        } else {
+            ("r.epoch * 1000", "r.ms * 1000")
+        };
+        let mut summary = Summary::default();
+        for (remote_id, name, kind) in remote {
+            let local_id = checks.get(&tx, &name, &kind)?;
+            let total: usize = tx.query_row(
+                "select count(*) from src.results where check_id=?1",
+                [remote_id],
+                |row| row.get(0),
+            )?;
+            let imported = tx.execute(
+                &format!(
+                    "
+                    insert into results (check_id, epoch_ms, us, err, source)
+                    select ?1, {epoch_ms}, {us}, r.err, ?2
+                    from src.results r
+                    where r.check_id = ?3
+                    and not exists (
+                        select 1 from results l
+                        where l.check_id = ?1
+                        and l.epoch_ms = {epoch_ms}
+                        and l.us is {us}
+                        and l.err is r.err
+                    )
+                    "
+                ),
+                (local_id, source, remote_id),
+            )?;
+            summary.imported += imported;
+            summary.skipped += total - imported;
+        }
+        tx.commit()?;
+        anyhow::Ok(summary)
+    })();
+    conn.execute("detach database src", [])
+        .context("detach source db")?;
+    res
+}
+
+/// percent-encodes the characters which sqlite gives a meaning in a uri filename
+fn uri_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '%' | '?' | '#' => encoded.push_str(&format!("%{:02X}", c as u8)),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+fn import_ndjson(conn: &mut Connection, reader: impl BufRead, source: &str) -> Result<Summary> {
+    let tx = conn.transaction()?;
+    let mut checks = Checks::default();
+    let mut summary = Summary::default();
+    {
+        let mut insert = tx.prepare(
+            "
+            insert into results (check_id, epoch_ms, us, err, source)
+            select ?1, ?2, ?3, ?4, ?5
+            where not exists (
+                select 1 from results
+                where check_id = ?1 and epoch_ms = ?2 and us is ?3 and err is ?4
+            )
+            ",
+        )?;
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let raw: export::Raw =
+                serde_json::from_str(&line).with_context(|| format!("parse line {}", idx + 1))?;
+            let id = checks.get(&tx, &raw.name, &raw.kind)?;
+            // errors are exported without the debug formatting they are stored with
+            let err = raw.err.map(|err| format!("{err:?}"));
+            let imported = insert.execute((id, raw.ts.timestamp_millis(), raw.us, &err, source))?;
+            summary.imported += imported;
+            summary.skipped += 1 - imported;
+        }
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: &str = "
+        insert into checks (name, kind) values ('google', 'ping'), ('laptop', 'http');
+        insert into results (check_id, epoch_ms, us, err) values
+            (1, 60000, 10000, null),
+            (1, 61000, null, '\"timeout\"'),
+            (2, 61000, 20000, null);
+    ";
+
+    async fn results(db: &db::Db) -> Vec<(String, i64, Option<i64>, Option<String>)> {
+        db.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "select c.name, r.epoch_ms, r.us, r.source from results r
+                 join checks c on r.check_id = c.id order by r.epoch_ms, c.name",
+            )?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("laptop.db");
+        let remote = db::Db::connect(&path).await.unwrap();
+        remote
+            .with_conn(|conn| Ok(conn.execute_batch(SEED)?))
+            .await
+            .unwrap();
+
+        let local = db::Db::in_memory().await.unwrap();
+        local
+            .with_conn(|conn| {
+                conn.execute_batch(
+                    "
+                    insert into checks (name, kind) values ('google', 'ping');
+                    insert into results (check_id, epoch_ms, us) values (1, 60000, 10000), (1, 62000, 12000);
+                    ",
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        let req = Request {
+            path: path.clone(),
+            format: None,
+            source: None,
+        };
+        let summary = import(&local, req.clone()).await.unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                imported: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(
+            results(&local).await,
+            vec![
+                ("google".into(), 60000, Some(10000), None),
+                ("google".into(), 61000, None, Some("laptop".into())),
+                ("laptop".into(), 61000, Some(20000), Some("laptop".into())),
+                ("google".into(), 62000, Some(12000), None),
+            ]
+        );
+
+        // importing again is a noop
+        let summary = import(&local, req).await.unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                imported: 0,
+                skipped: 3
+            }
+        );
+
+        // checks which only exist in the import are archived
+        let archived: bool = local
+            .with_conn(|conn| {
+                Ok(conn.query_row(
+                    "select archived_at is not null from checks where name='laptop'",
+                    [],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .unwrap();
+        assert!(archived);
+    }
+
+    #[tokio::test]
+    async fn sqlite_legacy() {
+        // dbs from before sub-second timestamps, at a path which needs encoding in a uri
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old?#%25.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "
+                create table checks (id integer primary key, name text not null, kind text not null);
+                create table results (check_id integer not null, epoch integer not null, ms integer, err text);
+                insert into checks (name, kind) values ('google', 'ping');
+                insert into results (check_id, epoch, ms, err) values (1, 60, 12, null), (1, 61, null, '\"timeout\"');
+                ",
+            )
+            .unwrap();
+
+        let local = db::Db::in_memory().await.unwrap();
+        let req = Request {
+            path,
+            format: None,
+            source: Some("old".into()),
+        };
+        let summary = import(&local, req.clone()).await.unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                imported: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(
+            results(&local).await,
+            vec![
+                ("google".into(), 60000, Some(12000), Some("old".into())),
+                ("google".into(), 61000, None, Some("old".into())),
+            ]
+        );
+        let summary = import(&local, req).await.unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                imported: 0,
+                skipped: 2
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn ndjson() {
+        let remote = db::Db::in_memory().await.unwrap();
+        remote
+            .with_conn(|conn| Ok(conn.execute_batch(SEED)?))
+            .await
+            .unwrap();
+        let mut out = vec![];
+        export::export(
+            &remote,
+            &export::Request {
+                format: export::Format::Ndjson,
+                ..Default::default()
+            },
+            &mut out,
+        )
+        .unwrap();
+
+        let local = db::Db::in_memory().await.unwrap();
+        let mut conn = local.conn().unwrap();
+        let summary = import_ndjson(&mut conn, out.as_slice(), "travel").unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                imported: 3,
+                skipped: 0
+            }
+        );
+        let summary = import_ndjson(&mut conn, out.as_slice(), "travel").unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                imported: 0,
+                skipped: 3
+            }
+        );
+        drop(conn);
+        assert_eq!(
+            results(&local).await,
+            vec![
+                ("google".into(), 60000, Some(10000), Some("travel".into())),
+                ("google".into(), 61000, None, Some("travel".into())),
+                ("laptop".into(), 61000, Some(20000), Some("travel".into())),
+            ]
+        );
+
+        // errors are stored as the checker stores them, although they are exported unquoted
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains(r#""err":"timeout""#));
+        let err: String = local
+            .with_conn(|conn| {
+                Ok(
+                    conn.query_row("select err from results where err is not null", [], |row| {
+                        row.get(0)
+                    })?,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(err, r#""timeout""#);
+    }
+}
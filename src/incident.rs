@@ -0,0 +1,571 @@
+//! derives incidents from contiguous periods of failures. failures of any check which are closer
+//! together than the merge gap belong to the same incident, and an incident ends once every
+//! affected check has recovered.
+//!
+//! incidents are derived incrementally. an incident is final once its merge gap has passed
+//! without another failure. each pass records the time before which the results are settled, and
+//! the next pass derives everything after it again so that ongoing incidents can grow.
+use crate::{checker, config, db, uptime};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{types::Value, Connection};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+use tracing::instrument;
+
+/// an incident and the checks it affected
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Incident {
+    pub id: u64,
+    /// the time of the first failure
+    pub start: DateTime<Utc>,
+    /// the time at which every affected check had recovered. none while the incident is ongoing.
+    pub end: Option<DateTime<Utc>>,
+    /// the most common error during the incident
+    pub err: Option<String>,
+    pub failures: u64,
+    pub checks: Vec<Affected>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Affected {
+    pub name: String,
+    pub kind: checker::Kind,
+    pub failures: u64,
+}
+
+/// an incident along with a breakdown of its errors
+#[derive(Debug, Serialize)]
+pub struct Detail {
+    #[serde(flatten)]
+    pub incident: Incident,
+    pub errors: Vec<ErrorCount>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ErrorCount {
+    pub err: String,
+    pub count: u64,
+}
+
+/// describes which incidents to list
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ListRequest {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    /// lists incidents from the most recent period, e.g. "7d". overrides start and end.
+    #[serde(with = "humantime_serde")]
+    pub last: Option<Duration>,
+    /// only lists incidents which affected these checks. may be repeated.
+    #[serde(rename = "check")]
+    pub checks: Vec<String>,
+    /// only lists incidents which affected checks of this kind
+    pub kind: Option<checker::Kind>,
+    pub limit: usize,
+}
+
+impl Default for ListRequest {
+    fn default() -> Self {
+        Self {
+            start: None,
+            end: None,
+            last: None,
+            checks: vec![],
+            kind: None,
+            limit: 100,
+        }
+    }
+}
+
+impl ListRequest {
+    /// the start and end of the range to list. defaults to all incidents.
+    pub fn range(&self, now: DateTime<Utc>) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+        if let Some(last) = self.last {
+            return Ok((uptime::ago(now, last)?, now));
+        }
+        let start = self.start.unwrap_or(DateTime::UNIX_EPOCH);
+        let end = self.end.unwrap_or(now);
+        Ok((start, end))
+    }
+}
+
+/// detects incidents on each interval.
+#[instrument(skip_all)]
+pub async fn run(db: db::Db, config: config::Incidents) -> Result<()> {
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let config = config.clone();
+        let res = db
+            .with_conn(move |mut conn| detect(&mut conn, &config, Utc::now()))
+            .await;
+        if let Err(err) = res {
+            tracing::error!("incident detection failed: {err:?}");
+        }
+    }
+}
+
+/// an incident which is being derived from the results
+struct Pending {
+    start: i64,
+    last_failure: i64,
+    recovered: Option<i64>,
+    failing: HashSet<u64>,
+    checks: BTreeMap<u64, u64>,
+    errs: HashMap<String, u64>,
+    failures: u64,
+}
+
+impl Pending {
+    fn new(start: i64) -> Self {
+        Self {
+            start,
+            last_failure: start,
+            recovered: None,
+            failing: HashSet::new(),
+            checks: BTreeMap::new(),
+            errs: HashMap::new(),
+            failures: 0,
+        }
+    }
+
+    /// the most common error, preferring the first alphabetically on a tie
+    fn dominant_err(&self) -> Option<&str> {
+        self.errs
+            .iter()
+            .max_by(|(a, x), (b, y)| x.cmp(y).then(b.cmp(a)))
+            .map(|(err, _)| err.as_str())
+    }
+}
+
+/// derives incidents from the results which are not yet covered by a final incident, replacing
+/// the incidents which were not yet final. returns the number of incidents that were written.
+pub fn detect(
+    conn: &mut Connection,
+    config: &config::Incidents,
+    now: DateTime<Utc>,
+) -> Result<usize> {
+    let gap = config.merge_gap.as_millis() as i64;
+    let tx = conn.transaction()?;
+    // incidents which may still change are derived again. their ids are reused so that links to
+    // an ongoing incident keep working.
+    let mut reuse: VecDeque<u64> = {
+        let mut stmt = tx.prepare(
+            "select id from incidents where end_ms is null or end_ms + ?1 > ?2 order by start_ms",
+        )?;
+        let ids = stmt.query_map((gap, now.timestamp_millis()), |row| row.get(0))?;
+        ids.collect::<Result<_, _>>()?
+    };
+    // the first pass scans every result, and later passes start from where the previous one
+    // stopped or from the earliest incident which may still change
+    let from: i64 = tx.query_row(
+        "
+        select coalesce(
+            (
+                select min(ms) from (
+                    select scanned_ms as ms from incident_scan
+                    union all
+                    select start_ms from incidents where end_ms is null or end_ms + ?1 > ?2
+                )
+            ),
+            (select max(end_ms) from incidents),
+            0
+        )
+        ",
+        (gap, now.timestamp_millis()),
+        |row| row.get(0),
+    )?;
+    for id in &reuse {
+        tx.execute("delete from incident_checks where incident_id = ?1", [id])?;
+        tx.execute("delete from incidents where id = ?1", [id])?;
+    }
+
+    let mut derived = vec![];
+    {
+        let mut stmt = tx.prepare(
+            "
+            select r.check_id, r.epoch_ms, r.err
+            from results r
+            join checks c on r.check_id = c.id
+            where r.epoch_ms >= ?1
+            and c.archived_at is null
+            order by r.epoch_ms, r.id
+            ",
+        )?;
+        let mut rows = stmt.query([from])?;
+        let mut pending: Option<Pending> = None;
+        while let Some(row) = rows.next()? {
+            let check_id: u64 = row.get(0)?;
+            let epoch_ms: i64 = row.get(1)?;
+            let err: Option<String> = row.get(2)?;
+            match err {
+                Some(err) => {
+                    if pending
+                        .as_ref()
+                        .is_some_and(|p| epoch_ms - p.last_failure > gap)
+                    {
+                        derived.extend(pending.take());
+                    }
+                    let p = pending.get_or_insert_with(|| Pending::new(epoch_ms));
+                    p.last_failure = epoch_ms;
+                    p.recovered = None;
+                    p.failing.insert(check_id);
+                    *p.checks.entry(check_id).or_default() += 1;
+                    *p.errs.entry(err).or_default() += 1;
+                    p.failures += 1;
+                }
+                None => {
+                    if let Some(p) = &mut pending {
+                        p.failing.remove(&check_id);
+                        if p.failing.is_empty() && p.recovered.is_none() {
+                            p.recovered = Some(epoch_ms);
+                        }
+                    }
+                }
+            }
+        }
+        // the results before the merge gap are settled, unless they belong to the latest
+        // incident which may still grow
+        let mut settled = now.timestamp_millis() - gap;
+        if let Some(p) = pending.as_ref().filter(|p| {
+            p.recovered
+                .is_none_or(|end| end + gap > now.timestamp_millis())
+        }) {
+            settled = settled.min(p.start);
+        }
+        tx.execute(
+            "
+            insert into incident_scan (id, scanned_ms) values (1, ?1)
+            on conflict (id) do update set scanned_ms = excluded.scanned_ms
+            ",
+            [settled],
+        )?;
+        derived.extend(pending);
+    }
+
+    let mut written = 0;
+    for p in derived.iter().filter(|p| p.failures >= config.min_failures) {
+        tx.execute(
+            "insert into incidents (id, start_ms, end_ms, err, failures) values (?1, ?2, ?3, ?4, ?5)",
+            (reuse.pop_front(), p.start, p.recovered, p.dominant_err(), p.failures),
+        )?;
+        let id = tx.last_insert_rowid();
+        for (check_id, failures) in &p.checks {
+            tx.execute(
+                "insert into incident_checks (incident_id, check_id, failures) values (?1, ?2, ?3)",
+                (id, check_id, failures),
+            )?;
+        }
+        written += 1;
+    }
+    tx.commit()?;
+    Ok(written)
+}
+
+/// lists the incidents which overlap the requested range, most recent first.
+pub fn list(conn: &Connection, req: &ListRequest, now: DateTime<Utc>) -> Result<Vec<Incident>> {
+    let (start, end) = req.range(now)?;
+    let (start, end) = (start.timestamp_millis(), end.timestamp_millis());
+    let mut sql = String::from(
+        "
+        select i.id, i.start_ms, i.end_ms, i.err, i.failures
+        from incidents i
+        where i.start_ms <= ?
+        and coalesce(i.end_ms, ?) >= ?
+        ",
+    );
+    let mut params = vec![
+        Value::Integer(end),
+        Value::Integer(end),
+        Value::Integer(start),
+    ];
+    if !req.checks.is_empty() || req.kind.is_some() {
+        sql.push_str(
+            "
+            and exists (
+                select 1 from incident_checks ic
+                join checks c on ic.check_id = c.id
+                where ic.incident_id = i.id
+            ",
+        );
+        if !req.checks.is_empty() {
+            let placeholders = vec!["?"; req.checks.len()].join(",");
+            sql.push_str(&format!(" and c.name in ({placeholders})"));
+            params.extend(req.checks.iter().cloned().map(Value::Text));
+        }
+        if let Some(kind) = req.kind {
+            sql.push_str(" and c.kind = ?");
+            params.push(Value::Text(kind.as_str().to_string()));
+        }
+        sql.push(')');
+    }
+    sql.push_str(" order by i.start_ms desc limit ?");
+    params.push(Value::Integer(req.limit.try_into().unwrap_or(i64::MAX)));
+    let mut stmt = conn.prepare_cached(&sql)?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+    let mut incidents = vec![];
+    while let Some(row) = rows.next()? {
+        incidents.push(from_row(conn, row)?);
+    }
+    Ok(incidents)
+}
+
+/// returns the incident with the specified id along with a breakdown of its errors.
+pub fn get(conn: &Connection, id: u64, now: DateTime<Utc>) -> Result<Option<Detail>> {
+    let mut stmt = conn.prepare_cached(
+        "select id, start_ms, end_ms, err, failures from incidents where id = ?1",
+    )?;
+    let mut rows = stmt.query([id])?;
+    let Some(row) = rows.next()? else {
+        return Ok(None);
+    };
+    let incident = from_row(conn, row)?;
+    let end = incident.end.unwrap_or(now);
+    let mut stmt = conn.prepare_cached(
+        "
+        select r.err, count(*)
+        from results r
+        join incident_checks ic on r.check_id = ic.check_id
+        where ic.incident_id = ?1
+        and r.epoch_ms >= ?2
+        and r.epoch_ms <= ?3
+        and r.err is not null
+        group by r.err
+        order by count(*) desc, r.err
+        ",
+    )?;
+    let errors = stmt
+        .query_map(
+            (
+                id,
+                incident.start.timestamp_millis(),
+                end.timestamp_millis(),
+            ),
+            |row| {
+                Ok(ErrorCount {
+                    err: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Detail { incident, errors }))
+}
+
+fn from_row(conn: &Connection, row: &rusqlite::Row) -> Result<Incident> {
+    let id: u64 = row.get("id")?;
+    let start: i64 = row.get("start_ms")?;
+    let end: Option<i64> = row.get("end_ms")?;
+    let start = DateTime::from_timestamp_millis(start).context("invalid incident start")?;
+    let end = end
+        .map(|end| DateTime::from_timestamp_millis(end).context("invalid incident end"))
+        .transpose()?;
+    let mut stmt = conn.prepare_cached(
+        "
+        select c.name, c.kind, ic.failures
+        from incident_checks ic
+        join checks c on ic.check_id = c.id
+        where ic.incident_id = ?1
+        order by c.name, c.kind
+        ",
+    )?;
+    let mut rows = stmt.query([id])?;
+    let mut checks = vec![];
+    while let Some(row) = rows.next()? {
+        let kind: String = row.get(1)?;
+        checks.push(Affected {
+            name: row.get(0)?,
+            kind: checker::Kind::try_from(kind.as_str())?,
+            failures: row.get(2)?,
+        });
+    }
+    Ok(Incident {
+        id,
+        start,
+        end,
+        err: row.get("err")?,
+        failures: row.get("failures")?,
+        checks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> config::Incidents {
+        config::Incidents {
+            merge_gap: Duration::from_secs(10),
+            min_failures: 2,
+            ..Default::default()
+        }
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn detect() {
+        let db = db::Db::in_memory().await.unwrap();
+        let mut conn = db.conn().unwrap();
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('gateway', 'ping'), ('google', 'http');
+            insert into results (check_id, epoch_ms, us, err) values
+                (1, 0, 1000, null),
+                -- the gateway goes down and takes google with it
+                (1, 1000, null, 'timeout'),
+                (2, 1500, null, 'dns'),
+                (1, 2000, null, 'timeout'),
+                (1, 3000, 1000, null),
+                -- google recovers last, which ends the incident
+                (2, 4000, 2000, null),
+                -- a failure within the merge gap belongs to the same incident
+                (1, 9000, null, 'timeout'),
+                (1, 10000, 1000, null),
+                -- a single failure is ignored
+                (2, 30000, null, 'dns'),
+                (2, 31000, 2000, null),
+                -- an ongoing incident
+                (1, 60000, null, 'timeout'),
+                (1, 61000, null, 'unreachable'),
+                (1, 62000, null, 'unreachable');
+            ",
+        )
+        .unwrap();
+        assert_eq!(super::detect(&mut conn, &config(), at(63)).unwrap(), 2);
+        let incidents = list(&conn, &ListRequest::default(), at(63)).unwrap();
+        assert_eq!(
+            incidents,
+            vec![
+                Incident {
+                    id: 2,
+                    start: DateTime::from_timestamp_millis(60000).unwrap(),
+                    end: None,
+                    err: Some(String::from("unreachable")),
+                    failures: 3,
+                    checks: vec![Affected {
+                        name: String::from("gateway"),
+                        kind: checker::Kind::Ping,
+                        failures: 3,
+                    }],
+                },
+                Incident {
+                    id: 1,
+                    start: DateTime::from_timestamp_millis(1000).unwrap(),
+                    end: Some(DateTime::from_timestamp_millis(10000).unwrap()),
+                    err: Some(String::from("timeout")),
+                    failures: 4,
+                    checks: vec![
+                        Affected {
+                            name: String::from("gateway"),
+                            kind: checker::Kind::Ping,
+                            failures: 3,
+                        },
+                        Affected {
+                            name: String::from("google"),
+                            kind: checker::Kind::Http,
+                            failures: 1,
+                        },
+                    ],
+                },
+            ]
+        );
+
+        // the ongoing incident recovers and keeps its id
+        conn.execute(
+            "insert into results (check_id, epoch_ms, us) values (1, 65000, 1000)",
+            [],
+        )
+        .unwrap();
+        assert_eq!(super::detect(&mut conn, &config(), at(100)).unwrap(), 1);
+        let detail = get(&conn, 2, at(100)).unwrap().unwrap();
+        assert_eq!(
+            detail.incident.end,
+            Some(DateTime::from_timestamp_millis(65000).unwrap())
+        );
+        assert_eq!(
+            detail.errors,
+            vec![
+                ErrorCount {
+                    err: String::from("unreachable"),
+                    count: 2
+                },
+                ErrorCount {
+                    err: String::from("timeout"),
+                    count: 1
+                },
+            ]
+        );
+
+        // final incidents are not derived again
+        assert_eq!(super::detect(&mut conn, &config(), at(100)).unwrap(), 0);
+        let req = ListRequest {
+            checks: vec![String::from("google")],
+            ..Default::default()
+        };
+        let ids: Vec<_> = list(&conn, &req, at(100))
+            .unwrap()
+            .iter()
+            .map(|i| i.id)
+            .collect();
+        assert_eq!(ids, vec![1]);
+        let req = ListRequest {
+            checks: vec![String::from("google")],
+            kind: Some(checker::Kind::Ping),
+            ..Default::default()
+        };
+        assert!(list(&conn, &req, at(100)).unwrap().is_empty());
+        let req = ListRequest {
+            kind: Some(checker::Kind::Ping),
+            limit: 1,
+            ..Default::default()
+        };
+        assert_eq!(list(&conn, &req, at(100)).unwrap().len(), 1);
+        assert!(get(&conn, 3, at(100)).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn scan() {
+        let db = db::Db::in_memory().await.unwrap();
+        let mut conn = db.conn().unwrap();
+        let scanned = |conn: &Connection| -> i64 {
+            conn.query_row("select scanned_ms from incident_scan", [], |row| row.get(0))
+                .unwrap()
+        };
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('gateway', 'ping');
+            insert into results (check_id, epoch_ms, us, err) values (1, 1000, 1000, null);
+            ",
+        )
+        .unwrap();
+        // without any incidents, the next pass starts from the merge gap before now
+        assert_eq!(super::detect(&mut conn, &config(), at(100)).unwrap(), 0);
+        assert_eq!(scanned(&conn), 90000);
+
+        // a failure which is too few for an incident yet is scanned again by the next pass
+        conn.execute(
+            "insert into results (check_id, epoch_ms, err) values (1, 95000, 'timeout')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(super::detect(&mut conn, &config(), at(96)).unwrap(), 0);
+        assert_eq!(scanned(&conn), 86000);
+        conn.execute(
+            "insert into results (check_id, epoch_ms, err) values (1, 97000, 'timeout')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(super::detect(&mut conn, &config(), at(98)).unwrap(), 1);
+        let incidents = list(&conn, &ListRequest::default(), at(98)).unwrap();
+        assert_eq!(incidents[0].failures, 2);
+        assert_eq!(scanned(&conn), 88000);
+    }
+}
@@ -1,5 +1,15 @@
 pub mod app;
+pub mod backup;
 pub mod checker;
 pub mod config;
 pub mod db;
+pub mod export;
+pub mod import;
+pub mod incident;
+pub mod percentile;
+pub mod push;
+pub mod reload;
+pub mod stats;
+pub mod status;
+pub mod uptime;
 pub mod web;
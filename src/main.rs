@@ -1,5 +1,5 @@
 use clap::Parser;
-use dialer::{app::App, config::Config};
+use dialer::{app::App, backup, config::Config, db::Db, export, import};
 use std::path::PathBuf;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
@@ -7,11 +7,41 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilte
 struct Args {
     #[arg(long, default_value = "checks.toml")]
     config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// run the checker and web server. this is the default.
+    Run,
+    /// export results from the db
+    Export {
+        #[command(flatten)]
+        req: export::Request,
+        /// the file to write to. defaults to stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// import results from another dialer db or an ndjson export
+    Import(import::Request),
+    /// write a consistent snapshot of the db
+    Backup {
+        /// the file to write the snapshot to
+        #[arg(long)]
+        out: PathBuf,
+        /// gzip the snapshot
+        #[arg(long)]
+        compress: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let fmt_layer = tracing_subscriber::fmt::layer().with_target(false);
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .with_writer(std::io::stderr);
     let filter_layer = tracing_subscriber::EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
         .unwrap();
@@ -22,7 +52,23 @@ async fn main() -> anyhow::Result<()> {
         .init();
     let args = Args::parse();
     let config = Config::from_path(&args.config).await?;
-    let app = App::new(&config).await?;
-    app.run().await?;
+    match args.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            let app = App::new(&config, args.config).await?;
+            app.run().await?;
+        }
+        Command::Export { req, out } => {
+            let db = Db::connect(&config.db_path).await?;
+            export::to_path(&db, req, out).await?;
+        }
+        Command::Import(req) => {
+            let db = Db::connect(&config.db_path).await?;
+            import::import(&db, req).await?;
+        }
+        Command::Backup { out, compress } => {
+            let db = Db::connect(&config.db_path).await?;
+            backup::to_path(&db, out, compress).await?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,139 @@
+//! percentile estimation. small sets of samples are kept exactly, and larger sets are summarized
+//! with a ddsketch which bounds the relative error of every percentile.
+use std::collections::BTreeMap;
+
+/// the number of samples kept exactly before switching to a sketch
+pub const EXACT_LIMIT: usize = 1024;
+
+/// the relative accuracy of percentiles computed from a sketch
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// collects samples for percentile calculation
+#[derive(Debug, Default)]
+pub struct Samples {
+    exact: Vec<f64>,
+    sketch: Option<Sketch>,
+}
+
+impl Samples {
+    pub fn add(&mut self, value: f64) {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.add(value);
+            return;
+        }
+        self.exact.push(value);
+        if self.exact.len() > EXACT_LIMIT {
+            let mut sketch = Sketch::new(RELATIVE_ACCURACY);
+            for value in self.exact.drain(..) {
+                sketch.add(value);
+            }
+            self.sketch = Some(sketch);
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        self.sketch.is_none()
+    }
+
+    /// returns the value at the quantile, which must be within 0 and 1. exact quantiles are
+    /// interpolated linearly between the closest ranks.
+    pub fn quantile(&mut self, q: f64) -> Option<f64> {
+        if let Some(sketch) = &self.sketch {
+            return sketch.quantile(q);
+        }
+        if self.exact.is_empty() {
+            return None;
+        }
+        self.exact.sort_by(f64::total_cmp);
+        let rank = q.clamp(0.0, 1.0) * (self.exact.len() - 1) as f64;
+        let lo = rank.floor() as usize;
+        let hi = rank.ceil() as usize;
+        let frac = rank - lo as f64;
+        Some(self.exact[lo] + (self.exact[hi] - self.exact[lo]) * frac)
+    }
+}
+
+/// a ddsketch over non-negative values. values are counted in logarithmically sized buckets so
+/// that any quantile is within the relative accuracy of the true value.
+#[derive(Debug, Clone)]
+pub struct Sketch {
+    gamma: f64,
+    ln_gamma: f64,
+    zeros: u64,
+    count: u64,
+    bins: BTreeMap<i32, u64>,
+}
+
+impl Sketch {
+    pub fn new(relative_accuracy: f64) -> Self {
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        Self {
+            gamma,
+            ln_gamma: gamma.ln(),
+            zeros: 0,
+            count: 0,
+            bins: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        if value <= f64::MIN_POSITIVE {
+            self.zeros += 1;
+            return;
+        }
+        let idx = (value.ln() / self.ln_gamma).ceil() as i32;
+        *self.bins.entry(idx).or_default() += 1;
+    }
+
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+        if rank < self.zeros {
+            return Some(0.0);
+        }
+        let mut seen = self.zeros;
+        for (idx, count) in &self.bins {
+            seen += count;
+            if seen > rank {
+                return Some(2.0 * self.gamma.powi(*idx) / (self.gamma + 1.0));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        let mut samples = Samples::default();
+        assert_eq!(samples.quantile(0.5), None);
+        for v in [4.0, 1.0, 3.0, 2.0] {
+            samples.add(v);
+        }
+        assert!(samples.is_exact());
+        assert_eq!(samples.quantile(0.0), Some(1.0));
+        assert_eq!(samples.quantile(0.5), Some(2.5));
+        assert_eq!(samples.quantile(1.0), Some(4.0));
+    }
+
+    #[test]
+    fn sketch() {
+        let mut samples = Samples::default();
+        for v in 0..10_000 {
+            samples.add(v as f64 / 10.0);
+        }
+        assert!(!samples.is_exact());
+        for (q, want) in [(0.5, 500.0), (0.9, 900.0), (0.99, 990.0)] {
+            let got = samples.quantile(q).unwrap();
+            let err = (got - want).abs() / want;
+            assert!(err <= RELATIVE_ACCURACY, "q={q} got={got} want={want}");
+        }
+        assert_eq!(samples.quantile(0.0), Some(0.0));
+    }
+}
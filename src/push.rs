@@ -0,0 +1,540 @@
+//! pushes results to prometheus remote write or otlp endpoints for instances which cannot be
+//! scraped. the results table is the buffer: each target keeps a cursor of the last result it
+//! pushed, so results which could not be delivered are retried from the db, even across restarts.
+//! the backlog is bounded by skipping the oldest results once a target falls too far behind.
+//!
+//! only server errors, rate limits and failures to reach the target are retried. a batch which
+//! the target rejects as invalid, e.g. samples which are too old, would be rejected forever, so
+//! it is skipped and counted in the stats.
+use crate::{
+    config::{self, PushFormat},
+    db, stats,
+};
+use anyhow::{bail, Context, Result};
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, time::Duration};
+use tracing::instrument;
+
+/// the longest delay between retries of a failed push
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// a single result to push
+#[derive(Debug, Clone, PartialEq)]
+struct Point {
+    id: u64,
+    name: String,
+    kind: String,
+    epoch_ms: i64,
+    us: Option<i64>,
+}
+
+/// pushes new results to the target on each interval until the process exits.
+#[instrument(skip_all, fields(target = name))]
+pub async fn run(
+    db: db::Db,
+    stats: stats::Registry,
+    name: String,
+    config: config::Push,
+) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .context("build http client")?;
+    let mut backoff = config.interval;
+    loop {
+        match push(&db, &stats, &client, &name, &config).await {
+            // a full batch means that there are more results waiting
+            Ok(sent) if sent == config.batch_size => continue,
+            Ok(_) => {
+                backoff = config.interval;
+                tokio::time::sleep(config.interval).await;
+            }
+            Err(err) => {
+                tracing::error!("push to '{name}' failed, retrying in {backoff:?}: {err:#}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF.max(config.interval));
+            }
+        }
+    }
+}
+
+/// sends the next batch of results to the target, returning the number of results sent. the
+/// cursor is only advanced once the target has accepted or permanently rejected the batch.
+async fn push(
+    db: &db::Db,
+    stats: &stats::Registry,
+    client: &reqwest::Client,
+    name: &str,
+    config: &config::Push,
+) -> Result<usize> {
+    let points = {
+        let (name, config) = (name.to_string(), config.clone());
+        db.with_conn(move |conn| next_batch(&conn, &name, &config))
+            .await?
+    };
+    let Some(last) = points.last() else {
+        return Ok(0);
+    };
+    let last = last.id;
+    let mut req = match config.format {
+        PushFormat::RemoteWrite => {
+            let body = snap::raw::Encoder::new()
+                .compress_vec(&encode_remote_write(&points, &config.labels))
+                .context("compress remote write request")?;
+            client
+                .post(&config.url)
+                .header("Content-Encoding", "snappy")
+                .header("Content-Type", "application/x-protobuf")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(body)
+        }
+        PushFormat::Otlp => {
+            let body = serde_json::to_vec(&encode_otlp(&points, &config.labels))?;
+            client
+                .post(&config.url)
+                .header("Content-Type", "application/json")
+                .body(body)
+        }
+    };
+    for (key, value) in &config.headers {
+        req = req.header(key, value);
+    }
+    let resp = req.send().await.context("send request")?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        if !status.is_client_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            bail!("target responded with {status}: {body}");
+        }
+        tracing::error!(
+            "push target '{name}' rejected {} results with {status}, skipping them: {body}",
+            points.len()
+        );
+        stats.push_rejected(name, points.len() as u64);
+    }
+    let name = name.to_string();
+    db.with_conn(move |conn| {
+        conn.execute(
+            "insert into push_cursors (name, result_id) values (?1, ?2)
+             on conflict (name) do update set result_id=excluded.result_id",
+            (&name, last),
+        )?;
+        Ok(())
+    })
+    .await?;
+    Ok(points.len())
+}
+
+/// reads the results after the cursor of the target. a new target starts with the results
+/// recorded from now on rather than the whole history.
+fn next_batch(conn: &Connection, name: &str, config: &config::Push) -> Result<Vec<Point>> {
+    let max_id: u64 = conn.query_row("select coalesce(max(id), 0) from results", [], |row| {
+        row.get(0)
+    })?;
+    let cursor: Option<u64> = conn
+        .query_row(
+            "select result_id from push_cursors where name=?1",
+            [name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let mut cursor = match cursor {
+        Some(cursor) => cursor,
+        None => {
+            conn.execute(
+                "insert into push_cursors (name, result_id) values (?1, ?2)",
+                (name, max_id),
+            )?;
+            max_id
+        }
+    };
+    let backlog = max_id.saturating_sub(cursor);
+    if backlog > config.max_backlog as u64 {
+        let skipped = backlog - config.max_backlog as u64;
+        tracing::warn!("push target '{name}' is too far behind, skipping {skipped} results");
+        cursor += skipped;
+    }
+    let mut stmt = conn.prepare_cached(
+        "
+        select r.id, c.name, c.kind, r.epoch_ms, r.us
+        from results r
+        join checks c on r.check_id = c.id
+        where r.id > ?1
+        order by r.id
+        limit ?2
+        ",
+    )?;
+    let points = stmt
+        .query_map((cursor, config.batch_size), |row| {
+            Ok(Point {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                kind: row.get(2)?,
+                epoch_ms: row.get(3)?,
+                us: row.get(4)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(points)
+}
+
+/// the samples of one series, keyed by their sorted labels
+type Series = BTreeMap<Vec<(String, String)>, Vec<(i64, f64)>>;
+
+/// groups the points into an up series and a latency series for each check
+fn series(points: &[Point], labels: &BTreeMap<String, String>) -> Series {
+    let mut series = Series::new();
+    for point in points {
+        let labels = |metric: &str| {
+            let mut labels = labels.clone();
+            labels.insert(String::from("__name__"), metric.to_string());
+            labels.insert(String::from("name"), point.name.clone());
+            labels.insert(String::from("kind"), point.kind.clone());
+            labels.into_iter().collect::<Vec<_>>()
+        };
+        let up = f64::from(u8::from(point.us.is_some()));
+        series
+            .entry(labels("dialer_up"))
+            .or_default()
+            .push((point.epoch_ms, up));
+        if let Some(us) = point.us {
+            series
+                .entry(labels("dialer_latency_seconds"))
+                .or_default()
+                .push((point.epoch_ms, us as f64 / 1_000_000.0));
+        }
+    }
+    for samples in series.values_mut() {
+        samples.sort_by_key(|(ts, _)| *ts);
+    }
+    series
+}
+
+/// encodes a prometheus remote write request. the protobuf is encoded by hand since it only has
+/// a handful of fields.
+fn encode_remote_write(points: &[Point], labels: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut req = vec![];
+    for (labels, samples) in series(points, labels) {
+        let mut ts = vec![];
+        for (name, value) in labels {
+            let mut label = vec![];
+            proto::bytes(&mut label, 1, name.as_bytes());
+            proto::bytes(&mut label, 2, value.as_bytes());
+            proto::bytes(&mut ts, 1, &label);
+        }
+        for (epoch_ms, value) in samples {
+            let mut sample = vec![];
+            proto::double(&mut sample, 1, value);
+            proto::int64(&mut sample, 2, epoch_ms);
+            proto::bytes(&mut ts, 2, &sample);
+        }
+        proto::bytes(&mut req, 1, &ts);
+    }
+    req
+}
+
+/// encodes an otlp metrics export request in its json encoding
+fn encode_otlp(points: &[Point], labels: &BTreeMap<String, String>) -> Value {
+    let mut metrics: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (labels, samples) in series(points, labels) {
+        let mut name = String::new();
+        let mut attributes = vec![];
+        for (key, value) in labels {
+            if key == "__name__" {
+                name = value;
+            } else {
+                attributes.push(json!({ "key": key, "value": { "stringValue": value } }));
+            }
+        }
+        let points = metrics.entry(name).or_default();
+        for (epoch_ms, value) in samples {
+            points.push(json!({
+                "attributes": attributes,
+                // 64 bit integers are encoded as strings
+                "timeUnixNano": (epoch_ms as i128 * 1_000_000).to_string(),
+                "asDouble": value,
+            }));
+        }
+    }
+    let metrics: Vec<Value> = metrics
+        .into_iter()
+        .map(|(name, points)| {
+            let unit = if name.ends_with("_seconds") { "s" } else { "1" };
+            json!({ "name": name, "unit": unit, "gauge": { "dataPoints": points } })
+        })
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "dialer" } }]
+            },
+            "scopeMetrics": [{ "scope": { "name": "dialer" }, "metrics": metrics }]
+        }]
+    })
+}
+
+/// protobuf wire format encoding
+mod proto {
+    fn varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+        varint(buf, field << 3 | wire_type);
+    }
+
+    pub fn bytes(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+        key(buf, field, 2);
+        varint(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+
+    pub fn double(buf: &mut Vec<u8>, field: u64, value: f64) {
+        key(buf, field, 1);
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn int64(buf: &mut Vec<u8>, field: u64, value: i64) {
+        key(buf, field, 0);
+        varint(buf, value as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, extract::State, http::HeaderMap, http::StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    fn point(id: u64, epoch_ms: i64, us: Option<i64>) -> Point {
+        Point {
+            id,
+            name: String::from("gateway"),
+            kind: String::from("ping"),
+            epoch_ms,
+            us,
+        }
+    }
+
+    #[test]
+    fn remote_write() {
+        let points = [point(1, 1000, Some(1500))];
+        let req = encode_remote_write(&points, &BTreeMap::new());
+        // a time series per metric with labels sorted by name, each followed by its sample
+        let mut want = vec![];
+        for (name, value) in [("dialer_latency_seconds", 0.0015_f64), ("dialer_up", 1.0)] {
+            // the length of the time series is filled in at the end
+            let mut ts = vec![0x0a, 0];
+            ts.extend([0x0a, 12 + name.len() as u8, 0x0a, 0x08]);
+            ts.extend(b"__name__");
+            ts.extend([0x12, name.len() as u8]);
+            ts.extend(name.as_bytes());
+            ts.extend([0x0a, 0x0c, 0x0a, 0x04]);
+            ts.extend(b"kind");
+            ts.extend([0x12, 0x04]);
+            ts.extend(b"ping");
+            ts.extend([0x0a, 0x0f, 0x0a, 0x04]);
+            ts.extend(b"name");
+            ts.extend([0x12, 0x07]);
+            ts.extend(b"gateway");
+            ts.extend([0x12, 0x0c, 0x09]);
+            ts.extend(value.to_le_bytes());
+            ts.extend([0x10, 0xe8, 0x07]);
+            ts[1] = ts.len() as u8 - 2;
+            want.extend(ts);
+        }
+        assert_eq!(req, want);
+    }
+
+    #[test]
+    fn otlp() {
+        let points = [point(1, 1000, Some(1500)), point(2, 2000, None)];
+        let labels = BTreeMap::from([(String::from("instance"), String::from("cabin"))]);
+        let req = encode_otlp(&points, &labels);
+        let metrics = &req["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "dialer_latency_seconds");
+        assert_eq!(metrics[0]["unit"], "s");
+        let up = &metrics[1]["gauge"]["dataPoints"];
+        assert_eq!(up[0]["timeUnixNano"], "1000000000");
+        assert_eq!(up[0]["asDouble"], 1.0);
+        assert_eq!(up[1]["asDouble"], 0.0);
+        assert_eq!(
+            up[0]["attributes"],
+            json!([
+                { "key": "instance", "value": { "stringValue": "cabin" } },
+                { "key": "kind", "value": { "stringValue": "ping" } },
+                { "key": "name", "value": { "stringValue": "gateway" } },
+            ])
+        );
+    }
+
+    /// a stand-in for a remote write receiver which fails the first request
+    #[derive(Clone, Default)]
+    struct Receiver {
+        requests: Arc<Mutex<Vec<(HeaderMap, Bytes)>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut requests = receiver.requests.lock().unwrap();
+        requests.push((headers, body));
+        if requests.len() == 1 {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+
+    #[tokio::test]
+    async fn push() {
+        let receiver = Receiver::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let rtr = axum::Router::new()
+            .route("/write", axum::routing::post(receive))
+            .with_state(receiver.clone());
+        tokio::spawn(async move { axum::serve(listener, rtr).await });
+
+        let db = db::Db::in_memory().await.unwrap();
+        let exec = |sql: &'static str| {
+            let db = db.clone();
+            async move {
+                db.with_conn(move |conn| Ok(conn.execute_batch(sql)?))
+                    .await
+                    .unwrap()
+            }
+        };
+        exec("insert into checks (name, kind) values ('gateway', 'ping');").await;
+        exec("insert into results (check_id, epoch_ms, us) values (1, 1000, 1000);").await;
+        let config = config::Push {
+            format: PushFormat::RemoteWrite,
+            url: format!("http://{addr}/write"),
+            interval: Duration::from_secs(1),
+            batch_size: 2,
+            max_backlog: 3,
+            headers: BTreeMap::from([(String::from("x-token"), String::from("secret"))]),
+            labels: BTreeMap::new(),
+        };
+        let client = reqwest::Client::new();
+        let stats = stats::Registry::default();
+
+        // results from before the target was added are not pushed
+        assert_eq!(
+            super::push(&db, &stats, &client, "prom", &config)
+                .await
+                .unwrap(),
+            0
+        );
+
+        exec(
+            "insert into results (check_id, epoch_ms, us, err) values
+                (1, 2000, 1000, null), (1, 3000, null, 'timeout'), (1, 4000, 1000, null),
+                (1, 5000, 1000, null), (1, 6000, 1000, null);",
+        )
+        .await;
+        // the first request fails and is retried. the backlog is limited to the last 3 results.
+        assert!(super::push(&db, &stats, &client, "prom", &config)
+            .await
+            .is_err());
+        assert_eq!(
+            super::push(&db, &stats, &client, "prom", &config)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            super::push(&db, &stats, &client, "prom", &config)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            super::push(&db, &stats, &client, "prom", &config)
+                .await
+                .unwrap(),
+            0
+        );
+
+        let requests = receiver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let (headers, body) = &requests[1];
+        assert_eq!(headers["content-encoding"], "snappy");
+        assert_eq!(headers["x-token"], "secret");
+        let body = snap::raw::Decoder::new().decompress_vec(body).unwrap();
+        assert_eq!(
+            body,
+            encode_remote_write(
+                &[point(4, 4000, Some(1000)), point(5, 5000, Some(1000))],
+                &BTreeMap::new()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected() {
+        // a stand-in for a receiver which rejects every sample as out of order
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let rtr = axum::Router::new().route(
+            "/write",
+            axum::routing::post(|| async { (StatusCode::BAD_REQUEST, "out of order sample") }),
+        );
+        tokio::spawn(async move { axum::serve(listener, rtr).await });
+
+        let db = db::Db::in_memory().await.unwrap();
+        db.with_conn(|conn| {
+            Ok(conn.execute_batch(
+                "
+                insert into checks (name, kind) values ('gateway', 'ping');
+                insert into push_cursors (name, result_id) values ('prom', 0);
+                insert into results (check_id, epoch_ms, us) values
+                    (1, 1000, 1000), (1, 2000, 1000), (1, 3000, 1000);
+                ",
+            )?)
+        })
+        .await
+        .unwrap();
+        let config = config::Push {
+            format: PushFormat::RemoteWrite,
+            url: format!("http://{addr}/write"),
+            interval: Duration::from_secs(1),
+            batch_size: 2,
+            max_backlog: 100,
+            headers: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+        let client = reqwest::Client::new();
+        let stats = stats::Registry::default();
+
+        // rejected batches are skipped rather than retried
+        assert_eq!(
+            super::push(&db, &stats, &client, "prom", &config)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            super::push(&db, &stats, &client, "prom", &config)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            super::push(&db, &stats, &client, "prom", &config)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(stats
+            .render()
+            .contains(r#"dialer_push_rejected_total{target="prom"} 3"#));
+    }
+}
@@ -0,0 +1,194 @@
+//! reloads the config file when it changes, or on SIGHUP. the checks and the interval are applied
+//! to the running checker, while other settings such as the listen address only take effect after
+//! a restart. a config which fails to load is rejected and the previous one keeps running.
+use crate::{
+    checker::{Changes, Checker},
+    config::Config,
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use notify::{RecursiveMode, Watcher};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tracing::{info, instrument};
+
+/// how long to wait for further changes once the file changes, since editors often save a file in
+/// several steps
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// the outcome of the reloads, which is shown by the api. cloning shares the outcome.
+#[derive(Clone, Debug, Default)]
+pub struct State {
+    report: Arc<Mutex<Report>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct Report {
+    /// when the running config was loaded, or none if it is the one from startup
+    pub reloaded_at: Option<DateTime<Utc>>,
+    /// the checks which the running config changed
+    pub changes: Changes,
+    /// why the latest reload was rejected. it is cleared once a reload succeeds.
+    pub error: Option<String>,
+    pub failed_at: Option<DateTime<Utc>>,
+}
+
+impl State {
+    pub fn report(&self) -> Report {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn reloaded(&self, changes: Changes) {
+        let mut report = self.report.lock().unwrap();
+        *report = Report {
+            reloaded_at: Some(Utc::now()),
+            changes,
+            error: None,
+            failed_at: None,
+        };
+    }
+
+    /// clears the error of a rejected reload once the file matches the running config again
+    fn unchanged(&self) {
+        let mut report = self.report.lock().unwrap();
+        report.error = None;
+        report.failed_at = None;
+    }
+
+    fn failed(&self, err: &anyhow::Error) {
+        let mut report = self.report.lock().unwrap();
+        report.error = Some(format!("{err:#}"));
+        report.failed_at = Some(Utc::now());
+    }
+}
+
+/// watches the config file at the path, which the running config was loaded from
+#[instrument(skip_all)]
+pub async fn run(path: PathBuf, config: Config, checker: Checker, state: State) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(1);
+    let name = path.file_name().map(ToOwned::to_owned);
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let Ok(event) = res else {
+            return;
+        };
+        let ours = event.paths.iter().any(|p| p.file_name() == name.as_deref());
+        if ours && !event.kind.is_access() {
+            // a reload is already pending when the channel is full
+            let _ = tx.try_send(());
+        }
+    })
+    .context("create config watcher")?;
+    // the directory is watched rather than the file, since editors often replace the file rather
+    // than writing to it
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("watch {}", dir.display()))?;
+    let mut hangup = signal(SignalKind::hangup()).context("listen for SIGHUP")?;
+    info!("Watching {} for changes", path.display());
+
+    let mut current = config;
+    loop {
+        tokio::select! {
+            Some(()) = rx.recv() => {
+                tokio::time::sleep(DEBOUNCE).await;
+                while rx.try_recv().is_ok() {}
+            }
+            Some(()) = hangup.recv() => info!("Received SIGHUP"),
+            else => bail!("config watcher stopped"),
+        }
+        match reload(&path, &current, &checker).await {
+            Ok(Some((config, changes))) => {
+                info!(
+                    "Reloaded {}: {} added, {} removed, {} restarted",
+                    path.display(),
+                    changes.added.len(),
+                    changes.removed.len(),
+                    changes.restarted.len()
+                );
+                current = config;
+                state.reloaded(changes);
+            }
+            Ok(None) => {
+                tracing::debug!("{} is unchanged", path.display());
+                state.unchanged();
+            }
+            Err(err) => {
+                tracing::error!("Rejected the config at {}: {err:#}", path.display());
+                state.failed(&err);
+            }
+        }
+    }
+}
+
+/// loads the config at the path and applies it to the checker, returning none when it has not
+/// changed
+async fn reload(
+    path: &Path,
+    current: &Config,
+    checker: &Checker,
+) -> Result<Option<(Config, Changes)>> {
+    let config = Config::from_path(path).await?;
+    if config == *current {
+        return Ok(None);
+    }
+    let others = |config: &Config| Config {
+        interval: Duration::default(),
+        http: Default::default(),
+        ping: Default::default(),
+        ..config.clone()
+    };
+    if others(&config) != others(current) {
+        tracing::warn!("Changes to settings other than the checks and interval need a restart");
+    }
+    let changes = checker.reload(&config).await?;
+    Ok(Some((config, changes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checks.toml");
+        let write = |s: &str| std::fs::write(&path, s).unwrap();
+        write("[ping]\ngateway = { host = \"127.0.0.1\" }\n");
+        let config = Config::from_path(&path).await.unwrap();
+        let db = db::Db::in_memory().await.unwrap();
+        let checker = Checker::new(db, &config).await.unwrap();
+
+        assert!(super::reload(&path, &config, &checker)
+            .await
+            .unwrap()
+            .is_none());
+
+        write("[ping]\ngateway = { host = \"127.0.0.1\" }\nnas = { host = \"127.0.0.2\" }\n");
+        let (config, changes) = super::reload(&path, &config, &checker)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(changes.added, vec!["nas"]);
+        assert_eq!(checker.list().len(), 2);
+
+        // the old config keeps running when the new one is invalid
+        write("[http]\nnas = { url = \"not a url\" }\n");
+        assert!(super::reload(&path, &config, &checker).await.is_err());
+        write("[ping\n");
+        assert!(super::reload(&path, &config, &checker).await.is_err());
+        assert_eq!(checker.list().len(), 2);
+    }
+}
@@ -0,0 +1,337 @@
+//! in-process stats about the checks and push targets which are rendered in the prometheus text
+//! format. the stats only cover the lifetime of the process, which is what prometheus expects of
+//! counters.
+use crate::checker::Kind;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// the upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// the stats of every check, keyed by check id. cloning shares the stats.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    checks: Arc<Mutex<BTreeMap<u64, CheckStats>>>,
+    /// the number of results which each push target rejected, keyed by target name
+    rejected: Arc<Mutex<BTreeMap<String, u64>>>,
+    started: SystemTime,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            checks: Arc::default(),
+            rejected: Arc::default(),
+            started: SystemTime::now(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CheckStats {
+    name: String,
+    kind: Kind,
+    /// the latency of the last successful probe in seconds
+    last_latency: Option<f64>,
+    /// whether the last probe succeeded
+    up: Option<bool>,
+    probes: u64,
+    errors: u64,
+    /// the number of successful probes with a latency within each bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+}
+
+impl Registry {
+    /// adds a check so that its stats are reported before its first probe.
+    pub fn register(&self, id: u64, name: &str, kind: Kind) {
+        let mut checks = self.checks.lock().unwrap();
+        checks.insert(
+            id,
+            CheckStats {
+                name: name.to_string(),
+                kind,
+                last_latency: None,
+                up: None,
+                probes: 0,
+                errors: 0,
+                buckets: [0; BUCKETS.len()],
+                sum: 0.0,
+            },
+        );
+    }
+
+    /// removes a check which is no longer probed
+    pub fn unregister(&self, id: u64) {
+        self.checks.lock().unwrap().remove(&id);
+    }
+
+    /// records the outcome of a probe. probes of unregistered checks are ignored.
+    pub fn observe(&self, id: u64, latency: Option<Duration>) {
+        let mut checks = self.checks.lock().unwrap();
+        let Some(stats) = checks.get_mut(&id) else {
+            return;
+        };
+        stats.probes += 1;
+        stats.up = Some(latency.is_some());
+        match latency {
+            Some(latency) => {
+                let secs = latency.as_secs_f64();
+                stats.last_latency = Some(secs);
+                stats.sum += secs;
+                if let Some(idx) = BUCKETS.iter().position(|le| secs <= *le) {
+                    stats.buckets[idx] += 1;
+                }
+            }
+            None => stats.errors += 1,
+        }
+    }
+
+    /// records results which a push target rejected and which are not pushed again
+    pub fn push_rejected(&self, target: &str, results: u64) {
+        let mut rejected = self.rejected.lock().unwrap();
+        *rejected.entry(target.to_string()).or_default() += results;
+    }
+
+    /// renders the stats in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        // copied so that rendering does not hold the lock
+        let mut checks: Vec<CheckStats> = {
+            let checks = self.checks.lock().unwrap();
+            checks.values().cloned().collect()
+        };
+        checks.sort_by(|a, b| (&a.name, a.kind.as_str()).cmp(&(&b.name, b.kind.as_str())));
+
+        let mut out = String::new();
+        let labels = |c: &CheckStats| format!("name=\"{}\",kind=\"{}\"", escape(&c.name), c.kind);
+        header(
+            &mut out,
+            "dialer_up",
+            "gauge",
+            "whether the last probe of the check succeeded",
+        );
+        for c in &checks {
+            if let Some(up) = c.up {
+                writeln!(out, "dialer_up{{{}}} {}", labels(c), u8::from(up)).unwrap();
+            }
+        }
+        header(
+            &mut out,
+            "dialer_last_latency_seconds",
+            "gauge",
+            "the latency of the last successful probe",
+        );
+        for c in &checks {
+            if let Some(latency) = c.last_latency {
+                writeln!(
+                    out,
+                    "dialer_last_latency_seconds{{{}}} {latency}",
+                    labels(c)
+                )
+                .unwrap();
+            }
+        }
+        header(
+            &mut out,
+            "dialer_probes_total",
+            "counter",
+            "the number of probes",
+        );
+        for c in &checks {
+            writeln!(out, "dialer_probes_total{{{}}} {}", labels(c), c.probes).unwrap();
+        }
+        header(
+            &mut out,
+            "dialer_errors_total",
+            "counter",
+            "the number of failed probes",
+        );
+        for c in &checks {
+            writeln!(out, "dialer_errors_total{{{}}} {}", labels(c), c.errors).unwrap();
+        }
+        header(
+            &mut out,
+            "dialer_latency_seconds",
+            "histogram",
+            "the latency of successful probes",
+        );
+        for c in &checks {
+            let labels = labels(c);
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(c.buckets) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "dialer_latency_seconds_bucket{{{labels},le=\"{le}\"}} {cumulative}"
+                )
+                .unwrap();
+            }
+            let count = c.probes - c.errors;
+            writeln!(
+                out,
+                "dialer_latency_seconds_bucket{{{labels},le=\"+Inf\"}} {count}"
+            )
+            .unwrap();
+            writeln!(out, "dialer_latency_seconds_sum{{{labels}}} {}", c.sum).unwrap();
+            writeln!(out, "dialer_latency_seconds_count{{{labels}}} {count}").unwrap();
+        }
+        header(
+            &mut out,
+            "dialer_push_rejected_total",
+            "counter",
+            "the number of results which a push target rejected",
+        );
+        for (target, results) in self.rejected.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "dialer_push_rejected_total{{target=\"{}\"}} {results}",
+                escape(target)
+            )
+            .unwrap();
+        }
+        self.render_process(&mut out);
+        out
+    }
+
+    /// renders the standard process metrics. the metrics which are read from procfs are only
+    /// available on linux.
+    fn render_process(&self, out: &mut String) {
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        header(
+            out,
+            "process_start_time_seconds",
+            "gauge",
+            "start time of the process since unix epoch in seconds",
+        );
+        writeln!(out, "process_start_time_seconds {started}").unwrap();
+        let Some(process) = Process::read() else {
+            return;
+        };
+        header(
+            out,
+            "process_cpu_seconds_total",
+            "counter",
+            "total user and system cpu time spent in seconds",
+        );
+        writeln!(out, "process_cpu_seconds_total {}", process.cpu_seconds).unwrap();
+        header(
+            out,
+            "process_resident_memory_bytes",
+            "gauge",
+            "resident memory size in bytes",
+        );
+        writeln!(out, "process_resident_memory_bytes {}", process.rss_bytes).unwrap();
+        header(
+            out,
+            "process_virtual_memory_bytes",
+            "gauge",
+            "virtual memory size in bytes",
+        );
+        writeln!(out, "process_virtual_memory_bytes {}", process.vm_bytes).unwrap();
+        header(
+            out,
+            "process_open_fds",
+            "gauge",
+            "number of open file descriptors",
+        );
+        writeln!(out, "process_open_fds {}", process.open_fds).unwrap();
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// the resource usage of this process
+struct Process {
+    cpu_seconds: f64,
+    rss_bytes: u64,
+    vm_bytes: u64,
+    open_fds: usize,
+}
+
+impl Process {
+    /// the kernel reports cpu time in clock ticks, which are 100 per second on all common
+    /// architectures
+    const TICKS_PER_SECOND: f64 = 100.0;
+
+    fn read() -> Option<Self> {
+        let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+        // the fields after the command, which is parenthesized and may contain spaces
+        let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+        let utime: f64 = fields.get(11)?.parse().ok()?;
+        let stime: f64 = fields.get(12)?.parse().ok()?;
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let kb = |key: &str| -> Option<u64> {
+            let line = status.lines().find(|line| line.starts_with(key))?;
+            let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb * 1024)
+        };
+        let open_fds = std::fs::read_dir("/proc/self/fd").ok()?.count();
+        Some(Self {
+            cpu_seconds: (utime + stime) / Self::TICKS_PER_SECOND,
+            rss_bytes: kb("VmRSS:")?,
+            vm_bytes: kb("VmSize:")?,
+            open_fds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let registry = Registry::default();
+        registry.register(1, "gateway", Kind::Ping);
+        registry.register(2, "home \"lab\"", Kind::Http);
+        registry.observe(1, Some(Duration::from_millis(3)));
+        registry.observe(1, Some(Duration::from_millis(20)));
+        registry.observe(1, None);
+        registry.observe(3, None);
+        registry.push_rejected("prom", 2);
+        registry.push_rejected("prom", 1);
+
+        let out = registry.render();
+        let lines: Vec<&str> = out.lines().collect();
+        for line in [
+            r#"dialer_up{name="gateway",kind="ping"} 0"#,
+            r#"dialer_last_latency_seconds{name="gateway",kind="ping"} 0.02"#,
+            r#"dialer_probes_total{name="gateway",kind="ping"} 3"#,
+            r#"dialer_errors_total{name="gateway",kind="ping"} 1"#,
+            r#"dialer_latency_seconds_bucket{name="gateway",kind="ping",le="0.001"} 0"#,
+            r#"dialer_latency_seconds_bucket{name="gateway",kind="ping",le="0.005"} 1"#,
+            r#"dialer_latency_seconds_bucket{name="gateway",kind="ping",le="0.025"} 2"#,
+            r#"dialer_latency_seconds_bucket{name="gateway",kind="ping",le="+Inf"} 2"#,
+            r#"dialer_latency_seconds_count{name="gateway",kind="ping"} 2"#,
+            r#"dialer_probes_total{name="home \"lab\"",kind="http"} 0"#,
+            "# TYPE dialer_latency_seconds histogram",
+            r#"dialer_push_rejected_total{target="prom"} 3"#,
+        ] {
+            assert!(lines.contains(&line), "missing {line} in\n{out}");
+        }
+        // checks which have not been probed yet have no state
+        assert!(!out.contains(r#"dialer_up{name="home \"lab\"""#));
+        assert!(out.contains("process_start_time_seconds "));
+    }
+}
@@ -0,0 +1,345 @@
+//! the public status page. checks are grouped into components, and only the state of the
+//! components is shown, so that the page can be shared without exposing the checks themselves.
+//!
+//! a component is down during an uptime bucket when any of its checks is down, using the same
+//! buckets as the uptime report.
+use crate::{checker, config, incident};
+use anyhow::Result;
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+/// how far back incidents are shown
+const INCIDENT_WINDOW: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// the most incidents shown
+const INCIDENT_LIMIT: usize = 10;
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// none of the checks have been probed yet
+    Unknown,
+    Operational,
+    /// some of the checks are down
+    Degraded,
+    /// every check is down
+    Outage,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Unknown => "unknown",
+            State::Operational => "operational",
+            State::Degraded => "degraded",
+            State::Outage => "outage",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub title: String,
+    /// the worst state of any component
+    pub state: State,
+    pub components: Vec<Component>,
+    /// ongoing and recent incidents which affected a component, newest first
+    pub incidents: Vec<Incident>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Component {
+    pub name: String,
+    pub description: Option<String>,
+    pub state: State,
+    /// the percentage of the observed time that the component was up over all of the days
+    pub uptime: Option<f64>,
+    /// the uptime of each day, oldest first
+    pub days: Vec<Day>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Day {
+    pub date: NaiveDate,
+    /// none when nothing was observed on the day
+    pub uptime: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Incident {
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    /// the names of the affected components
+    pub components: Vec<String>,
+}
+
+/// builds the status page as of now
+pub fn status(conn: &Connection, config: &config::Config, now: DateTime<Utc>) -> Result<Status> {
+    let today = now.date_naive();
+    let first = today
+        .checked_sub_days(Days::new(u64::from(config.status.days.max(1) - 1)))
+        .unwrap_or(today);
+    let mut components = vec![];
+    // the checks of each component, to attribute incidents to components
+    let mut members: Vec<BTreeSet<(String, &str)>> = vec![];
+    for component in &config.status.components {
+        let (filter, params) = filter(&component.checks);
+        let mut stmt = conn.prepare_cached(&format!(
+            "
+            SELECT c.name, c.kind, CASE WHEN r.id IS NULL THEN NULL ELSE r.err IS NULL END AS up
+            FROM checks c
+            LEFT JOIN results r ON r.id = (
+                SELECT id FROM results WHERE check_id = c.id ORDER BY epoch_ms DESC LIMIT 1
+            )
+            WHERE c.archived_at IS NULL AND ({filter})
+            "
+        ))?;
+        let mut rows = stmt.query(params_from_iter(&params))?;
+        let mut checks = BTreeSet::new();
+        let (mut up, mut down) = (0, 0);
+        while let Some(row) = rows.next()? {
+            let name: String = row.get("name")?;
+            let kind: String = row.get("kind")?;
+            let kind = checker::Kind::try_from(kind.as_str())?;
+            match row.get::<_, Option<bool>>("up")? {
+                Some(true) => up += 1,
+                Some(false) => down += 1,
+                None => {}
+            }
+            checks.insert((name, kind.as_str()));
+        }
+        let state = match (up, down) {
+            (0, 0) => State::Unknown,
+            (_, 0) => State::Operational,
+            (0, _) => State::Outage,
+            _ => State::Degraded,
+        };
+        let (uptime, days) = daily(conn, &config.uptime, &component.checks, first, today)?;
+        components.push(Component {
+            name: component.name.clone(),
+            description: component.description.clone(),
+            state,
+            uptime,
+            days,
+        });
+        members.push(checks);
+    }
+
+    let mut incidents = vec![];
+    let checks: BTreeSet<&str> = members
+        .iter()
+        .flatten()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if !checks.is_empty() {
+        let req = incident::ListRequest {
+            last: Some(INCIDENT_WINDOW),
+            checks: checks.iter().map(ToString::to_string).collect(),
+            limit: INCIDENT_LIMIT,
+            ..Default::default()
+        };
+        for found in incident::list(conn, &req, now)? {
+            let affected: Vec<String> = components
+                .iter()
+                .zip(&members)
+                .filter(|(_, checks)| {
+                    found
+                        .checks
+                        .iter()
+                        .any(|c| checks.contains(&(c.name.clone(), c.kind.as_str())))
+                })
+                .map(|(component, _)| component.name.clone())
+                .collect();
+            if !affected.is_empty() {
+                incidents.push(Incident {
+                    start: found.start,
+                    end: found.end,
+                    components: affected,
+                });
+            }
+        }
+    }
+
+    let state = components
+        .iter()
+        .map(|c| c.state)
+        .filter(|state| *state != State::Unknown)
+        .max()
+        .unwrap_or(State::Unknown);
+    Ok(Status {
+        title: config.status.title.clone(),
+        state,
+        components,
+        incidents,
+    })
+}
+
+/// the uptime of the checks over each day from first to last, and over all of the days
+fn daily(
+    conn: &Connection,
+    config: &config::Uptime,
+    checks: &[String],
+    first: NaiveDate,
+    last: NaiveDate,
+) -> Result<(Option<f64>, Vec<Day>)> {
+    let bucket_ms = config.bucket.as_millis().max(1) as i64;
+    let start = first.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+    let (filter, mut params) = filter(checks);
+    params.extend([
+        Value::Integer(bucket_ms),
+        Value::Integer(i64::from(config.down_percent)),
+        Value::Integer(start.timestamp_millis()),
+    ]);
+    let n = params.len();
+    let mut stmt = conn.prepare_cached(&format!(
+        "
+        SELECT bucket, MAX(down) AS down
+        FROM (
+            SELECT
+                r.epoch_ms / ?{bucket} AS bucket,
+                COUNT(r.err) * 100 >= COUNT(*) * ?{down_percent} AS down
+            FROM results r
+            JOIN checks c ON r.check_id = c.id
+            WHERE r.epoch_ms >= ?{start}
+            AND c.archived_at IS NULL
+            AND ({filter})
+            GROUP BY r.check_id, bucket
+        )
+        GROUP BY bucket
+        ",
+        bucket = n - 2,
+        down_percent = n - 1,
+        start = n,
+    ))?;
+    let mut rows = stmt.query(params_from_iter(&params))?;
+    // the number of observed and down buckets of each day
+    let mut buckets: BTreeMap<NaiveDate, (u64, u64)> = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let bucket: i64 = row.get("bucket")?;
+        let down: bool = row.get("down")?;
+        let Some(ts) = DateTime::from_timestamp_millis(bucket * bucket_ms) else {
+            continue;
+        };
+        let day = buckets.entry(ts.date_naive()).or_default();
+        day.0 += 1;
+        day.1 += u64::from(down);
+    }
+    let uptime = |(observed, down): (u64, u64)| {
+        (observed > 0).then(|| (observed - down) as f64 * 100.0 / observed as f64)
+    };
+    let days = first
+        .iter_days()
+        .take_while(|date| *date <= last)
+        .map(|date| Day {
+            date,
+            uptime: buckets.get(&date).copied().and_then(uptime),
+        })
+        .collect();
+    let total = buckets
+        .values()
+        .fold((0, 0), |acc, day| (acc.0 + day.0, acc.1 + day.1));
+    Ok((uptime(total), days))
+}
+
+/// the sql condition which selects the checks of a component, which joins checks as `c`, and its
+/// positional parameters
+fn filter(checks: &[String]) -> (String, Vec<Value>) {
+    if checks.is_empty() {
+        return (String::from("0"), vec![]);
+    }
+    let sql: Vec<String> = (1..=checks.len())
+        .map(|idx| format!("c.name GLOB ?{idx}"))
+        .collect();
+    let params = checks.iter().map(|c| Value::Text(c.clone())).collect();
+    (sql.join(" OR "), params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn status() {
+        let db = db::Db::in_memory().await.unwrap();
+        let mut conn = db.conn().unwrap();
+        // two days of a result per minute. isp-b fails for the last 3 hours of the first day,
+        // while isp-a is fine throughout.
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values
+                ('isp-a', 'ping'), ('isp-b', 'ping'), ('nas', 'http');
+            with recursive mins(m) as (select 0 union all select m + 1 from mins where m < 2879)
+            insert into results (check_id, epoch_ms, us, err)
+            select 1, m * 60000, 1000, null from mins
+            union all
+            select 2, m * 60000,
+                case when m between 1260 and 1439 then null else 1000 end,
+                case when m between 1260 and 1439 then 'timeout' else null end
+            from mins;
+            insert into results (check_id, epoch_ms, us, err) values (2, 2880 * 60000, null, 'x');
+            ",
+        )
+        .unwrap();
+        let config = crate::config::Incidents {
+            min_failures: 1,
+            ..Default::default()
+        };
+        let now = DateTime::UNIX_EPOCH + Duration::from_secs(2 * 24 * 60 * 60 + 60);
+        incident::detect(&mut conn, &config, now).unwrap();
+
+        let component = |name: &str, checks: &[&str]| config::Component {
+            name: name.to_string(),
+            description: None,
+            checks: checks.iter().map(ToString::to_string).collect(),
+        };
+        let config = config::Config {
+            status: config::Status {
+                days: 3,
+                components: vec![
+                    component("Internet", &["isp-*"]),
+                    component("Storage", &["nas"]),
+                ],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let status = super::status(&conn, &config, now).unwrap();
+        assert_eq!(status.state, State::Degraded);
+
+        let internet = &status.components[0];
+        assert_eq!(internet.state, State::Degraded);
+        let date = |s: &str| s.parse().unwrap();
+        assert_eq!(
+            internet.days,
+            vec![
+                Day {
+                    date: date("1970-01-01"),
+                    uptime: Some(1260.0 * 100.0 / 1440.0),
+                },
+                Day {
+                    date: date("1970-01-02"),
+                    uptime: Some(100.0),
+                },
+                Day {
+                    date: date("1970-01-03"),
+                    uptime: Some(0.0),
+                },
+            ]
+        );
+        assert_eq!(internet.uptime, Some(2700.0 * 100.0 / 2881.0));
+
+        let storage = &status.components[1];
+        assert_eq!((storage.state, storage.uptime), (State::Unknown, None));
+        assert!(storage.days.iter().all(|d| d.uptime.is_none()));
+
+        assert_eq!(status.incidents.len(), 2);
+        assert_eq!(status.incidents[0].components, vec!["Internet"]);
+        assert!(status.incidents[0].end.is_none());
+    }
+}
@@ -0,0 +1,261 @@
+//! availability reports. results are grouped into fixed size buckets and a bucket counts as
+//! downtime when enough of its probes failed. buckets without any results are not counted either
+//! way since dialer was not running to observe them.
+use crate::{checker, config};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Months, NaiveDate, TimeDelta, Utc};
+use rusqlite::{named_params, Connection};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// describes the range to report on
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Request {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    /// reports on the most recent results, e.g. "30d". overrides start and end.
+    #[serde(with = "humantime_serde")]
+    pub last: Option<Duration>,
+    /// reports on a calendar month in utc, e.g. "2024-05". overrides the other ranges.
+    pub month: Option<String>,
+    /// include checks which have been removed from the config
+    pub archived: bool,
+}
+
+impl Request {
+    /// the start and end of the range to report on. defaults to the last 24 hours.
+    pub fn range(&self, now: DateTime<Utc>) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+        let (start, end) = if let Some(month) = &self.month {
+            let start = NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d")
+                .with_context(|| format!("invalid month '{month}', expected YYYY-MM"))?;
+            let end = start
+                .checked_add_months(Months::new(1))
+                .context("month out of range")?;
+            let start = start.and_hms_opt(0, 0, 0).context("month out of range")?;
+            let end = end.and_hms_opt(0, 0, 0).context("month out of range")?;
+            (start.and_utc(), end.and_utc().min(now))
+        } else if let Some(last) = self.last {
+            (ago(now, last)?, now)
+        } else {
+            let start = self
+                .start
+                .unwrap_or(now - Duration::from_secs(60 * 60 * 24));
+            (start, self.end.unwrap_or(now))
+        };
+        if end <= start {
+            bail!("end date must be after start date");
+        }
+        Ok((start, end))
+    }
+}
+
+/// the time which is last before now. fails rather than overflowing when last comes from a request.
+pub fn ago(now: DateTime<Utc>, last: Duration) -> Result<DateTime<Utc>> {
+    TimeDelta::from_std(last)
+        .ok()
+        .and_then(|last| now.checked_sub_signed(last))
+        .with_context(|| format!("last of {} is too long", humantime::format_duration(last)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Uptime {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// the size of each bucket in seconds
+    pub bucket: u64,
+    pub reports: Vec<Report>,
+}
+
+/// the availability of a single check over the range
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Report {
+    pub name: String,
+    pub kind: checker::Kind,
+    /// the percentage of the observed time that the check was up
+    pub uptime: f64,
+    /// the number of seconds that dialer was running and observed the check
+    pub observed_secs: u64,
+    pub downtime_secs: u64,
+    /// the number of distinct outages. an outage is a run of consecutive buckets of downtime.
+    pub incidents: u64,
+    /// the mean time to recovery in seconds
+    pub mttr_secs: Option<u64>,
+    pub longest_outage_secs: u64,
+}
+
+impl Report {
+    fn new(name: String, kind: checker::Kind) -> Self {
+        Self {
+            name,
+            kind,
+            uptime: 100.0,
+            observed_secs: 0,
+            downtime_secs: 0,
+            incidents: 0,
+            mttr_secs: None,
+            longest_outage_secs: 0,
+        }
+    }
+}
+
+/// computes the availability of each check between start and end.
+pub fn report(
+    conn: &Connection,
+    config: &config::Uptime,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    archived: bool,
+) -> Result<Uptime> {
+    let bucket_ms = config.bucket.as_millis().max(1) as i64;
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT
+            c.name,
+            c.kind,
+            r.epoch_ms / :bucket AS bucket,
+            COUNT(*) AS count,
+            COUNT(r.err) AS errs
+        FROM results r
+        JOIN checks c on r.check_id = c.id
+        WHERE r.epoch_ms >= :start_time
+        AND r.epoch_ms < :end_time
+        AND (:archived OR c.archived_at IS NULL)
+        GROUP BY r.check_id, bucket
+        ORDER BY c.name, c.kind, bucket
+        ",
+    )?;
+    let params = named_params! {
+        ":bucket": bucket_ms,
+        ":start_time": start.timestamp_millis(),
+        ":end_time": end.timestamp_millis(),
+        ":archived": archived,
+    };
+    let mut rows = stmt.query(params)?;
+
+    let mut reports: Vec<Report> = vec![];
+    // the last bucket seen for the current check and the length of the outage in progress
+    let mut last: Option<i64> = None;
+    let mut outage: u64 = 0;
+    let bucket_secs = config.bucket.as_secs();
+    while let Some(row) = rows.next()? {
+        let name: String = row.get("name")?;
+        let kind: String = row.get("kind")?;
+        let kind = checker::Kind::try_from(kind.as_str())?;
+        let bucket: i64 = row.get("bucket")?;
+        let count: u64 = row.get("count")?;
+        let errs: u64 = row.get("errs")?;
+        if reports
+            .last()
+            .is_none_or(|r| r.name != name || r.kind != kind)
+        {
+            reports.push(Report::new(name, kind));
+            last = None;
+            outage = 0;
+        }
+        let report = reports.last_mut().expect("report was just pushed");
+        // a gap means dialer was not running, which ends the outage in progress
+        if last.is_some_and(|last| bucket != last + 1) {
+            outage = 0;
+        }
+        last = Some(bucket);
+        report.observed_secs += bucket_secs;
+        if errs * 100 >= count * u64::from(config.down_percent) {
+            if outage == 0 {
+                report.incidents += 1;
+            }
+            outage += bucket_secs;
+            report.downtime_secs += bucket_secs;
+            report.longest_outage_secs = report.longest_outage_secs.max(outage);
+        } else {
+            outage = 0;
+        }
+    }
+    for report in &mut reports {
+        if report.observed_secs > 0 {
+            let up = report.observed_secs - report.downtime_secs;
+            report.uptime = up as f64 * 100.0 / report.observed_secs as f64;
+        }
+        report.mttr_secs = report.downtime_secs.checked_div(report.incidents);
+    }
+    Ok(Uptime {
+        start,
+        end,
+        bucket: bucket_secs,
+        reports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn month() {
+        let now = "2024-03-10T12:00:00Z".parse().unwrap();
+        let req = Request {
+            month: Some(String::from("2024-02")),
+            ..Default::default()
+        };
+        let (start, end) = req.range(now).unwrap();
+        assert_eq!(start.to_rfc3339(), "2024-02-01T00:00:00+00:00");
+        assert_eq!(end.to_rfc3339(), "2024-03-01T00:00:00+00:00");
+
+        // the current month ends now
+        let req = Request {
+            month: Some(String::from("2024-03")),
+            ..Default::default()
+        };
+        assert_eq!(req.range(now).unwrap().1, now);
+
+        let req = Request {
+            month: Some(String::from("march")),
+            ..Default::default()
+        };
+        assert!(req.range(now).is_err());
+
+        let req = Request {
+            last: Some(Duration::from_secs(100_000_000 * 365 * 24 * 60 * 60)),
+            ..Default::default()
+        };
+        assert!(req.range(now).is_err());
+    }
+
+    #[tokio::test]
+    async fn report() {
+        let db = db::Db::in_memory().await.unwrap();
+        let conn = db.conn().unwrap();
+        // one result per second. the 3rd and 4th minute are down, then dialer stops for a
+        // minute, and the 6th and 7th minute are down again.
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('gateway', 'ping');
+            with recursive secs(s) as (select 0 union all select s + 1 from secs where s < 479)
+            insert into results (check_id, epoch_ms, us, err)
+            select 1, s * 1000,
+                case when s / 60 in (2, 3, 5, 6) then null else 1000 end,
+                case when s / 60 in (2, 3, 5, 6) then 'timeout' else null end
+            from secs where s / 60 != 4;
+            ",
+        )
+        .unwrap();
+        let config = config::Uptime::default();
+        let start = DateTime::UNIX_EPOCH;
+        let end = start + Duration::from_secs(480);
+        let uptime = super::report(&conn, &config, start, end, false).unwrap();
+        assert_eq!(
+            uptime.reports,
+            vec![Report {
+                name: String::from("gateway"),
+                kind: checker::Kind::Ping,
+                uptime: 180.0 * 100.0 / 420.0,
+                observed_secs: 420,
+                downtime_secs: 240,
+                incidents: 2,
+                mttr_secs: Some(120),
+                longest_outage_secs: 120,
+            }]
+        );
+    }
+}
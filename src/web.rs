@@ -1,62 +1,194 @@
-use crate::{checker, config::Config, db};
+use crate::{
+    backup, checker,
+    config::{self, Config},
+    db, export, incident, percentile, reload, stats, uptime,
+};
 use anyhow::{bail, Context, Result};
 use axum::{
-    extract::{Query, State},
-    response::{Html, IntoResponse, Response},
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    middleware,
+    response::{
+        sse::{self, Sse},
+        Html, IntoResponse, Response,
+    },
     routing, Json,
 };
 use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
-use rusqlite::named_params;
+use rusqlite::{named_params, types::Value, Connection, ToSql};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
-use tokio::net::TcpListener;
+use std::{
+    collections::BTreeMap,
+    future::IntoFuture,
+    io::{self, BufWriter, Write},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    StreamExt,
+};
+use tokio_util::io::ReaderStream;
 use tower::ServiceBuilder;
 use tower_http::{compression::CompressionLayer, services::ServeDir};
 use tower_livereload::LiveReloadLayer;
 use tracing::{info, instrument};
 
+mod assets;
+mod auth;
+mod badge;
+mod chart;
+mod check_page;
+mod checks;
+mod dashboard;
+mod live;
+mod status_page;
+
 /// handles web serving and api requests
 #[derive(Clone)]
 pub struct Server {
     config: Config,
     db: db::Db,
+    checker: checker::Checker,
+    stats: stats::Registry,
+    events: broadcast::Sender<checker::Event>,
+    reload: reload::State,
 }
 
 impl Server {
-    pub fn new(config: &Config, db: db::Db) -> Result<Self> {
+    pub fn new(
+        config: &Config,
+        db: db::Db,
+        checker: checker::Checker,
+        reload: reload::State,
+    ) -> Result<Self> {
         Ok(Self {
             config: config.clone(),
             db,
+            stats: checker.stats(),
+            events: checker.events(),
+            checker,
+            reload,
         })
     }
 
     #[instrument(skip_all)]
     pub async fn run(&self) -> Result<()> {
+        if !auth::enabled(&self.config.auth) {
+            tracing::warn!("Authentication is disabled, anyone who can reach dialer can manage it");
+        }
+        let missing = assets::missing_vendor();
+        if !missing.is_empty() {
+            tracing::warn!(
+                "Loading {} from their cdn, run scripts/vendor.sh before building to embed them",
+                missing.join(", ")
+            );
+        }
+        let rtr = self.router();
+        let listener = TcpListener::bind(&self.config.listen)
+            .await
+            .context(format!("bind to {}", self.config.listen))?;
+        info!("Bound http listener to {}", self.config.listen);
+        let serve = axum::serve(listener, rtr).into_future();
+        match &self.config.status.listen {
+            Some(listen) => {
+                let listener = TcpListener::bind(listen)
+                    .await
+                    .context(format!("bind to {listen}"))?;
+                info!("Bound status page listener to {listen}");
+                let rtr = status_page::router()
+                    .with_state(self.clone())
+                    .layer(CompressionLayer::new());
+                let status = axum::serve(listener, rtr).into_future();
+                tokio::try_join!(serve, status).context("axum failed")?;
+            }
+            None => serve.await.context("axum failed")?,
+        }
+        bail!("axum quit unexpectedly");
+    }
+
+    /// the routes of the ui and api, with auth in front of everything which is not public
+    fn router(&self) -> axum::Router {
+        // the backup handler does its own auth, since it also accepts the token in its config
+        let mut public = axum::Router::new()
+            .route("/backup", routing::get(handle_backup))
+            .route("/status", routing::get(status_page::handle_status))
+            .route(
+                "/status.json",
+                routing::get(status_page::handle_status_json),
+            );
         let mut rtr = axum::Router::new()
             .route("/query", routing::get(handle_metrics))
+            .route("/chart.svg", routing::get(chart::handle_chart))
+            .route("/metrics", routing::get(handle_prometheus))
+            .route("/events", routing::get(handle_events))
+            .route("/ws", routing::get(live::handle_ws))
+            .route("/export", routing::get(handle_export))
+            .route(
+                "/checks",
+                routing::get(checks::handle_list).post(checks::handle_create),
+            )
+            .route(
+                "/checks/:name",
+                routing::get(check_page::handle_check)
+                    .put(checks::handle_update)
+                    .delete(checks::handle_delete),
+            )
+            .route("/checks/:name/pause", routing::post(checks::handle_pause))
+            .route("/checks/:name/resume", routing::post(checks::handle_resume))
+            .route("/reload", routing::get(handle_reload))
+            .route("/uptime", routing::get(handle_uptime))
+            .route("/incidents", routing::get(handle_incidents))
+            .route("/incidents/:id", routing::get(handle_incident))
             .route("/old", routing::get(handle_old_index))
-            .route("/", routing::get(handle_index))
-            .fallback_service(ServeDir::new("html"))
-            .with_state(self.clone());
+            .route("/fragments/checks", routing::get(dashboard::handle_checks))
+            .route(
+                "/fragments/incidents",
+                routing::get(dashboard::handle_incidents),
+            )
+            .route("/", routing::get(dashboard::handle_index));
+        let badges = routing::get(badge::handle_badge);
+        if self.config.auth.public_badges {
+            public = public.route("/badges/:metric/:name", badges);
+        } else {
+            rtr = rtr.route("/badges/:metric/:name", badges);
+        }
+        let auth = Arc::new(self.config.auth.clone());
+        let rtr = rtr
+            .route_layer(middleware::from_fn_with_state(auth, auth::authorize))
+            .merge(public);
+        let mut rtr = match &self.config.assets_dir {
+            Some(dir) => {
+                info!("Serving assets from {}", dir.display());
+                let embedded = routing::get(assets::handle_asset);
+                rtr.fallback_service(ServeDir::new(dir).fallback(embedded))
+            }
+            None => rtr.fallback(assets::handle_asset),
+        }
+        .with_state(self.clone());
         if self.config.live_reload {
             info!("Live reload enabled");
             rtr = rtr.layer(LiveReloadLayer::new());
         }
-        rtr = rtr.layer(ServiceBuilder::new().layer(CompressionLayer::new()));
-        let listener = TcpListener::bind(&self.config.listen)
-            .await
-            .context(format!("bind to {}", self.config.listen))?;
-        info!("Bound http listener to {}", self.config.listen);
-        axum::serve(listener, rtr).await.context("axum failed")?;
-        bail!("axum quit unexpectedly");
+        rtr.layer(ServiceBuilder::new().layer(CompressionLayer::new()))
     }
 }
 
+#[derive(Debug)]
 enum ServerError {
     Anyhow(anyhow::Error),
     InvalidEndDate,
     Askama(askama::Error),
+    Unauthorized,
+    NotFound,
+    BadRequest(String),
+    Conflict(String),
 }
 
 impl IntoResponse for ServerError {
@@ -77,10 +209,33 @@ impl IntoResponse for ServerError {
                 tracing::error!("askama: {err}");
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
+            Self::Unauthorized => {
+                tracing::warn!("Unauthorized request");
+                let headers = [(header::WWW_AUTHENTICATE, "Bearer")];
+                (StatusCode::UNAUTHORIZED, headers).into_response()
+            }
+            Self::NotFound => StatusCode::NOT_FOUND.into_response(),
+            Self::BadRequest(msg) => {
+                tracing::warn!("Bad request: {msg}");
+                (StatusCode::BAD_REQUEST, msg).into_response()
+            }
+            Self::Conflict(msg) => {
+                tracing::warn!("Conflict: {msg}");
+                (StatusCode::CONFLICT, msg).into_response()
+            }
         }
     }
 }
 
+/// converts an error of the http handlers for use outside of a response
+fn describe(err: ServerError) -> anyhow::Error {
+    match err {
+        ServerError::Anyhow(err) => err,
+        ServerError::BadRequest(msg) => anyhow::anyhow!(msg),
+        err => anyhow::anyhow!("{err:?}"),
+    }
+}
+
 impl<E> From<E> for ServerError
 where
     E: Into<anyhow::Error>,
@@ -91,28 +246,132 @@ where
 }
 
 trait DateTimeExt {
-    fn epoch_secs(&self) -> Result<u64>;
+    fn epoch_millis(&self) -> Result<u64>;
 }
 
 impl DateTimeExt for DateTime<Utc> {
-    fn epoch_secs(&self) -> Result<u64> {
-        Ok((*self - DateTime::UNIX_EPOCH).to_std()?.as_secs())
+    fn epoch_millis(&self) -> Result<u64> {
+        Ok((*self - DateTime::UNIX_EPOCH).to_std()?.as_millis() as u64)
     }
 }
 
-trait DurationExt {
-    // the resolution of a rollup is based on the duration of the window
-    fn resolution(&self) -> Duration;
+/// the most points per series that a query may return
+const MAX_POINTS: u64 = 11_000;
+
+/// the number of points per series that the resolution is chosen for when neither a step nor a
+/// target is requested
+const DEFAULT_MAX_POINTS: u64 = 1000;
+
+/// the steps which may be chosen automatically, in seconds. windows which are too long for the
+/// largest step use a multiple of a day.
+const STEPS: &[u64] = &[
+    1,
+    2,
+    5,
+    10,
+    15,
+    30,
+    60,
+    2 * 60,
+    5 * 60,
+    10 * 60,
+    15 * 60,
+    30 * 60,
+    60 * 60,
+    2 * 60 * 60,
+    3 * 60 * 60,
+    6 * 60 * 60,
+    12 * 60 * 60,
+    24 * 60 * 60,
+];
+
+/// chooses the resolution of a rollup over the window. an explicit step is used as is, otherwise
+/// the smallest step which keeps the window within the target number of points is chosen.
+fn resolution(
+    window: Duration,
+    step: Option<Duration>,
+    max_points: Option<u64>,
+) -> Result<Duration, ServerError> {
+    let window = window.as_secs().max(1);
+    if let Some(step) = step {
+        if step < Duration::from_secs(1) || step.subsec_nanos() != 0 {
+            return Err(ServerError::BadRequest(String::from(
+                "step must be a whole number of seconds",
+            )));
+        }
+        if window.div_ceil(step.as_secs()) > MAX_POINTS {
+            return Err(ServerError::BadRequest(format!(
+                "step is too small for the window, it would return more than {MAX_POINTS} points"
+            )));
+        }
+        return Ok(step);
+    }
+    let max_points = max_points.unwrap_or(DEFAULT_MAX_POINTS);
+    if max_points == 0 || max_points > MAX_POINTS {
+        return Err(ServerError::BadRequest(format!(
+            "max_points must be between 1 and {MAX_POINTS}"
+        )));
+    }
+    let step = STEPS
+        .iter()
+        .copied()
+        .find(|step| window.div_ceil(*step) <= max_points)
+        .unwrap_or_else(|| {
+            let day = 24 * 60 * 60;
+            window.div_ceil(max_points).div_ceil(day) * day
+        });
+    Ok(Duration::from_secs(step))
+}
+
+/// how buckets without any results are filled in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Fill {
+    /// an empty bucket without latencies
+    Null,
+    /// an empty bucket with latencies of zero
+    Zero,
+    /// an empty bucket with the latencies of the previous bucket
+    Previous,
 }
 
-impl DurationExt for Duration {
-    fn resolution(&self) -> Duration {
-        // for a 10m window use a 1s resolution
-        if *self <= Duration::from_secs(60 * 10) {
-            return Duration::from_secs(1);
+impl Fill {
+    /// fills in a value for each missing bucket between start and end, which are in milliseconds.
+    /// the values must be sorted by time.
+    fn apply(self, values: Vec<TimeValue>, start: u64, end: u64, step: u64) -> Vec<TimeValue> {
+        let first = start / step * step;
+        let mut filled = Vec::with_capacity(((end - first) / step + 1) as usize);
+        let mut values = values.into_iter().peekable();
+        let mut bucket = first;
+        while bucket <= end {
+            let ts = DateTime::from_timestamp_millis(bucket as i64).unwrap_or_default();
+            match values.next_if(|v| v.ts <= ts) {
+                Some(value) => filled.push(value),
+                None => {
+                    let value = match (self, filled.last()) {
+                        (Fill::Previous, Some(prev)) => TimeValue {
+                            ts,
+                            count: 0,
+                            errs: 0,
+                            avg: prev.avg,
+                            min: prev.min,
+                            max: prev.max,
+                            percentiles: prev.percentiles.clone(),
+                        },
+                        (Fill::Zero, _) => TimeValue {
+                            avg: Some(0.0),
+                            min: Some(0.0),
+                            max: Some(0.0),
+                            ..TimeValue::empty(ts)
+                        },
+                        _ => TimeValue::empty(ts),
+                    };
+                    filled.push(value);
+                }
+            }
+            bucket += step;
         }
-        // fallback of 5s resolution
-        Duration::from_secs(5)
+        filled
     }
 }
 
@@ -132,22 +391,102 @@ where
 }
 
 mod tmpl {
+    use super::{
+        check_page::Detail,
+        dashboard::{Card, RANGES},
+    };
+    use crate::{
+        checker::{Definition, Source},
+        incident::Incident,
+        status::State,
+    };
     use askama::Template;
 
     #[derive(Template)]
     #[template(path = "../templates/index.html")]
-    pub struct Index {}
+    pub struct Index {
+        pub checks: Checks,
+        pub incidents: Incidents,
+    }
+
+    #[derive(Template)]
+    #[template(path = "../templates/fragments/checks.html")]
+    pub struct Checks {
+        pub cards: Vec<Card>,
+        /// the selected range
+        pub last: String,
+    }
+
+    impl Index {
+        fn ranges(&self) -> &'static [(&'static str, &'static str)] {
+            RANGES
+        }
+
+        fn selected(&self, range: &str) -> bool {
+            self.checks.last == range
+        }
+    }
+
+    #[derive(Template)]
+    #[template(path = "../templates/fragments/incidents.html")]
+    pub struct Incidents {
+        pub incidents: Vec<Incident>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "../templates/check.html")]
+    pub struct Check {
+        pub detail: Detail,
+    }
+
+    impl Check {
+        /// a latency in milliseconds
+        fn ms(&self, value: &Option<f64>) -> String {
+            match value {
+                Some(value) => format!("{value:.1} ms"),
+                None => String::from("-"),
+            }
+        }
+    }
 
     #[derive(Template)]
     #[template(path = "../templates/old-index.html")]
     pub struct OldIndex;
-}
 
-#[instrument(skip_all)]
-async fn handle_index() -> Result<impl IntoResponse, ServerError> {
-    info!("Rendering index");
-    let template = tmpl::Index {};
-    Ok(HtmlTemplate(template))
+    #[derive(Template)]
+    #[template(path = "../templates/status.html")]
+    pub struct Status {
+        pub status: crate::status::Status,
+    }
+
+    impl Status {
+        /// the bootstrap color of a state
+        fn color(&self, state: &State) -> &'static str {
+            match state {
+                State::Operational => "success",
+                State::Degraded => "warning",
+                State::Outage => "danger",
+                State::Unknown => "secondary",
+            }
+        }
+
+        /// the bootstrap color of a day's uptime bar
+        fn day_color(&self, uptime: &Option<f64>) -> &'static str {
+            match uptime {
+                Some(uptime) if *uptime >= 99.9 => "success",
+                Some(uptime) if *uptime >= 99.0 => "warning",
+                Some(_) => "danger",
+                None => "secondary-bg",
+            }
+        }
+    }
+
+    mod filters {
+        /// the url of a third party js or css file
+        pub fn vendor<T: std::fmt::Display>(name: T) -> askama::Result<String> {
+            Ok(crate::web::assets::vendor_url(&name.to_string()))
+        }
+    }
 }
 
 #[instrument(skip_all)]
@@ -158,12 +497,19 @@ async fn handle_old_index() -> Result<impl IntoResponse, ServerError> {
 
 #[instrument(skip_all)]
 async fn handle_metrics(
-    State(Server { config: _, db }): State<Server>,
-    Query(mut query): Query<MetricsQuery>,
+    State(Server { db, .. }): State<Server>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<MetricsQuery>,
 ) -> Result<Json<Metrics>, ServerError> {
+    Ok(Json(fetch_metrics(&db, query).await?))
+}
+
+/// resolves the range and resolution of a query and runs it
+async fn fetch_metrics(db: &db::Db, mut query: MetricsQuery) -> Result<Metrics, ServerError> {
     let now = Utc::now();
     if let Some(last) = query.last {
-        query.start = Some(now - last);
+        query.start = Some(
+            uptime::ago(now, last).map_err(|err| ServerError::BadRequest(format!("{err:#}")))?,
+        );
         query.end = Some(now);
     };
     let start = query.start.unwrap_or(now - Duration::from_secs(3600));
@@ -171,105 +517,674 @@ async fn handle_metrics(
     if end <= start {
         return Err(ServerError::InvalidEndDate);
     }
+    let percentiles = match &query.percentiles {
+        Some(percentiles) => parse_percentiles(percentiles)?,
+        None => vec![],
+    };
+    let filter = CheckFilter::new(&query)?;
     let window = (end - start).to_std()?;
-    let resolution = window.resolution();
+    let resolution = resolution(window, query.step, query.max_points)?;
     let metrics = db
         .with_conn(move |conn| {
-            let mut metrics = Metrics {
-                meta: Meta {
-                    res: resolution.as_secs(),
-                    start,
-                    end,
-                },
-                ..Default::default()
-            };
-            let mut rows = conn.prepare_cached(
-                "
-                    SELECT
-                        r.check_id,
-                        c.name,
-                        c.kind,
-                        r.epoch / :rollup * :rollup AS bucket,
-                        datetime(r.epoch / :rollup * :rollup, 'unixepoch') as time,
-                        CAST(MIN(r.ms) as INTEGER) AS min,
-                        CAST(AVG(r.ms) as INTEGER) AS avg,
-                        CAST(MAX(r.ms) as INTEGER) AS max,
-                        COUNT(*) AS count,
-                        COUNT(r.err) AS errs
-                    FROM results r
-                    JOIN checks c on r.check_id = c.id
-                    WHERE r.epoch >= :start_time
-                    AND r.epoch <= :end_time
-                    GROUP BY r.check_id, c.name, c.kind, bucket
-                    ORDER BY bucket, name, kind
-                    ",
-            )?;
-            let start = start.epoch_secs()?;
-            let end = end.epoch_secs()?;
-            let params = named_params! {
-                ":rollup": resolution.as_secs(),
-                ":start_time": start,
-                ":end_time": end,
-            };
-            #[allow(unused)]
-            struct Rollup {
-                id: u64,
-                name: String,
-                kind: String,
-                bucket: i64,
-                min: Option<u64>,
-                avg: Option<u64>,
-                max: Option<u64>,
-                count: usize,
-                errs: usize,
+            query_metrics(&conn, &query, &filter, start, end, resolution, &percentiles)
+        })
+        .await?;
+    Ok(metrics)
+}
+
+/// aggregates the results of the checks selected by the filter into buckets of the resolution
+fn query_metrics(
+    conn: &Connection,
+    query: &MetricsQuery,
+    filter: &CheckFilter,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    resolution: Duration,
+    percentiles: &[f64],
+) -> Result<Metrics> {
+    let mut metrics = Metrics {
+        meta: Meta {
+            res: resolution.as_secs(),
+            start,
+            end,
+        },
+        ..Default::default()
+    };
+    let start = start.epoch_millis()?;
+    let end = end.epoch_millis()?;
+    let rollup = resolution.as_millis() as u64;
+    let mut params = named_params! {
+        ":rollup": rollup,
+        ":start_time": start,
+        ":end_time": end,
+        ":archived": query.archived,
+    }
+    .to_vec();
+    params.extend(filter.params());
+    if percentiles.is_empty() {
+        query_rollups(conn, &filter.sql, &params, &mut metrics)?;
+    } else {
+        query_samples(conn, &filter.sql, &params, percentiles, &mut metrics)?;
+    }
+    if let Some(fill) = query.fill {
+        let step = resolution.as_millis() as u64;
+        for series in &mut metrics.series {
+            let values = std::mem::take(&mut series.values);
+            series.values = fill.apply(values, start, end, step);
+        }
+    }
+
+    let mut params = named_params! {
+        ":start_time": start,
+        ":end_time": end,
+        ":archived": query.archived,
+    }
+    .to_vec();
+    params.extend(filter.params());
+    query_configs(conn, &filter.sql, &params, &mut metrics)?;
+    Ok(metrics)
+}
+
+/// annotates each series with the config versions which were active during the window, once for
+/// every change so that switching back to an earlier version is shown too
+fn query_configs(
+    conn: &Connection,
+    filter: &str,
+    params: &[(&str, &dyn ToSql)],
+    metrics: &mut Metrics,
+) -> Result<()> {
+    let mut rows = conn.prepare_cached(&format!(
+        "
+            SELECT
+                c.name,
+                c.kind,
+                cc.hash,
+                cc.config,
+                v.epoch_ms AS since
+            FROM (
+                SELECT
+                    r.check_id,
+                    r.config_id,
+                    r.epoch_ms,
+                    LAG(r.config_id) OVER (PARTITION BY r.check_id ORDER BY r.epoch_ms) AS previous
+                FROM results r
+                JOIN checks c on r.check_id = c.id
+                WHERE r.epoch_ms >= :start_time
+                AND r.epoch_ms <= :end_time
+                AND (:archived OR c.archived_at IS NULL)
+                {filter}
+            ) v
+            JOIN checks c on v.check_id = c.id
+            JOIN check_configs cc on v.config_id = cc.id
+            WHERE v.previous IS NOT v.config_id
+            ORDER BY since
+            "
+    ))?;
+    let rows = rows
+        .query_map(params, |row| {
+            let name: String = row.get("name")?;
+            let kind: String = row.get("kind")?;
+            let hash: String = row.get("hash")?;
+            let config: String = row.get("config")?;
+            let since: i64 = row.get("since")?;
+            Ok((name, kind, hash, config, since))
+        })
+        .context("config query failed")?;
+    for row in rows {
+        let (name, kind, hash, config, since) = row?;
+        let kind = checker::Kind::try_from(kind.as_str())?;
+        let series = metrics.get_mut(&name, kind);
+        let since = DateTime::from_timestamp_millis(since)
+            .context("could not convert epoch to timestamp")?;
+        let config = serde_json::from_str(&config).context("parse config snapshot")?;
+        series.configs.push(ConfigVersion {
+            since,
+            hash,
+            config,
+        });
+    }
+    Ok(())
+}
+
+/// aggregates each bucket in the db
+fn query_rollups(
+    conn: &Connection,
+    filter: &str,
+    params: &[(&str, &dyn ToSql)],
+    metrics: &mut Metrics,
+) -> Result<()> {
+    let mut rows = conn.prepare_cached(&format!(
+        "
+            SELECT
+                r.check_id,
+                c.name,
+                c.kind,
+                r.epoch_ms / :rollup * :rollup AS bucket,
+                MIN(r.us) / 1000.0 AS min,
+                AVG(r.us) / 1000.0 AS avg,
+                MAX(r.us) / 1000.0 AS max,
+                COUNT(*) AS count,
+                COUNT(r.err) AS errs
+            FROM results r
+            JOIN checks c on r.check_id = c.id
+            WHERE r.epoch_ms >= :start_time
+            AND r.epoch_ms <= :end_time
+            AND (:archived OR c.archived_at IS NULL)
+            {filter}
+            GROUP BY r.check_id, c.name, c.kind, bucket
+            ORDER BY bucket, name, kind
+            "
+    ))?;
+    #[allow(unused)]
+    struct Rollup {
+        id: u64,
+        name: String,
+        kind: String,
+        bucket: i64,
+        min: Option<f64>,
+        avg: Option<f64>,
+        max: Option<f64>,
+        count: usize,
+        errs: usize,
+    }
+    let rows = rows
+        .query_map(params, |row| {
+            Ok(Rollup {
+                id: row.get("check_id")?,
+                name: row.get("name")?,
+                kind: row.get("kind")?,
+                bucket: row.get("bucket")?,
+                min: row.get("min")?,
+                max: row.get("max")?,
+                avg: row.get("avg")?,
+                count: row.get("count")?,
+                errs: row.get("errs")?,
+            })
+        })
+        .context("query failed")?;
+    for row in rows {
+        let row = row?;
+        let kind = checker::Kind::try_from(row.kind.as_str())?;
+        let series = metrics.get_mut(&row.name, kind);
+        let ts = DateTime::from_timestamp_millis(row.bucket)
+            .context("could not convert epoch to timestamp")?;
+        series.values.push(TimeValue {
+            ts,
+            avg: row.avg,
+            min: row.min,
+            max: row.max,
+            count: row.count,
+            errs: row.errs,
+            percentiles: BTreeMap::new(),
+        });
+    }
+    Ok(())
+}
+
+/// aggregates each bucket from the raw samples so that percentiles can be computed. percentiles
+/// are exact unless a bucket has more than [percentile::EXACT_LIMIT] samples, in which case they
+/// are estimated with a sketch.
+fn query_samples(
+    conn: &Connection,
+    filter: &str,
+    params: &[(&str, &dyn ToSql)],
+    percentiles: &[f64],
+    metrics: &mut Metrics,
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "
+            SELECT
+                c.name,
+                c.kind,
+                r.epoch_ms / :rollup * :rollup AS bucket,
+                r.us,
+                r.err IS NOT NULL AS err
+            FROM results r
+            JOIN checks c on r.check_id = c.id
+            WHERE r.epoch_ms >= :start_time
+            AND r.epoch_ms <= :end_time
+            AND (:archived OR c.archived_at IS NULL)
+            {filter}
+            ORDER BY r.check_id, r.epoch_ms
+            "
+    ))?;
+
+    /// the samples of one check in one bucket
+    struct Bucket {
+        name: String,
+        kind: checker::Kind,
+        bucket: i64,
+        count: usize,
+        errs: usize,
+        sum: f64,
+        min: Option<f64>,
+        max: Option<f64>,
+        samples: percentile::Samples,
+    }
+
+    let mut flush = |b: Bucket| -> Result<()> {
+        let Bucket {
+            name,
+            kind,
+            bucket,
+            count,
+            errs,
+            sum,
+            min,
+            max,
+            mut samples,
+        } = b;
+        let ts = DateTime::from_timestamp_millis(bucket)
+            .context("could not convert epoch to timestamp")?;
+        let oks = count - errs;
+        let percentiles = percentiles
+            .iter()
+            .filter_map(|p| {
+                let value = samples.quantile(p / 100.0)?;
+                Some((format!("p{p}"), value))
+            })
+            .collect();
+        metrics.get_mut(&name, kind).values.push(TimeValue {
+            ts,
+            count,
+            errs,
+            avg: (oks > 0).then(|| sum / oks as f64),
+            min,
+            max,
+            percentiles,
+        });
+        Ok(())
+    };
+
+    let mut rows = stmt.query(params)?;
+    let mut current: Option<Bucket> = None;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get("name")?;
+        let kind: String = row.get("kind")?;
+        let kind = checker::Kind::try_from(kind.as_str())?;
+        let bucket: i64 = row.get("bucket")?;
+        let us: Option<i64> = row.get("us")?;
+        let err: bool = row.get("err")?;
+        let same = current
+            .as_ref()
+            .is_some_and(|b| b.bucket == bucket && b.name == name && b.kind == kind);
+        if !same {
+            if let Some(b) = current.take() {
+                flush(b)?;
             }
-            let rows = rows
-                .query_map(params, |row| {
-                    Ok(Rollup {
-                        id: row.get("check_id")?,
-                        name: row.get("name")?,
-                        kind: row.get("kind")?,
-                        bucket: row.get("bucket")?,
-                        min: row.get("min")?,
-                        max: row.get("max")?,
-                        avg: row.get("avg")?,
-                        count: row.get("count")?,
-                        errs: row.get("errs")?,
-                    })
+            current = Some(Bucket {
+                name,
+                kind,
+                bucket,
+                count: 0,
+                errs: 0,
+                sum: 0.0,
+                min: None,
+                max: None,
+                samples: percentile::Samples::default(),
+            });
+        }
+        let b = current.as_mut().expect("bucket was just set");
+        b.count += 1;
+        if err {
+            b.errs += 1;
+        }
+        if let Some(us) = us {
+            let ms = us as f64 / 1000.0;
+            b.sum += ms;
+            b.min = Some(b.min.map_or(ms, |min| min.min(ms)));
+            b.max = Some(b.max.map_or(ms, |max| max.max(ms)));
+            b.samples.add(ms);
+        }
+    }
+    if let Some(b) = current.take() {
+        flush(b)?;
+    }
+    Ok(())
+}
+
+/// selects the checks returned by a query. each kind of selector narrows the checks further, and
+/// repeated selectors of the same kind match any of their values.
+struct CheckFilter {
+    /// the conditions appended to the where clause of a query which joins checks as `c`
+    sql: String,
+    params: Vec<(String, Value)>,
+}
+
+impl CheckFilter {
+    fn new(query: &MetricsQuery) -> Result<Self, ServerError> {
+        let mut filter = Self {
+            sql: String::new(),
+            params: vec![],
+        };
+        if !query.names.is_empty() {
+            let names = query.names.iter().map(|name| Value::Text(name.clone()));
+            let names = filter.bind("name", names);
+            let names: Vec<_> = names.iter().map(|n| format!("c.name GLOB {n}")).collect();
+            filter.sql += &format!(" AND ({})", names.join(" OR "));
+        }
+        if !query.kinds.is_empty() {
+            let kinds = query
+                .kinds
+                .iter()
+                .map(|kind| {
+                    let kind = checker::Kind::try_from(kind.as_str())
+                        .map_err(|err| ServerError::BadRequest(err.to_string()))?;
+                    Ok(Value::Text(kind.to_string()))
                 })
-                .context("query failed")?;
-            for row in rows {
-                let row = row?;
-                let kind = checker::Kind::try_from(row.kind.as_str())?;
-                let series = metrics.get_mut(&row.name, kind);
-                let ts = DateTime::from_timestamp(row.bucket, 0)
-                    .context("could not convert epoch to timestamp")?;
-
-                // TODO: we should be using None for avg/min/max if there are no valid samples when
-                // the series was only erroring out during this bucket. When we get a proper
-                // rendering FE in place this should change to not rendering these values.
-                series.values.push(TimeValue {
-                    ts,
-                    avg: row.avg.unwrap_or_default(),
-                    min: row.min.unwrap_or_default(),
-                    max: row.max.unwrap_or_default(),
-                    count: row.count,
-                    errs: row.errs,
-                });
+                .collect::<Result<Vec<_>, ServerError>>()?;
+            let kinds = filter.bind("kind", kinds);
+            filter.sql += &format!(" AND c.kind IN ({})", kinds.join(", "));
+        }
+        if let Some(regex) = &query.regex {
+            regex::Regex::new(regex)
+                .map_err(|err| ServerError::BadRequest(format!("invalid regex: {err}")))?;
+            let regex = filter.bind("regex", [Value::Text(regex.clone())]);
+            filter.sql += &format!(" AND c.name REGEXP {}", regex[0]);
+        }
+        for tag in &query.tags {
+            let (key, value) = match tag.split_once(':') {
+                Some((key, value)) => (key, Some(value)),
+                None => (tag.as_str(), None),
+            };
+            let key = filter.bind("tag_key", [Value::Text(key.to_string())]);
+            let mut sql = format!(
+                "SELECT 1 FROM check_tags t WHERE t.check_id = c.id AND t.key = {}",
+                key[0]
+            );
+            if let Some(value) = value {
+                let value = filter.bind("tag_value", [Value::Text(value.to_string())]);
+                sql += &format!(" AND t.value = {}", value[0]);
+            }
+            filter.sql += &format!(" AND EXISTS ({sql})");
+        }
+        Ok(filter)
+    }
+
+    /// adds a named parameter for each value, returning their names
+    fn bind(&mut self, prefix: &str, values: impl IntoIterator<Item = Value>) -> Vec<String> {
+        values
+            .into_iter()
+            .map(|value| {
+                let name = format!(":{prefix}_{}", self.params.len());
+                self.params.push((name.clone(), value));
+                name
+            })
+            .collect()
+    }
+
+    fn params(&self) -> impl Iterator<Item = (&str, &dyn ToSql)> {
+        self.params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value as &dyn ToSql))
+    }
+}
+
+/// escapes text for use in xml, such as the text and attributes of the svg charts and badges
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// parses a comma separated list of percentiles such as "50,90,p99,99.9"
+fn parse_percentiles(s: &str) -> Result<Vec<f64>, ServerError> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let value: f64 = p
+                .trim_start_matches('p')
+                .parse()
+                .map_err(|_| ServerError::BadRequest(format!("invalid percentile: '{p}'")))?;
+            if !(0.0..=100.0).contains(&value) {
+                return Err(ServerError::BadRequest(format!(
+                    "percentile must be between 0 and 100: '{p}'"
+                )));
             }
-            Ok(metrics)
+            Ok(value)
         })
+        .collect()
+}
+
+/// streams an export of the results. the export is written from a blocking thread into a channel
+/// which feeds the response body, so at most a few chunks are held in memory at once. the export
+/// only holds a connection while it reads a chunk, so a slow client does not block the checker.
+#[instrument(skip_all)]
+async fn handle_export(
+    State(Server { db, .. }): State<Server>,
+    axum_extra::extract::Query(req): axum_extra::extract::Query<export::Request>,
+) -> Result<Response, ServerError> {
+    let (start, end) = req
+        .range(Utc::now())
+        .map_err(|err| ServerError::BadRequest(format!("{err:#}")))?;
+    if end <= start {
+        return Err(ServerError::InvalidEndDate);
+    }
+    let format = req.format;
+    let (tx, rx) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let out = BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        let res = export::export(&db, &req, out);
+        if let Err(err) = res {
+            tracing::error!("export failed: {err:#}");
+            // aborts the response so that the client does not mistake it for a complete export
+            let _ = tx.blocking_send(Err(io::Error::other(format!("{err:#}"))));
+        }
+    });
+    let disposition = format!(
+        "attachment; filename=\"dialer-results.{}\"",
+        format.extension()
+    );
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (header::CONTENT_DISPOSITION, disposition),
+    ];
+    let body = Body::from_stream(ReceiverStream::new(rx));
+    Ok((headers, body).into_response())
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct EventsQuery {
+    /// only streams the results of these checks. may be repeated.
+    #[serde(rename = "check")]
+    checks: Vec<String>,
+    /// only streams the results of checks of this kind
+    kind: Option<String>,
+}
+
+/// streams each result as it is recorded. a `result` event is sent for every probe, and a `lagged`
+/// event with the number of skipped results is sent if the client falls behind.
+#[instrument(skip_all)]
+async fn handle_events(
+    State(Server { events, .. }): State<Server>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<EventsQuery>,
+) -> Result<impl IntoResponse, ServerError> {
+    let kind = query
+        .kind
+        .as_deref()
+        .map(checker::Kind::try_from)
+        .transpose()
+        .map_err(|err| ServerError::BadRequest(err.to_string()))?;
+    let stream = BroadcastStream::new(events.subscribe()).filter_map(move |event| match event {
+        Ok(event) => {
+            let wanted = (query.checks.is_empty() || query.checks.contains(&event.name))
+                && kind.is_none_or(|kind| kind == event.kind);
+            wanted.then(|| sse::Event::default().event("result").json_data(event))
+        }
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => Some(Ok(sse::Event::default()
+            .event("lagged")
+            .data(skipped.to_string()))),
+    });
+    Ok(Sse::new(stream).keep_alive(sse::KeepAlive::default()))
+}
+
+/// exposes the current state of the checks for prometheus to scrape
+#[instrument(skip_all)]
+async fn handle_prometheus(State(Server { stats, .. }): State<Server>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        stats.render(),
+    )
+}
+
+/// reports the availability of each check over the requested range
+#[instrument(skip_all)]
+async fn handle_uptime(
+    State(Server { config, db, .. }): State<Server>,
+    Query(req): Query<uptime::Request>,
+) -> Result<Json<uptime::Uptime>, ServerError> {
+    let (start, end) = req
+        .range(Utc::now())
+        .map_err(|err| ServerError::BadRequest(format!("{err:#}")))?;
+    let report = db
+        .with_conn(move |conn| uptime::report(&conn, &config.uptime, start, end, req.archived))
         .await?;
-    Ok(Json(metrics))
+    Ok(Json(report))
 }
 
-#[derive(Deserialize, Clone)]
+/// lists the incidents in the requested range, most recent first
+#[instrument(skip_all)]
+async fn handle_incidents(
+    State(Server { db, .. }): State<Server>,
+    axum_extra::extract::Query(req): axum_extra::extract::Query<incident::ListRequest>,
+) -> Result<Json<Vec<incident::Incident>>, ServerError> {
+    let (start, end) = req
+        .range(Utc::now())
+        .map_err(|err| ServerError::BadRequest(format!("{err:#}")))?;
+    if end <= start {
+        return Err(ServerError::InvalidEndDate);
+    }
+    let incidents = db
+        .with_conn(move |conn| incident::list(&conn, &req, Utc::now()))
+        .await?;
+    Ok(Json(incidents))
+}
+
+#[instrument(skip_all)]
+async fn handle_incident(
+    State(Server { db, .. }): State<Server>,
+    Path(id): Path<u64>,
+) -> Result<Json<incident::Detail>, ServerError> {
+    let detail = db
+        .with_conn(move |conn| incident::get(&conn, id, Utc::now()))
+        .await?;
+    detail.map(Json).ok_or(ServerError::NotFound)
+}
+
+/// writes each buffer to a channel as a chunk of the response body
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// the outcome of the latest reloads of the config file, including why the latest one was
+/// rejected
+#[instrument(skip_all)]
+async fn handle_reload(State(Server { reload, .. }): State<Server>) -> Json<reload::Report> {
+    Json(reload.report())
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct BackupQuery {
+    compress: bool,
+}
+
+/// streams a snapshot of the db. requires the bearer token configured in the backup config, or an
+/// admin when auth is configured.
+#[instrument(skip_all)]
+async fn handle_backup(
+    State(Server { config, db, .. }): State<Server>,
+    headers: HeaderMap,
+    Query(query): Query<BackupQuery>,
+) -> Result<Response, ServerError> {
+    let token = config.backup.token.as_ref();
+    if token.is_none() && !auth::enabled(&config.auth) {
+        return Err(ServerError::NotFound);
+    }
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let backup_token = token
+        .zip(bearer)
+        .is_some_and(|(token, bearer)| auth::constant_time_eq(bearer.as_bytes(), token.as_bytes()));
+    let admin = auth::authenticate(&config.auth, &headers)
+        .is_some_and(|identity| identity.role == config::Role::Admin);
+    if !backup_token && !admin {
+        if auth::enabled(&config.auth) {
+            return Ok(auth::unauthorized(&config.auth));
+        }
+        return Err(ServerError::Unauthorized);
+    }
+    // the snapshot is unlinked once the dir is dropped but remains readable through the open file
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("backup");
+    backup::to_path(&db, path.clone(), query.compress).await?;
+    let file = tokio::fs::File::open(&path).await?;
+    drop(dir);
+    let (content_type, ext) = if query.compress {
+        ("application/gzip", "db.gz")
+    } else {
+        ("application/vnd.sqlite3", "db")
+    };
+    let disposition = format!(
+        "attachment; filename=\"dialer-{}.{ext}\"",
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    let headers = [
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (header::CONTENT_DISPOSITION, disposition),
+    ];
+    Ok((headers, Body::from_stream(ReaderStream::new(file))).into_response())
+}
+
+#[derive(Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct MetricsQuery {
     start: Option<DateTime<Utc>>,
     end: Option<DateTime<Utc>>,
     #[serde(with = "humantime_serde")]
     last: Option<Duration>,
+    /// include checks which have been removed from the config
+    archived: bool,
+    /// a comma separated list of the percentiles to compute for each bucket, e.g. "50,90,99"
+    percentiles: Option<String>,
+    /// the size of each bucket, e.g. "1m". overrides max_points.
+    #[serde(with = "humantime_serde")]
+    step: Option<Duration>,
+    /// the most buckets to return per series. the smallest step which fits is chosen.
+    max_points: Option<u64>,
+    /// fills in buckets without any results. they are omitted by default.
+    fill: Option<Fill>,
+    /// the names of the checks to return, which may be glob patterns such as "isp-*". may be
+    /// repeated.
+    #[serde(rename = "name")]
+    names: Vec<String>,
+    /// the kinds of checks to return. may be repeated.
+    #[serde(rename = "kind")]
+    kinds: Vec<String>,
+    /// only returns checks whose name matches this regular expression
+    regex: Option<String>,
+    /// only returns checks with these tags, given as "key:value" or just "key". may be repeated.
+    #[serde(rename = "tag")]
+    tags: Vec<String>,
 }
 
 impl std::fmt::Debug for MetricsQuery {
@@ -285,16 +1200,6 @@ impl std::fmt::Debug for MetricsQuery {
     }
 }
 
-impl Default for MetricsQuery {
-    fn default() -> Self {
-        Self {
-            start: None,
-            end: None,
-            last: None,
-        }
-    }
-}
-
 #[derive(Debug, Serialize, Default)]
 pub struct Metrics {
     meta: Meta,
@@ -317,6 +1222,7 @@ impl Metrics {
                 let series = Series {
                     kind,
                     name: name.to_string(),
+                    configs: vec![],
                     values: Vec::with_capacity(1024),
                 };
                 self.series.push(series);
@@ -339,17 +1245,47 @@ impl Metrics {
 pub struct Series {
     pub kind: checker::Kind,
     pub name: String,
+    /// the config versions which produced the values in this series, in the order in which they
+    /// became active. more than one version means that the check was reconfigured in the window.
+    pub configs: Vec<ConfigVersion>,
     pub values: Vec<TimeValue>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct ConfigVersion {
+    /// the time of the first result in the window produced by this config
+    pub since: DateTime<Utc>,
+    pub hash: String,
+    pub config: serde_json::Value,
+}
+
 #[derive(Debug, Serialize)]
 pub struct TimeValue {
     pub ts: DateTime<Utc>,
     pub count: usize,
     pub errs: usize,
-    pub avg: u64,
-    pub min: u64,
-    pub max: u64,
+    /// latencies in milliseconds with microsecond precision. none when every probe in the
+    /// bucket failed.
+    pub avg: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// the requested percentiles keyed by name, e.g. "p99"
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub percentiles: BTreeMap<String, f64>,
+}
+
+impl TimeValue {
+    fn empty(ts: DateTime<Utc>) -> Self {
+        Self {
+            ts,
+            count: 0,
+            errs: 0,
+            avg: None,
+            min: None,
+            max: None,
+            percentiles: BTreeMap::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -357,3 +1293,531 @@ pub struct Error {
     pub msg: String,
     pub kind: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(
+            parse_percentiles("50, p90,99.9,").unwrap(),
+            vec![50.0, 90.0, 99.9]
+        );
+        assert!(matches!(
+            parse_percentiles("101"),
+            Err(ServerError::BadRequest(_))
+        ));
+        assert!(matches!(
+            parse_percentiles("fast"),
+            Err(ServerError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn samples() {
+        let db = db::Db::in_memory().await.unwrap();
+        let conn = db.conn().unwrap();
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('google', 'ping');
+            insert into results (check_id, epoch_ms, us, err) values
+                (1, 1000, 1000, null),
+                (1, 2000, 2000, null),
+                (1, 3000, 3000, null),
+                (1, 4000, null, 'timeout'),
+                (1, 6000, 5000, null);
+            ",
+        )
+        .unwrap();
+        let mut metrics = Metrics::default();
+        let params = named_params! {
+            ":rollup": 5000,
+            ":start_time": 0,
+            ":end_time": 10000,
+            ":archived": false,
+        };
+        query_samples(&conn, "", params, &[50.0, 100.0], &mut metrics).unwrap();
+        let values = &metrics.get_mut("google", checker::Kind::Ping).values;
+        assert_eq!(values.len(), 2);
+        let first = &values[0];
+        assert_eq!((first.count, first.errs), (4, 1));
+        assert_eq!(
+            (first.min, first.avg, first.max),
+            (Some(1.0), Some(2.0), Some(3.0))
+        );
+        assert_eq!(first.percentiles["p50"], 2.0);
+        assert_eq!(first.percentiles["p100"], 3.0);
+        assert_eq!(values[1].percentiles["p50"], 5.0);
+    }
+
+    #[tokio::test]
+    async fn configs() {
+        let db = db::Db::in_memory().await.unwrap();
+        let conn = db.conn().unwrap();
+        conn.execute_batch(
+            r#"
+            insert into checks (name, kind) values ('google', 'ping');
+            insert into check_configs (check_id, hash, config) values
+                (1, 'a', '{"host":"google.com"}'), (1, 'b', '{"host":"8.8.8.8"}');
+            insert into results (check_id, epoch_ms, us, config_id) values
+                (1, 1000, 1000, 1),
+                (1, 2000, 1000, 1),
+                (1, 3000, 1000, 2),
+                (1, 4000, 1000, 1),
+                (1, 5000, 1000, 1);
+            "#,
+        )
+        .unwrap();
+        let mut metrics = Metrics::default();
+        let params = named_params! {
+            ":start_time": 0,
+            ":end_time": 10000,
+            ":archived": false,
+        };
+        query_configs(&conn, "", params, &mut metrics).unwrap();
+        let configs = &metrics.get_mut("google", checker::Kind::Ping).configs;
+        let configs: Vec<_> = configs
+            .iter()
+            .map(|c| (c.hash.as_str(), c.since.timestamp_millis()))
+            .collect();
+        assert_eq!(configs, vec![("a", 1000), ("b", 3000), ("a", 4000)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_while_checking() {
+        let db = db::Db::in_memory().await.unwrap();
+        let config = Config::try_from(
+            r#"
+            interval = "50ms"
+
+            [http]
+            refused = { url = "http://127.0.0.1:1/" }
+            "#,
+        )
+        .unwrap();
+        let checker = checker::Checker::new(db.clone(), &config).await.unwrap();
+        // enough results that the export fills the channel which feeds the response body
+        db.conn()
+            .unwrap()
+            .execute_batch(
+                "
+                with recursive n(i) as (select 1 union all select i + 1 from n where i < 50000)
+                insert into results (check_id, epoch_ms, us) select 1, i, 1000 from n;
+                ",
+            )
+            .unwrap();
+        let server = Server::new(&config, db.clone(), checker.clone(), Default::default()).unwrap();
+        let count = || {
+            db.with_conn(|conn| {
+                Ok(conn.query_row("select count(*) from results", [], |row| {
+                    row.get::<_, usize>(0)
+                })?)
+            })
+        };
+
+        let query = axum_extra::extract::Query(export::Request::default());
+        let res = handle_export(State(server), query).await.unwrap();
+        let before = count().await.unwrap();
+        let run = tokio::spawn(async move { checker.run().await });
+        // the body is not read yet, so the export is stalled on the channel while the checker runs
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(count().await.unwrap() > before);
+        run.abort();
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let lines = body.split(|b| *b == b'\n').filter(|l| !l.is_empty());
+        assert!(lines.count() > 50_000);
+    }
+
+    #[tokio::test]
+    async fn router() {
+        use axum::body::Body;
+        use base64::{prelude::BASE64_STANDARD, Engine};
+        use tower::ServiceExt;
+
+        let db = db::Db::in_memory().await.unwrap();
+        let rtr = |public_badges: bool| {
+            let db = db.clone();
+            async move {
+                let config = Config::try_from(&*format!(
+                    r#"
+                    [auth]
+                    public_badges = {public_badges}
+
+                    [[auth.tokens]]
+                    name = "grafana"
+                    token = "r3ad"
+
+                    [[auth.tokens]]
+                    name = "deploy"
+                    token = "adm1n"
+                    role = "admin"
+
+                    [[auth.users]]
+                    name = "bob"
+                    password = "correct horse"
+                    role = "admin"
+
+                    [ping]
+                    gateway = {{ host = "127.0.0.1" }}
+                    "#
+                ))
+                .unwrap();
+                let checker = checker::Checker::new(db.clone(), &config).await.unwrap();
+                Server::new(&config, db, checker, Default::default())
+                    .unwrap()
+                    .router()
+            }
+        };
+        let status = |rtr: &axum::Router, method: &str, uri: &str, headers: &[(&str, String)]| {
+            let mut req = axum::http::Request::builder().method(method).uri(uri);
+            for (name, value) in headers {
+                req = req.header(*name, value);
+            }
+            let res = rtr.clone().oneshot(req.body(Body::empty()).unwrap());
+            async move { res.await.unwrap().status() }
+        };
+        let bearer = |token: &str| (header::AUTHORIZATION.as_str(), format!("Bearer {token}"));
+        let basic = (
+            header::AUTHORIZATION.as_str(),
+            format!("Basic {}", BASE64_STANDARD.encode("bob:correct horse")),
+        );
+
+        let private = rtr(false).await;
+        for uri in [
+            "/",
+            "/query",
+            "/chart.svg",
+            "/metrics",
+            "/events",
+            "/ws",
+            "/export",
+            "/checks",
+            "/checks/gateway",
+            "/reload",
+            "/uptime",
+            "/incidents",
+            "/incidents/1",
+            "/old",
+            "/fragments/checks",
+            "/fragments/incidents",
+            "/badges/uptime/gateway.svg",
+        ] {
+            let unauthorized = status(&private, "GET", uri, &[]).await;
+            assert_eq!(unauthorized, StatusCode::UNAUTHORIZED, "{uri}");
+        }
+        for (method, uri) in [
+            ("POST", "/checks"),
+            ("PUT", "/checks/gateway"),
+            ("DELETE", "/checks/gateway"),
+            ("POST", "/checks/gateway/pause"),
+            ("POST", "/checks/gateway/resume"),
+        ] {
+            let unauthorized = status(&private, method, uri, &[]).await;
+            assert_eq!(unauthorized, StatusCode::UNAUTHORIZED, "{method} {uri}");
+            let forbidden = status(&private, method, uri, &[bearer("r3ad")]).await;
+            assert_eq!(forbidden, StatusCode::FORBIDDEN, "{method} {uri}");
+        }
+        for uri in ["/status", "/status.json", "/favicon.ico"] {
+            assert_eq!(
+                status(&private, "GET", uri, &[]).await,
+                StatusCode::OK,
+                "{uri}"
+            );
+        }
+        let checks = status(&private, "GET", "/checks", &[bearer("r3ad")]).await;
+        assert_eq!(checks, StatusCode::OK);
+
+        // a form on another site cannot pause a check with the basic auth of the browser
+        let pause = "/checks/gateway/pause";
+        let cross = ("sec-fetch-site", String::from("cross-site"));
+        let same = ("sec-fetch-site", String::from("same-origin"));
+        let forbidden = status(&private, "POST", pause, &[basic.clone(), cross]).await;
+        assert_eq!(forbidden, StatusCode::FORBIDDEN);
+        let paused = status(&private, "POST", pause, &[basic.clone(), same]).await;
+        assert!(paused.is_success(), "{paused}");
+        let resumed = status(
+            &private,
+            "POST",
+            "/checks/gateway/resume",
+            &[bearer("adm1n")],
+        )
+        .await;
+        assert!(resumed.is_success(), "{resumed}");
+
+        // nor can it read the live results through the websocket
+        let upgrade = [
+            (header::CONNECTION.as_str(), String::from("upgrade")),
+            (header::UPGRADE.as_str(), String::from("websocket")),
+            (header::SEC_WEBSOCKET_VERSION.as_str(), String::from("13")),
+            (
+                header::SEC_WEBSOCKET_KEY.as_str(),
+                String::from("dGhlIHNhbXBsZSBub25jZQ=="),
+            ),
+            (header::HOST.as_str(), String::from("dialer:8080")),
+        ];
+        let origin = |origin: &str| (header::ORIGIN.as_str(), origin.to_string());
+        let mut cross = upgrade.to_vec();
+        cross.extend([basic.clone(), origin("https://evil.example")]);
+        assert_eq!(
+            status(&private, "GET", "/ws", &cross).await,
+            StatusCode::FORBIDDEN
+        );
+        let mut same = upgrade.to_vec();
+        same.extend([basic.clone(), origin("http://dialer:8080")]);
+        assert_ne!(
+            status(&private, "GET", "/ws", &same).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let public = rtr(true).await;
+        let badge = status(&public, "GET", "/badges/uptime/gateway.svg", &[]).await;
+        assert_eq!(badge, StatusCode::OK);
+        let checks = status(&public, "GET", "/checks", &[]).await;
+        assert_eq!(checks, StatusCode::UNAUTHORIZED);
+
+        // a range too long to subtract from now is refused rather than panicking
+        let uri = "/badges/uptime/gateway.svg?last=100000000y";
+        assert_eq!(
+            status(&public, "GET", uri, &[]).await,
+            StatusCode::BAD_REQUEST
+        );
+        for uri in [
+            "/uptime?last=100000000y",
+            "/incidents?last=100000000y",
+            "/export?last=100000000y",
+            "/query?last=100000000y",
+            "/?last=100000000y",
+        ] {
+            let status = status(&public, "GET", uri, &[bearer("r3ad")]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn backup() {
+        let db = db::Db::in_memory().await.unwrap();
+        let status = |config: &str, authorization: Option<&str>| {
+            let (db, config) = (db.clone(), Config::try_from(config).unwrap());
+            let authorization = authorization.map(ToString::to_string);
+            async move {
+                let checker = checker::Checker::new(db.clone(), &config).await.unwrap();
+                let server = Server::new(&config, db, checker, Default::default()).unwrap();
+                let mut headers = HeaderMap::new();
+                if let Some(authorization) = authorization {
+                    headers.insert(header::AUTHORIZATION, authorization.parse().unwrap());
+                }
+                let query = Query(BackupQuery::default());
+                match handle_backup(State(server), headers, query).await {
+                    Ok(res) => res.status(),
+                    Err(err) => err.into_response().status(),
+                }
+            }
+        };
+        let token = "[backup]\ntoken = \"s3cret\"";
+        assert_eq!(status("", None).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(token, Some("Bearer s3cret")).await, StatusCode::OK);
+        assert_eq!(
+            status(token, Some("Bearer wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(token, None).await, StatusCode::UNAUTHORIZED);
+
+        // admins may take backups too, but readers may not
+        let auth = "
+            [[auth.tokens]]
+            name = \"grafana\"
+            token = \"r3ad\"
+
+            [[auth.tokens]]
+            name = \"deploy\"
+            token = \"adm1n\"
+            role = \"admin\"
+            ";
+        assert_eq!(status(auth, Some("Bearer adm1n")).await, StatusCode::OK);
+        assert_eq!(
+            status(auth, Some("Bearer r3ad")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(auth, None).await, StatusCode::UNAUTHORIZED);
+        let both = format!("{token}\n{auth}");
+        assert_eq!(status(&both, Some("Bearer s3cret")).await, StatusCode::OK);
+        assert_eq!(status(&both, Some("Bearer adm1n")).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn resolution() {
+        let mins = |m: u64| Duration::from_secs(60 * m);
+        // the defaults match the previous fixed resolutions for short windows
+        assert_eq!(
+            super::resolution(mins(10), None, None).unwrap(),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            super::resolution(mins(60), None, None).unwrap(),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            super::resolution(mins(60 * 24 * 30), None, None).unwrap(),
+            mins(60)
+        );
+        assert_eq!(
+            super::resolution(mins(60), None, Some(60)).unwrap(),
+            mins(1)
+        );
+        assert_eq!(
+            super::resolution(mins(60 * 24 * 365 * 10), None, None).unwrap(),
+            mins(60 * 24 * 4)
+        );
+        assert_eq!(
+            super::resolution(mins(60), Some(mins(15)), None).unwrap(),
+            mins(15)
+        );
+        for (step, max_points) in [
+            (Some(Duration::from_millis(500)), None),
+            (Some(Duration::from_secs(1)), None),
+            (None, Some(0)),
+            (None, Some(MAX_POINTS + 1)),
+        ] {
+            assert!(matches!(
+                super::resolution(mins(60 * 24), step, max_points),
+                Err(ServerError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn fill() {
+        let ts = |ms: i64| DateTime::from_timestamp_millis(ms).unwrap();
+        let values = || {
+            vec![
+                TimeValue {
+                    count: 1,
+                    avg: Some(2.0),
+                    min: Some(2.0),
+                    max: Some(2.0),
+                    ..TimeValue::empty(ts(1000))
+                },
+                TimeValue {
+                    count: 1,
+                    errs: 1,
+                    ..TimeValue::empty(ts(3000))
+                },
+            ]
+        };
+        let avgs = |fill: Fill| {
+            fill.apply(values(), 500, 4000, 1000)
+                .iter()
+                .map(|v| (v.ts.timestamp_millis(), v.count, v.avg))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            avgs(Fill::Null),
+            vec![
+                (0, 0, None),
+                (1000, 1, Some(2.0)),
+                (2000, 0, None),
+                (3000, 1, None),
+                (4000, 0, None),
+            ]
+        );
+        assert_eq!(
+            avgs(Fill::Zero),
+            vec![
+                (0, 0, Some(0.0)),
+                (1000, 1, Some(2.0)),
+                (2000, 0, Some(0.0)),
+                (3000, 1, None),
+                (4000, 0, Some(0.0)),
+            ]
+        );
+        assert_eq!(
+            avgs(Fill::Previous),
+            vec![
+                (0, 0, None),
+                (1000, 1, Some(2.0)),
+                (2000, 0, Some(2.0)),
+                (3000, 1, None),
+                (4000, 0, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn filter() {
+        let db = db::Db::in_memory().await.unwrap();
+        let conn = db.conn().unwrap();
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values
+                ('gateway', 'ping'), ('gateway', 'http'), ('isp-a', 'ping'), ('isp-b', 'ping');
+            insert into check_tags (check_id, key, value) values
+                (1, 'site', 'home'), (3, 'site', 'home'), (4, 'site', 'office');
+            insert into results (check_id, epoch_ms, us) values
+                (1, 1000, 1000), (2, 1000, 1000), (3, 1000, 1000), (4, 1000, 1000);
+            ",
+        )
+        .unwrap();
+        let names = |query: MetricsQuery| {
+            let filter = CheckFilter::new(&query).unwrap();
+            let mut params = named_params! {
+                ":rollup": 1000,
+                ":start_time": 0,
+                ":end_time": 10000,
+                ":archived": false,
+            }
+            .to_vec();
+            params.extend(filter.params());
+            let mut metrics = Metrics::default();
+            query_rollups(&conn, &filter.sql, &params, &mut metrics).unwrap();
+            metrics
+                .series
+                .iter()
+                .map(|s| format!("{}/{}", s.name, s.kind))
+                .collect::<Vec<_>>()
+        };
+        let strings = |s: &[&str]| s.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(names(MetricsQuery::default()).len(), 4);
+        assert_eq!(
+            names(MetricsQuery {
+                names: strings(&["isp-*", "gateway"]),
+                kinds: strings(&["ping"]),
+                ..Default::default()
+            }),
+            vec!["gateway/ping", "isp-a/ping", "isp-b/ping"]
+        );
+        assert_eq!(
+            names(MetricsQuery {
+                regex: Some(String::from("^isp-[b-z]$")),
+                ..Default::default()
+            }),
+            vec!["isp-b/ping"]
+        );
+        assert_eq!(
+            names(MetricsQuery {
+                tags: strings(&["site:home", "site"]),
+                names: strings(&["isp-*"]),
+                ..Default::default()
+            }),
+            vec!["isp-a/ping"]
+        );
+        for query in [
+            MetricsQuery {
+                kinds: strings(&["dns"]),
+                ..Default::default()
+            },
+            MetricsQuery {
+                regex: Some(String::from("(")),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                CheckFilter::new(&query),
+                Err(ServerError::BadRequest(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! the static files in `html/`, which are embedded in the binary so that it can be deployed on its
+//! own. responses carry an etag so that browsers only download a file again once it changes.
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+
+#[derive(RustEmbed)]
+#[folder = "html/"]
+struct Assets;
+
+/// third party files, which are served from `html/vendor/` once they have been downloaded by
+/// `scripts/vendor.sh` and from their cdn otherwise
+const VENDOR: &[(&str, &str)] = &[
+    (
+        "bootstrap.min.css",
+        "https://cdn.jsdelivr.net/npm/bootstrap@5.3.3/dist/css/bootstrap.min.css",
+    ),
+    (
+        "bootstrap.bundle.min.js",
+        "https://cdn.jsdelivr.net/npm/bootstrap@5.3.3/dist/js/bootstrap.bundle.min.js",
+    ),
+    (
+        "htmx.min.js",
+        "https://unpkg.com/htmx.org@2.0.2/dist/htmx.min.js",
+    ),
+    (
+        "d3.min.js",
+        "https://cdnjs.cloudflare.com/ajax/libs/d3/7.8.5/d3.min.js",
+    ),
+];
+
+/// the url of a third party file
+pub fn vendor_url(name: &str) -> String {
+    let path = format!("vendor/{name}");
+    if Assets::get(&path).is_some() {
+        return format!("/{path}");
+    }
+    match VENDOR.iter().find(|(file, _)| *file == name) {
+        Some((_, url)) => url.to_string(),
+        None => format!("/{path}"),
+    }
+}
+
+/// the third party files which were not downloaded before the build, and so are loaded from their
+/// cdn
+pub(super) fn missing_vendor() -> Vec<&'static str> {
+    VENDOR
+        .iter()
+        .map(|(file, _)| *file)
+        .filter(|file| Assets::get(&format!("vendor/{file}")).is_none())
+        .collect()
+}
+
+/// serves an embedded file
+pub(super) async fn handle_asset(uri: Uri, headers: HeaderMap) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    let path = match path.is_empty() || path.ends_with('/') {
+        true => format!("{path}index.html"),
+        false => path.to_string(),
+    };
+    let Some(file) = Assets::get(&path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let hash: String = file
+        .metadata
+        .sha256_hash()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let etag = format!("\"{hash}\"");
+    // vendored files only change when they are upgraded, so they are not revalidated as often
+    let cache_control = match path.starts_with("vendor/") {
+        true => "public, max-age=86400",
+        false => "no-cache",
+    };
+    let not_modified = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == etag || tag.trim() == "*");
+    let cache_headers = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, cache_control.to_string()),
+    ];
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (
+        cache_headers,
+        [(header::CONTENT_TYPE, file.metadata.mimetype().to_string())],
+        file.data,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn handle_asset() {
+        let uri = Uri::from_static("/favicon.ico");
+        let res = super::handle_asset(uri.clone(), HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "image/x-icon");
+        let etag = res.headers()[header::ETAG].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        let res = super::handle_asset(uri.clone(), headers).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], etag);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, "\"stale\"".parse().unwrap());
+        let res = super::handle_asset(uri, headers).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = super::handle_asset(Uri::from_static("/nope.js"), HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
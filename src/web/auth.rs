@@ -0,0 +1,283 @@
+//! authentication of the web ui and api. machine clients send a static token as a bearer token
+//! and people sign in with http basic auth. the read role may use anything which only reads,
+//! while requests which change something, such as managing the checks, need the admin role.
+//!
+//! auth is off unless tokens or users are configured. the status page and the static assets are
+//! always public, as are the badges when `public_badges` is set.
+//!
+//! browsers resend basic auth on their own, so changes made with it, and the websocket, must come
+//! from a page of dialer itself rather than another site.
+use crate::config::{self, Role};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use std::sync::Arc;
+use tracing::warn;
+
+/// a token or user which authenticated
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Identity<'a> {
+    pub name: &'a str,
+    pub role: Role,
+}
+
+pub(super) fn enabled(auth: &config::Auth) -> bool {
+    !auth.tokens.is_empty() || !auth.users.is_empty()
+}
+
+/// finds the token or user of the authorization header
+pub(super) fn authenticate<'a>(
+    auth: &'a config::Auth,
+    headers: &HeaderMap,
+) -> Option<Identity<'a>> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    if let Some(bearer) = value.strip_prefix("Bearer ") {
+        return auth
+            .tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.as_bytes(), bearer.as_bytes()))
+            .map(|t| Identity {
+                name: &t.name,
+                role: t.role,
+            });
+    }
+    let basic = value.strip_prefix("Basic ")?;
+    let basic = BASE64_STANDARD.decode(basic.trim()).ok()?;
+    let (name, password) = std::str::from_utf8(&basic).ok()?.split_once(':')?;
+    // every user is compared so that the time taken does not reveal which names exist
+    auth.users
+        .iter()
+        .fold(None, |found, u| {
+            let name = constant_time_eq(u.name.as_bytes(), name.as_bytes());
+            let password = constant_time_eq(u.password.as_bytes(), password.as_bytes());
+            if name & password {
+                Some(u)
+            } else {
+                found
+            }
+        })
+        .map(|u| Identity {
+            name: &u.name,
+            role: u.role,
+        })
+}
+
+/// the role needed to make a request with the method
+fn required(method: &Method) -> Role {
+    match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => Role::Read,
+        _ => Role::Admin,
+    }
+}
+
+/// rejects requests without a token or user that has the role needed for the method
+pub(super) async fn authorize(
+    State(auth): State<Arc<config::Auth>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !enabled(&auth) {
+        return next.run(req).await;
+    }
+    let Some(identity) = authenticate(&auth, req.headers()) else {
+        warn!("Unauthenticated request for {}", req.uri().path());
+        return unauthorized(&auth);
+    };
+    if identity.role < required(req.method()) {
+        warn!(
+            "{} may not {} {}",
+            identity.name,
+            req.method(),
+            req.uri().path()
+        );
+        return (StatusCode::FORBIDDEN, "this needs the admin role").into_response();
+    }
+    let changes = required(req.method()) > Role::Read;
+    if (changes || is_websocket(req.headers()))
+        && is_basic(req.headers())
+        && cross_site(req.headers())
+    {
+        warn!(
+            "Refused a cross site {} {} by {}",
+            req.method(),
+            req.uri().path(),
+            identity.name
+        );
+        return (StatusCode::FORBIDDEN, "cross site requests are not allowed").into_response();
+    }
+    next.run(req).await
+}
+
+/// whether the request signed in with basic auth, which browsers send without being asked
+fn is_basic(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("Basic "))
+}
+
+/// whether the request opens a websocket, which browsers open from any site since websockets are
+/// not covered by cors
+fn is_websocket(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// whether a browser made the request from another site. `Sec-Fetch-Site` is preferred, and older
+/// browsers are caught by an `Origin` which differs from the `Host`.
+fn cross_site(headers: &HeaderMap) -> bool {
+    let get = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(site) = get(header::HeaderName::from_static("sec-fetch-site")) {
+        return !matches!(site, "same-origin" | "none");
+    }
+    match (get(header::ORIGIN), get(header::HOST)) {
+        (Some(origin), Some(host)) => origin
+            .split_once("://")
+            .is_none_or(|(_, origin)| !origin.eq_ignore_ascii_case(host)),
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// asks for basic auth when users are configured, so that browsers prompt for a sign in
+pub(super) fn unauthorized(auth: &config::Auth) -> Response {
+    let challenge = if auth.users.is_empty() {
+        "Bearer"
+    } else {
+        "Basic realm=\"dialer\", charset=\"UTF-8\""
+    };
+    let headers = [(header::WWW_AUTHENTICATE, challenge)];
+    (StatusCode::UNAUTHORIZED, headers).into_response()
+}
+
+/// compares two byte strings in constant time with respect to their contents
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn authorize() {
+        let config = config::Config::try_from(
+            r#"
+            [[auth.tokens]]
+            name = "grafana"
+            token = "s3cret"
+
+            [[auth.tokens]]
+            name = "deploy"
+            token = "adm1n"
+            role = "admin"
+
+            [[auth.users]]
+            name = "alice"
+            password = "hunter2"
+
+            [[auth.users]]
+            name = "bob"
+            password = "correct horse"
+            role = "admin"
+            "#,
+        )
+        .unwrap();
+        let auth = Arc::new(config.auth);
+        let rtr = Router::new()
+            .route("/checks", routing::get(|| async {}).post(|| async {}))
+            .route_layer(axum::middleware::from_fn_with_state(
+                auth.clone(),
+                super::authorize,
+            ));
+        let status = |method: Method, authorization: Option<String>| {
+            let rtr = rtr.clone();
+            async move {
+                let mut req = Request::builder().method(method).uri("/checks");
+                if let Some(authorization) = authorization {
+                    req = req.header(header::AUTHORIZATION, authorization);
+                }
+                let res = rtr.oneshot(req.body(Body::empty()).unwrap()).await;
+                res.unwrap().status()
+            }
+        };
+        let bearer = |token: &str| Some(format!("Bearer {token}"));
+        let basic =
+            |credentials: &str| Some(format!("Basic {}", BASE64_STANDARD.encode(credentials)));
+
+        assert_eq!(status(Method::GET, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Method::GET, bearer("wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(Method::GET, bearer("s3cret")).await, StatusCode::OK);
+        assert_eq!(
+            status(Method::POST, bearer("s3cret")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(Method::POST, bearer("adm1n")).await, StatusCode::OK);
+        assert_eq!(
+            status(Method::GET, basic("alice:hunter2")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::GET, basic("alice:hunter3")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(Method::GET, basic("hunter2")).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // changes with basic auth must come from the same site
+        let post = |headers: &[(&'static str, &'static str)]| {
+            let rtr = rtr.clone();
+            let mut req = Request::post("/checks")
+                .header(header::AUTHORIZATION, basic("bob:correct horse").unwrap());
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            async move { rtr.oneshot(req.body(Body::empty()).unwrap()).await }
+        };
+        let status = |res: Result<Response, _>| res.unwrap().status();
+        assert_eq!(status(post(&[]).await), StatusCode::OK);
+        let same = [("sec-fetch-site", "same-origin")];
+        assert_eq!(status(post(&same).await), StatusCode::OK);
+        let cross = [("sec-fetch-site", "cross-site")];
+        assert_eq!(status(post(&cross).await), StatusCode::FORBIDDEN);
+        let same = [("origin", "http://dialer:8080"), ("host", "dialer:8080")];
+        assert_eq!(status(post(&same).await), StatusCode::OK);
+        let cross = [("origin", "https://evil.example"), ("host", "dialer:8080")];
+        assert_eq!(status(post(&cross).await), StatusCode::FORBIDDEN);
+        let cross = [("origin", "null"), ("host", "dialer:8080")];
+        assert_eq!(status(post(&cross).await), StatusCode::FORBIDDEN);
+        // tokens are not sent by browsers on their own
+        let req = Request::post("/checks")
+            .header(header::AUTHORIZATION, bearer("adm1n").unwrap())
+            .header("sec-fetch-site", "cross-site");
+        let res = rtr.clone().oneshot(req.body(Body::empty()).unwrap()).await;
+        assert_eq!(status(res), StatusCode::OK);
+
+        let res = super::unauthorized(&auth);
+        let challenge = res.headers().get(header::WWW_AUTHENTICATE).unwrap();
+        assert!(challenge.to_str().unwrap().starts_with("Basic"));
+
+        // everything is open when nothing is configured
+        let rtr = Router::new()
+            .route("/checks", routing::post(|| async {}))
+            .route_layer(axum::middleware::from_fn_with_state(
+                Arc::new(config::Auth::default()),
+                super::authorize,
+            ));
+        let req = Request::post("/checks").body(Body::empty()).unwrap();
+        assert_eq!(rtr.oneshot(req).await.unwrap().status(), StatusCode::OK);
+    }
+}
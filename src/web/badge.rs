@@ -0,0 +1,229 @@
+//! shields style badges of the status, latency or uptime of a check, to embed in the readmes of
+//! the services that dialer monitors.
+use super::{checks::KindQuery, escape_xml, Server, ServerError};
+use crate::{checker, config, uptime};
+use anyhow::Result;
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{named_params, Connection, OptionalExtension};
+use serde::Deserialize;
+use std::{fmt::Write, time::Duration};
+use tracing::instrument;
+
+const GREEN: &str = "#4c1";
+const YELLOW_GREEN: &str = "#97ca00";
+const YELLOW: &str = "#dfb317";
+const ORANGE: &str = "#fe7d37";
+const RED: &str = "#e05d44";
+const GREY: &str = "#9f9f9f";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Metric {
+    /// whether the last probe succeeded
+    Status,
+    /// the latency of the last probe
+    Latency,
+    /// the uptime over a window, as in the uptime report
+    Uptime,
+}
+
+impl Metric {
+    /// how long the badge may be cached, in seconds
+    fn max_age(self) -> u64 {
+        match self {
+            Metric::Status | Metric::Latency => 60,
+            Metric::Uptime => 300,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(super) struct BadgeQuery {
+    /// picks a check when checks of different kinds share the name
+    #[serde(flatten)]
+    kind: KindQuery,
+    /// the window of the uptime badge, e.g. "7d"
+    #[serde(with = "humantime_serde")]
+    last: Duration,
+    /// replaces the name of the check on the left of the badge
+    label: Option<String>,
+}
+
+impl Default for BadgeQuery {
+    fn default() -> Self {
+        Self {
+            kind: KindQuery::default(),
+            last: Duration::from_secs(30 * 24 * 60 * 60),
+            label: None,
+        }
+    }
+}
+
+/// renders a badge, e.g. `/badges/uptime/gateway.svg?last=7d`
+#[instrument(skip_all)]
+pub(super) async fn handle_badge(
+    State(Server { config, db, .. }): State<Server>,
+    Path((metric, name)): Path<(Metric, String)>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<BadgeQuery>,
+) -> Result<impl IntoResponse, ServerError> {
+    let name = name.strip_suffix(".svg").unwrap_or(&name).to_string();
+    let kind = query.kind.kind()?;
+    let label = query.label.clone().unwrap_or_else(|| name.clone());
+    let now = Utc::now();
+    let start =
+        uptime::ago(now, query.last).map_err(|err| ServerError::BadRequest(format!("{err:#}")))?;
+    let (message, color) = db
+        .with_conn(move |conn| badge(&conn, &config.uptime, metric, &name, kind, start, now))
+        .await?;
+    let headers = [
+        (header::CONTENT_TYPE, String::from("image/svg+xml")),
+        (
+            header::CACHE_CONTROL,
+            format!("max-age={}, s-maxage={0}", metric.max_age()),
+        ),
+    ];
+    Ok((headers, render(&label, &message, color)))
+}
+
+/// the message and color of a badge. checks which do not exist get a grey badge rather than an
+/// error, since a broken image is less useful in a readme.
+fn badge(
+    conn: &Connection,
+    config: &config::Uptime,
+    metric: Metric,
+    name: &str,
+    kind: Option<checker::Kind>,
+    start: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<(String, &'static str)> {
+    let kind = kind.map(checker::Kind::as_str);
+    if metric == Metric::Uptime {
+        let report = uptime::report(conn, config, start, now, false)?
+            .reports
+            .into_iter()
+            .find(|r| r.name == name && kind.is_none_or(|kind| kind == r.kind.as_str()));
+        return Ok(match report {
+            Some(report) if report.observed_secs > 0 => {
+                let color = match report.uptime {
+                    u if u >= 99.9 => GREEN,
+                    u if u >= 99.0 => YELLOW_GREEN,
+                    u if u >= 95.0 => YELLOW,
+                    _ => RED,
+                };
+                (format_uptime(report.uptime), color)
+            }
+            _ => (String::from("unknown"), GREY),
+        });
+    }
+
+    let last: Option<(Option<i64>, Option<String>)> = conn
+        .query_row(
+            "
+            SELECT r.us, r.err
+            FROM results r
+            JOIN checks c ON r.check_id = c.id
+            WHERE c.name = :name
+            AND (:kind IS NULL OR c.kind = :kind)
+            AND c.archived_at IS NULL
+            ORDER BY r.epoch_ms DESC
+            LIMIT 1
+            ",
+            named_params! { ":name": name, ":kind": kind },
+            |row| Ok((row.get("us")?, row.get("err")?)),
+        )
+        .optional()?;
+    Ok(match (metric, last) {
+        (_, None) => (String::from("unknown"), GREY),
+        (_, Some((_, Some(_)))) => (String::from("down"), RED),
+        (Metric::Latency, Some((Some(us), None))) => {
+            let ms = us as f64 / 1000.0;
+            let color = match ms {
+                ms if ms < 100.0 => GREEN,
+                ms if ms < 500.0 => YELLOW,
+                _ => ORANGE,
+            };
+            (format!("{ms:.1} ms"), color)
+        }
+        _ => (String::from("up"), GREEN),
+    })
+}
+
+/// formats an uptime with up to two decimals. it is truncated so that it never rounds up to 100%.
+fn format_uptime(uptime: f64) -> String {
+    let uptime = format!("{:.2}", (uptime * 100.0).floor() / 100.0);
+    let uptime = uptime.trim_end_matches('0').trim_end_matches('.');
+    format!("{uptime}%")
+}
+
+/// renders a flat badge with the label on grey and the message on the color
+fn render(label: &str, message: &str, color: &str) -> String {
+    // verdana at 11px is about 7px wide per character, plus padding on both sides
+    let width = |s: &str| s.chars().count() as u32 * 7 + 10;
+    let (lw, mw) = (width(label), width(message));
+    let w = lw + mw;
+    let (label, message) = (escape_xml(label), escape_xml(message));
+    let mut out = String::new();
+    write!(
+        out,
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{w}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{lw}" height="20" fill="#555"/><rect x="{lw}" width="{mw}" height="20" fill="{color}"/><rect width="{w}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{lx}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{lx}" y="14">{label}</text><text x="{mx}" y="15" fill="#010101" fill-opacity=".3">{message}</text><text x="{mx}" y="14">{message}</text></g></svg>"##,
+        lx = f64::from(lw) / 2.0,
+        mx = f64::from(lw) + f64::from(mw) / 2.0,
+    )
+    .unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn badge() {
+        let db = db::Db::in_memory().await.unwrap();
+        let conn = db.conn().unwrap();
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('gateway', 'ping'), ('gateway', 'http');
+            insert into results (check_id, epoch_ms, us, err) values
+                (1, 0, 1000, null),
+                (1, 60000, 250000, null),
+                (2, 0, null, 'refused'),
+                (2, 60000, null, 'refused');
+            ",
+        )
+        .unwrap();
+        let config = config::Uptime::default();
+        let now = DateTime::UNIX_EPOCH + Duration::from_secs(120);
+        let start = DateTime::UNIX_EPOCH;
+        let badge = |metric, kind| {
+            super::badge(&conn, &config, metric, "gateway", kind, start, now).unwrap()
+        };
+        let ping = Some(checker::Kind::Ping);
+        let http = Some(checker::Kind::Http);
+        assert_eq!(badge(Metric::Status, ping), (String::from("up"), GREEN));
+        assert_eq!(badge(Metric::Status, http), (String::from("down"), RED));
+        assert_eq!(
+            badge(Metric::Latency, ping),
+            (String::from("250.0 ms"), YELLOW)
+        );
+        assert_eq!(badge(Metric::Uptime, ping), (String::from("100%"), GREEN));
+        assert_eq!(badge(Metric::Uptime, http), (String::from("0%"), RED));
+        let missing = super::badge(&conn, &config, Metric::Status, "nas", None, start, now);
+        assert_eq!(missing.unwrap(), (String::from("unknown"), GREY));
+
+        assert_eq!(format_uptime(99.996), "99.99%");
+        assert_eq!(format_uptime(98.5), "98.5%");
+        assert_eq!(format_uptime(100.0), "100%");
+
+        let svg = render("a&b", "up", GREEN);
+        assert!(svg.contains(r#"width="55""#));
+        assert!(svg.contains("<title>a&amp;b: up</title>"));
+    }
+}
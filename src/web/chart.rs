@@ -0,0 +1,398 @@
+//! renders the results of a query as an svg chart so that graphs can be embedded where javascript
+//! is not available, such as wiki pages, chat and email. the chart takes the same parameters as
+//! `/query`, plus its size and theme.
+use super::{escape_xml, fetch_metrics, Metrics, MetricsQuery, Server, ServerError, STEPS};
+use axum::{extract::State, http::header, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{collections::BTreeSet, fmt::Write};
+use tracing::instrument;
+
+/// the colors of the series, which are cycled through. they are legible on both themes.
+const PALETTE: &[&str] = &[
+    "#0d6efd", "#fd7e14", "#20c997", "#d63384", "#6f42c1", "#ffc107", "#0dcaf0", "#198754",
+];
+
+/// the space around the plot for the axis labels, in pixels
+const MARGIN_LEFT: f64 = 64.0;
+const MARGIN_RIGHT: f64 = 16.0;
+const MARGIN_BOTTOM: f64 = 24.0;
+const LEGEND_ROW: f64 = 18.0;
+
+/// the rough width of a character of the labels, used to lay out the legend
+const CHAR_WIDTH: f64 = 7.0;
+
+/// the least space between the labels of the time axis, in pixels
+const TICK_SPACING: f64 = 100.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Theme {
+    #[default]
+    Light,
+    Dark,
+}
+
+impl Theme {
+    /// the background, text, grid and error colors
+    fn colors(self) -> (&'static str, &'static str, &'static str, &'static str) {
+        match self {
+            Theme::Light => ("#ffffff", "#212529", "#dee2e6", "#dc3545"),
+            Theme::Dark => ("#212529", "#dee2e6", "#495057", "#ea868f"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(super) struct ChartOptions {
+    /// the size of the image in pixels
+    width: u32,
+    height: u32,
+    theme: Theme,
+}
+
+impl Default for ChartOptions {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 300,
+            theme: Theme::default(),
+        }
+    }
+}
+
+impl ChartOptions {
+    fn validate(&self) -> Result<(), ServerError> {
+        if !(200..=4000).contains(&self.width) || !(100..=2000).contains(&self.height) {
+            return Err(ServerError::BadRequest(String::from(
+                "width must be between 200 and 4000 and height between 100 and 2000",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// renders a chart of the latency of each check. buckets with errors are shaded.
+#[instrument(skip_all)]
+pub(super) async fn handle_chart(
+    State(Server { db, .. }): State<Server>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<MetricsQuery>,
+    axum_extra::extract::Query(options): axum_extra::extract::Query<ChartOptions>,
+) -> Result<impl IntoResponse, ServerError> {
+    options.validate()?;
+    let metrics = fetch_metrics(&db, query).await?;
+    let headers = [
+        (header::CONTENT_TYPE, "image/svg+xml"),
+        (header::CACHE_CONTROL, "no-cache"),
+    ];
+    Ok((headers, render(&metrics, &options)))
+}
+
+/// a line of the chart. each check has a line for its average latency, or one per percentile when
+/// percentiles were requested.
+struct Line<'a> {
+    label: String,
+    color: &'static str,
+    points: Vec<(DateTime<Utc>, Option<f64>)>,
+    series: &'a super::Series,
+}
+
+fn render(metrics: &Metrics, options: &ChartOptions) -> String {
+    let (background, foreground, grid, error) = options.theme.colors();
+    let width = f64::from(options.width);
+    let height = f64::from(options.height);
+
+    let mut lines = vec![];
+    for series in &metrics.series {
+        let percentiles: BTreeSet<&String> = series
+            .values
+            .iter()
+            .flat_map(|v| v.percentiles.keys())
+            .collect();
+        let label = format!("{} ({})", series.name, series.kind);
+        if percentiles.is_empty() {
+            lines.push(Line {
+                label: label.clone(),
+                color: "",
+                points: series.values.iter().map(|v| (v.ts, v.avg)).collect(),
+                series,
+            });
+        }
+        for p in percentiles {
+            lines.push(Line {
+                label: format!("{label} {p}"),
+                color: "",
+                points: series
+                    .values
+                    .iter()
+                    .map(|v| (v.ts, v.percentiles.get(p).copied()))
+                    .collect(),
+                series,
+            });
+        }
+    }
+    for (idx, line) in lines.iter_mut().enumerate() {
+        line.color = PALETTE[idx % PALETTE.len()];
+    }
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+        w = options.width,
+        h = options.height
+    )
+    .unwrap();
+    writeln!(
+        out,
+        r#"<rect width="100%" height="100%" fill="{background}"/>"#
+    )
+    .unwrap();
+
+    // the legend wraps onto as many rows as it needs above the plot
+    let mut x = MARGIN_LEFT;
+    let mut row = 0.0;
+    for line in &lines {
+        let entry = 20.0 + line.label.chars().count() as f64 * CHAR_WIDTH + 16.0;
+        if x > MARGIN_LEFT && x + entry > width - MARGIN_RIGHT {
+            x = MARGIN_LEFT;
+            row += 1.0;
+        }
+        let y = 6.0 + row * LEGEND_ROW;
+        writeln!(
+            out,
+            r#"<rect x="{x}" y="{y}" width="12" height="12" fill="{}"/><text x="{}" y="{}" fill="{foreground}">{}</text>"#,
+            line.color,
+            x + 16.0,
+            y + 10.0,
+            escape_xml(&line.label)
+        )
+        .unwrap();
+        x += entry;
+    }
+    let top = 12.0 + (row + 1.0) * LEGEND_ROW;
+
+    let max = lines
+        .iter()
+        .flat_map(|line| line.points.iter().filter_map(|(_, v)| *v))
+        .fold(0.0, f64::max);
+    let step = nice(max.max(f64::EPSILON) / 4.0);
+    let plot = Plot {
+        left: MARGIN_LEFT,
+        right: (width - MARGIN_RIGHT).max(MARGIN_LEFT + 1.0),
+        top,
+        bottom: (height - MARGIN_BOTTOM).max(top + 1.0),
+        start: metrics.meta.start,
+        end: metrics.meta.end,
+        max: (max / step).ceil().max(1.0) * step,
+    };
+
+    // the latency axis
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    let mut tick = 0.0;
+    while tick <= plot.max + step / 2.0 {
+        let y = plot.y(tick);
+        writeln!(
+            out,
+            r#"<line x1="{}" x2="{}" y1="{y:.1}" y2="{y:.1}" stroke="{grid}"/><text x="{}" y="{:.1}" fill="{foreground}" text-anchor="end">{tick:.decimals$} ms</text>"#,
+            plot.left,
+            plot.right,
+            plot.left - 6.0,
+            y + 4.0
+        )
+        .unwrap();
+        tick += step;
+    }
+
+    // the time axis, with ticks aligned to a step that leaves room for the labels
+    let window = (plot.end - plot.start).num_seconds().max(1) as u64;
+    let max_ticks = ((plot.right - plot.left) / TICK_SPACING).max(1.0) as u64;
+    let tick_secs = STEPS
+        .iter()
+        .copied()
+        .find(|step| window / step <= max_ticks)
+        .unwrap_or_else(|| {
+            let day = 24 * 60 * 60;
+            (window / max_ticks).div_ceil(day) * day
+        });
+    let format = match tick_secs {
+        s if s >= 24 * 60 * 60 => "%m-%d",
+        s if s >= 60 && window > 24 * 60 * 60 => "%m-%d %H:%M",
+        s if s >= 60 => "%H:%M",
+        _ => "%H:%M:%S",
+    };
+    let first = plot.start.timestamp().div_euclid(tick_secs as i64) + 1;
+    let mut tick = first * tick_secs as i64;
+    while tick < plot.end.timestamp() {
+        let ts = DateTime::from_timestamp(tick, 0).unwrap_or_default();
+        let x = plot.x(ts);
+        writeln!(
+            out,
+            r#"<line x1="{x:.1}" x2="{x:.1}" y1="{}" y2="{}" stroke="{grid}"/><text x="{x:.1}" y="{}" fill="{foreground}" text-anchor="middle">{}</text>"#,
+            plot.top,
+            plot.bottom,
+            plot.bottom + 16.0,
+            ts.format(format)
+        )
+        .unwrap();
+        tick += tick_secs as i64;
+    }
+
+    // buckets with errors are shaded by the share of probes that failed
+    let bucket = chrono::Duration::seconds(metrics.meta.res as i64);
+    let mut shaded = BTreeSet::new();
+    for line in &lines {
+        if !shaded.insert((&line.series.name, line.series.kind.as_str())) {
+            continue;
+        }
+        for value in line.series.values.iter().filter(|v| v.errs > 0) {
+            let x = plot.x(value.ts);
+            let w = (plot.x(value.ts + bucket) - x).max(1.0);
+            let opacity = 0.1 + 0.4 * value.errs as f64 / value.count.max(1) as f64;
+            writeln!(
+                out,
+                r#"<rect x="{x:.1}" y="{}" width="{w:.1}" height="{:.1}" fill="{error}" fill-opacity="{opacity:.2}"/>"#,
+                plot.top,
+                plot.bottom - plot.top
+            )
+            .unwrap();
+        }
+    }
+
+    for line in &lines {
+        let mut path = String::new();
+        let mut gap = true;
+        for (ts, value) in &line.points {
+            let Some(value) = value else {
+                gap = true;
+                continue;
+            };
+            let cmd = if gap { 'M' } else { 'L' };
+            write!(path, "{cmd}{:.1} {:.1} ", plot.x(*ts), plot.y(*value)).unwrap();
+            gap = false;
+        }
+        writeln!(
+            out,
+            r#"<path d="{}" fill="none" stroke="{}" stroke-width="1.5" stroke-linejoin="round" stroke-linecap="round"/>"#,
+            path.trim_end(),
+            line.color
+        )
+        .unwrap();
+    }
+
+    if lines.is_empty() {
+        writeln!(
+            out,
+            r#"<text x="{:.1}" y="{:.1}" fill="{foreground}" text-anchor="middle">no data</text>"#,
+            (plot.left + plot.right) / 2.0,
+            (plot.top + plot.bottom) / 2.0
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        r#"<line x1="{l}" x2="{l}" y1="{t}" y2="{b}" stroke="{foreground}"/><line x1="{l}" x2="{r}" y1="{b}" y2="{b}" stroke="{foreground}"/>"#,
+        l = plot.left,
+        r = plot.right,
+        t = plot.top,
+        b = plot.bottom
+    )
+    .unwrap();
+    out.push_str("</svg>\n");
+    out
+}
+
+/// maps times and latencies to coordinates
+struct Plot {
+    left: f64,
+    right: f64,
+    top: f64,
+    bottom: f64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// the latency at the top of the plot
+    max: f64,
+}
+
+impl Plot {
+    fn x(&self, ts: DateTime<Utc>) -> f64 {
+        let span = (self.end - self.start).num_milliseconds().max(1) as f64;
+        let offset = (ts - self.start).num_milliseconds() as f64;
+        (self.left + offset / span * (self.right - self.left)).clamp(self.left, self.right)
+    }
+
+    fn y(&self, value: f64) -> f64 {
+        self.bottom - value / self.max * (self.bottom - self.top)
+    }
+}
+
+/// rounds up to 1, 2 or 5 times a power of ten
+fn nice(x: f64) -> f64 {
+    let magnitude = 10f64.powf(x.log10().floor());
+    let fraction = x / magnitude;
+    let nice = match fraction {
+        f if f <= 1.0 => 1.0,
+        f if f <= 2.0 => 2.0,
+        f if f <= 5.0 => 5.0,
+        _ => 10.0,
+    };
+    nice * magnitude
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checker, web::TimeValue};
+
+    #[test]
+    fn render() {
+        let start = DateTime::UNIX_EPOCH;
+        let mut metrics = Metrics::default();
+        metrics.meta.res = 60;
+        metrics.meta.start = start;
+        metrics.meta.end = start + chrono::Duration::minutes(4);
+        let value = |min: i64, avg: Option<f64>, errs: usize| TimeValue {
+            avg,
+            count: 2,
+            errs,
+            ..TimeValue::empty(start + chrono::Duration::minutes(min))
+        };
+        let series = metrics.get_mut("<lab>", checker::Kind::Ping);
+        series.values = vec![
+            value(0, Some(10.0), 0),
+            value(1, Some(30.0), 1),
+            value(2, None, 2),
+            value(3, Some(20.0), 0),
+        ];
+
+        let svg = super::render(&metrics, &ChartOptions::default());
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="800""#));
+        assert!(svg.contains("&lt;lab&gt; (ping)"));
+        // the axis goes up in steps of 10ms to fit 30ms
+        assert!(svg.contains(">30 ms</text>"));
+        assert!(!svg.contains(">40 ms</text>"));
+        // the bucket without a latency breaks the line, and both buckets with errors are shaded
+        let path = svg.lines().find(|l| l.starts_with("<path")).unwrap();
+        assert_eq!(path.matches('M').count(), 2);
+        assert_eq!(svg.matches("fill-opacity").count(), 2);
+
+        let dark = ChartOptions {
+            theme: Theme::Dark,
+            ..Default::default()
+        };
+        assert!(super::render(&Metrics::default(), &dark).contains(">no data</text>"));
+        let small = ChartOptions {
+            width: 10,
+            ..Default::default()
+        };
+        assert!(small.validate().is_err());
+    }
+
+    #[test]
+    fn nice() {
+        assert_eq!(super::nice(0.3), 0.5);
+        assert_eq!(super::nice(7.0), 10.0);
+        assert_eq!(super::nice(12.0), 20.0);
+    }
+}
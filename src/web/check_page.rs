@@ -0,0 +1,404 @@
+//! the page of a single check, with its config, its current state, its latency over several
+//! windows, its latest results and a histogram of its errors. clients which accept json get the
+//! same details as json.
+use super::{
+    checks::{rejected, KindQuery},
+    dashboard::summarize,
+    tmpl, HtmlTemplate, Server, ServerError,
+};
+use crate::{
+    checker::Summary,
+    db::{self, unquote},
+    percentile::Samples,
+};
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{named_params, Connection};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use tracing::instrument;
+
+/// the windows which the latency is summarized over
+const WINDOWS: &[(&str, Duration)] = &[
+    ("1h", Duration::from_secs(60 * 60)),
+    ("24h", Duration::from_secs(24 * 60 * 60)),
+    ("7d", Duration::from_secs(7 * 24 * 60 * 60)),
+    ("30d", Duration::from_secs(30 * 24 * 60 * 60)),
+];
+
+/// the number of results shown unless a limit is requested
+const RESULTS: usize = 50;
+
+/// the most results which may be requested
+const MAX_RESULTS: usize = 1000;
+
+/// the classes of errors, each with the phrases which identify it. the first class with a matching
+/// phrase wins, and errors which match none are counted as "other".
+const ERROR_CLASSES: &[(&str, &[&str])] = &[
+    ("timeout", &["timeout", "timed out", "deadline has elapsed"]),
+    (
+        "dns",
+        &[
+            "lookup host",
+            "dns error",
+            "failed to lookup",
+            "no ip for host",
+            "name or service not known",
+        ],
+    ),
+    ("refused", &["connection refused"]),
+    ("reset", &["connection reset", "broken pipe"]),
+    ("tls", &["certificate", "tls", "ssl"]),
+    ("unreachable", &["unreachable", "no route to host"]),
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(super) struct CheckQuery {
+    /// picks a check when checks of different kinds share the name
+    #[serde(flatten)]
+    kind: KindQuery,
+    /// the number of results shown
+    limit: usize,
+}
+
+impl Default for CheckQuery {
+    fn default() -> Self {
+        Self {
+            kind: KindQuery::default(),
+            limit: RESULTS,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Detail {
+    #[serde(flatten)]
+    pub check: Summary,
+    pub current: Current,
+    pub windows: Vec<Window>,
+    /// the errors over the longest window by class, most frequent first
+    pub errors: Vec<ErrorClass>,
+    /// the latest results, newest first
+    pub results: Vec<Probe>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Current {
+    /// whether the last probe succeeded. none when the check has not been probed yet.
+    pub up: Option<bool>,
+    /// when the check last went up or down within the longest window, or was first probed in it
+    pub since: Option<DateTime<Utc>>,
+    pub last: Option<Probe>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Probe {
+    pub ts: DateTime<Utc>,
+    /// the latency in milliseconds
+    pub latency: Option<f64>,
+    /// the full error, including its causes
+    pub err: Option<String>,
+}
+
+/// the latencies of the successful probes in a window, in milliseconds
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct Window {
+    pub last: &'static str,
+    pub count: u64,
+    pub errors: u64,
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
+    pub p50: Option<f64>,
+    pub p95: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ErrorClass {
+    pub class: &'static str,
+    pub count: u64,
+    pub last_ts: DateTime<Utc>,
+    /// the latest error of the class, on one line
+    pub last: String,
+}
+
+impl Detail {
+    pub fn status(&self) -> &'static str {
+        match (self.check.paused, self.current.up) {
+            (true, _) => "paused",
+            (_, Some(true)) => "up",
+            (_, Some(false)) => "down",
+            (_, None) => "pending",
+        }
+    }
+
+    /// the bootstrap color of the status
+    pub fn badge(&self) -> &'static str {
+        match (self.check.paused, self.current.up) {
+            (true, _) | (_, None) => "secondary",
+            (_, Some(true)) => "success",
+            (_, Some(false)) => "danger",
+        }
+    }
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_check(
+    State(Server { checker, db, .. }): State<Server>,
+    Path(name): Path<String>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<CheckQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ServerError> {
+    if query.limit > MAX_RESULTS {
+        return Err(ServerError::BadRequest(format!(
+            "limit must be at most {MAX_RESULTS}"
+        )));
+    }
+    let check = checker.get(&name, query.kind.kind()?).map_err(rejected)?;
+    let detail = db
+        .with_conn(move |conn| detail(&conn, check.clone(), query.limit, Utc::now()))
+        .await?;
+    if wants_json(&headers) {
+        return Ok(Json(detail).into_response());
+    }
+    Ok(HtmlTemplate(tmpl::Check { detail }).into_response())
+}
+
+/// whether the client asked for json rather than html, e.g. with `Accept: application/json`
+fn wants_json(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+/// the details of a check as of now, with up to limit of its latest results
+fn detail(conn: &Connection, check: Summary, limit: usize, now: DateTime<Utc>) -> Result<Detail> {
+    let kind = check.definition.kind().as_str();
+    let Some(id) = db::find_check(conn, &check.name, kind)? else {
+        bail!("the {kind} check '{}' is not in the db", check.name);
+    };
+
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT epoch_ms, us, err FROM results
+        WHERE check_id = :id
+        ORDER BY epoch_ms DESC
+        LIMIT :limit
+        ",
+    )?;
+    let mut results = stmt
+        .query_map(named_params! { ":id": id, ":limit": limit.max(1) }, |row| {
+            let ts: i64 = row.get("epoch_ms")?;
+            let us: Option<i64> = row.get("us")?;
+            let err: Option<String> = row.get("err")?;
+            Ok(Probe {
+                ts: DateTime::from_timestamp_millis(ts).unwrap_or_default(),
+                latency: us.map(|us| us as f64 / 1000.0),
+                err: err.as_deref().map(unquote),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut current = Current::default();
+    if let Some(last) = results.first() {
+        current.up = Some(last.err.is_none());
+        current.last = Some(last.clone());
+    }
+    results.truncate(limit);
+
+    let starts: Vec<i64> = WINDOWS
+        .iter()
+        .map(|(_, window)| (now - *window).timestamp_millis())
+        .collect();
+    let mut samples: Vec<Samples> = WINDOWS.iter().map(|_| Samples::default()).collect();
+    let mut sums = vec![0.0; WINDOWS.len()];
+    let mut windows: Vec<Window> = WINDOWS
+        .iter()
+        .map(|(last, _)| Window {
+            last,
+            ..Default::default()
+        })
+        .collect();
+    let mut classes: HashMap<&'static str, ErrorClass> = HashMap::new();
+    // the outcome of the latest result so far and when the check last changed to it
+    let mut changed: Option<(bool, i64)> = None;
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT epoch_ms, us, err FROM results
+        WHERE check_id = :id AND epoch_ms >= :start
+        ORDER BY epoch_ms
+        ",
+    )?;
+    let mut rows = stmt.query(named_params! { ":id": id, ":start": starts.iter().min() })?;
+    while let Some(row) = rows.next()? {
+        let ts: i64 = row.get("epoch_ms")?;
+        let us: Option<i64> = row.get("us")?;
+        let err: Option<String> = row.get("err")?;
+        let up = err.is_none();
+        if changed.is_none_or(|(was, _)| was != up) {
+            changed = Some((up, ts));
+        }
+        for (idx, window) in windows.iter_mut().enumerate() {
+            if ts < starts[idx] {
+                continue;
+            }
+            window.count += 1;
+            match (us, &err) {
+                (Some(us), None) => {
+                    let ms = us as f64 / 1000.0;
+                    samples[idx].add(ms);
+                    sums[idx] += ms;
+                    window.min = Some(window.min.map_or(ms, |min| min.min(ms)));
+                    window.max = Some(window.max.map_or(ms, |max| max.max(ms)));
+                }
+                _ => window.errors += 1,
+            }
+        }
+        if let Some(err) = err {
+            let last = summarize(&err);
+            let class = classify(&last);
+            let last_ts = DateTime::from_timestamp_millis(ts).unwrap_or_default();
+            let entry = classes.entry(class).or_insert_with(|| ErrorClass {
+                class,
+                count: 0,
+                last_ts,
+                last: String::new(),
+            });
+            entry.count += 1;
+            entry.last_ts = last_ts;
+            entry.last = last;
+        }
+    }
+    for ((window, samples), sum) in windows.iter_mut().zip(&mut samples).zip(sums) {
+        let ok = window.count - window.errors;
+        window.avg = (ok > 0).then(|| sum / ok as f64);
+        window.p50 = samples.quantile(0.5);
+        window.p95 = samples.quantile(0.95);
+        window.p99 = samples.quantile(0.99);
+    }
+    if let (Some(up), Some(last)) = (current.up, &current.last) {
+        // when nothing in the longest window has the outcome of the latest result, that result is
+        // older than the window
+        current.since = match changed {
+            Some((was, ts)) if was == up => DateTime::from_timestamp_millis(ts),
+            _ => Some(last.ts),
+        };
+    }
+    let mut errors: Vec<ErrorClass> = classes.into_values().collect();
+    errors.sort_by(|a, b| b.count.cmp(&a.count).then(a.class.cmp(b.class)));
+
+    Ok(Detail {
+        check,
+        current,
+        windows,
+        errors,
+        results,
+    })
+}
+
+/// the class of an error which has been summarized onto one line
+fn classify(err: &str) -> &'static str {
+    let err = err.to_lowercase();
+    ERROR_CLASSES
+        .iter()
+        .find(|(_, phrases)| phrases.iter().any(|phrase| err.contains(phrase)))
+        .map_or("other", |(class, _)| class)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checker::Definition, checker::Source, config};
+
+    #[tokio::test]
+    async fn detail() {
+        let db = db::Db::in_memory().await.unwrap();
+        let conn = db.conn().unwrap();
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('gateway', 'ping');
+            insert into results (check_id, epoch_ms, us, err) values
+                (1, 0, 1000, null),
+                (1, 60000, null, '\"timeout\"'),
+                (1, 3540000, null, '\"ping failed\\n\\nCaused by:\\n    Network is unreachable\\n\\nStack backtrace:\\n   0: main\"'),
+                (1, 3560000, 3000, null),
+                (1, 3580000, 2000, null);
+            ",
+        )
+        .unwrap();
+        let check = Summary {
+            name: String::from("gateway"),
+            source: Source::Config,
+            paused: false,
+            definition: Definition::Ping(config::Ping {
+                host: String::from("192.168.0.1"),
+                previous_name: None,
+                tags: Default::default(),
+            }),
+        };
+        let now = DateTime::UNIX_EPOCH + Duration::from_secs(60 * 60 + 1);
+        let detail = super::detail(&conn, check, 2, now).unwrap();
+        assert_eq!(detail.status(), "up");
+        let ts = |ms| DateTime::from_timestamp_millis(ms).unwrap();
+        assert_eq!(detail.current.since, Some(ts(3560000)));
+        assert_eq!(
+            detail.results.iter().map(|r| r.ts).collect::<Vec<_>>(),
+            vec![ts(3580000), ts(3560000)]
+        );
+
+        // the first result is outside of the hour
+        let hour = &detail.windows[0];
+        assert_eq!((hour.count, hour.errors), (4, 2));
+        assert_eq!(
+            (hour.min, hour.avg, hour.max),
+            (Some(2.0), Some(2.5), Some(3.0))
+        );
+        assert_eq!(hour.p50, Some(2.5));
+        assert_eq!(detail.windows[1].count, 5);
+
+        assert_eq!(
+            detail.errors,
+            vec![
+                ErrorClass {
+                    class: "timeout",
+                    count: 1,
+                    last_ts: ts(60000),
+                    last: String::from("timeout"),
+                },
+                ErrorClass {
+                    class: "unreachable",
+                    count: 1,
+                    last_ts: ts(3540000),
+                    last: String::from("ping failed: Network is unreachable"),
+                },
+            ]
+        );
+        let detail = super::detail(&conn, detail.check, 5, now).unwrap();
+        assert_eq!(
+            detail.results[2].err.as_deref(),
+            Some("ping failed\n\nCaused by:\n    Network is unreachable")
+        );
+        assert_eq!(
+            classify("request: error sending request: Connection refused"),
+            "refused"
+        );
+        assert_eq!(classify("http 500"), "other");
+
+        // a check is only followed back as far as the longest window
+        let now = DateTime::UNIX_EPOCH + Duration::from_secs(30 * 24 * 60 * 60 + 3550);
+        let detail = super::detail(&conn, detail.check, 5, now).unwrap();
+        assert_eq!(detail.current.since, Some(ts(3560000)));
+        let now = DateTime::UNIX_EPOCH + Duration::from_secs(30 * 24 * 60 * 60 + 3570);
+        let detail = super::detail(&conn, detail.check, 5, now).unwrap();
+        assert_eq!(detail.current.since, Some(ts(3580000)));
+    }
+}
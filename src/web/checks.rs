@@ -0,0 +1,123 @@
+//! an api to manage the checks at runtime. checks created through it are stored in the db and are
+//! probed alongside the checks in the config file, which it can only pause and resume.
+//!
+//! checks are addressed by name, with `?kind=` to pick one when checks of different kinds share
+//! the name.
+use super::{Server, ServerError};
+use crate::checker::{Definition, Kind, ManageError, Summary};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use tracing::instrument;
+
+/// the body of a create or update, e.g. `{"name": "nas", "kind": "http", "url": "http://nas/"}`
+#[derive(Debug, Deserialize)]
+pub(super) struct CheckRequest {
+    name: String,
+    #[serde(flatten)]
+    definition: Definition,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(super) struct KindQuery {
+    kind: Option<String>,
+}
+
+impl KindQuery {
+    pub(super) fn kind(&self) -> Result<Option<Kind>, ServerError> {
+        self.kind
+            .as_deref()
+            .map(Kind::try_from)
+            .transpose()
+            .map_err(|err| ServerError::BadRequest(err.to_string()))
+    }
+}
+
+/// maps the reason that a change was rejected to its status code
+pub(super) fn rejected(err: ManageError) -> ServerError {
+    match err {
+        ManageError::NotFound(_) => ServerError::NotFound,
+        ManageError::Conflict(msg) => ServerError::Conflict(msg),
+        ManageError::Invalid(msg) => ServerError::BadRequest(msg),
+        ManageError::Other(err) => ServerError::Anyhow(err),
+    }
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_list(
+    State(Server { checker, .. }): State<Server>,
+) -> Json<Vec<Summary>> {
+    Json(checker.list())
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_create(
+    State(Server { checker, .. }): State<Server>,
+    Json(req): Json<CheckRequest>,
+) -> Result<impl IntoResponse, ServerError> {
+    let summary = checker
+        .create(&req.name, req.definition)
+        .await
+        .map_err(rejected)?;
+    Ok((StatusCode::CREATED, Json(summary)))
+}
+
+/// replaces the config of a check, which is renamed when the name in the body differs
+#[instrument(skip_all)]
+pub(super) async fn handle_update(
+    State(Server { checker, .. }): State<Server>,
+    Path(name): Path<String>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<KindQuery>,
+    Json(req): Json<CheckRequest>,
+) -> Result<Json<Summary>, ServerError> {
+    let summary = checker
+        .update(&name, query.kind()?, &req.name, req.definition)
+        .await
+        .map_err(rejected)?;
+    Ok(Json(summary))
+}
+
+/// archives a check, which keeps its results
+#[instrument(skip_all)]
+pub(super) async fn handle_delete(
+    State(Server { checker, .. }): State<Server>,
+    Path(name): Path<String>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<KindQuery>,
+) -> Result<StatusCode, ServerError> {
+    checker
+        .delete(&name, query.kind()?)
+        .await
+        .map_err(rejected)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_pause(
+    State(Server { checker, .. }): State<Server>,
+    Path(name): Path<String>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<KindQuery>,
+) -> Result<Json<Summary>, ServerError> {
+    let summary = checker
+        .pause(&name, query.kind()?)
+        .await
+        .map_err(rejected)?;
+    Ok(Json(summary))
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_resume(
+    State(Server { checker, .. }): State<Server>,
+    Path(name): Path<String>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<KindQuery>,
+) -> Result<Json<Summary>, ServerError> {
+    let summary = checker
+        .resume(&name, query.kind()?)
+        .await
+        .map_err(rejected)?;
+    Ok(Json(summary))
+}
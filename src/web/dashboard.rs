@@ -0,0 +1,393 @@
+//! the dashboard. the index page is rendered in full, and the parts which change are also served
+//! as fragments so that htmx can reload them when the range changes or on a timer.
+use super::{
+    describe, query_metrics, resolution, tmpl, CheckFilter, HtmlTemplate, MetricsQuery, Server,
+    ServerError, TimeValue,
+};
+use crate::{checker, config, db::unquote, incident, uptime};
+use anyhow::{Context, Result};
+use axum::{extract::State, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use rusqlite::{named_params, Connection};
+use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+use tracing::instrument;
+
+/// the ranges offered by the picker, as understood by humantime
+pub const RANGES: &[(&str, &str)] = &[
+    ("1h", "1 hour"),
+    ("6h", "6 hours"),
+    ("1day", "24 hours"),
+    ("7days", "7 days"),
+    ("30days", "30 days"),
+];
+
+/// the number of buckets in each sparkline
+const SPARKLINE_POINTS: u64 = 60;
+
+/// the number of recent errors shown for each check
+const RECENT_ERRORS: u64 = 3;
+
+/// the number of incidents shown on the dashboard
+const INCIDENTS: usize = 10;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(super) struct Range {
+    /// how far back the dashboard looks, e.g. "6h"
+    #[serde(with = "humantime_serde")]
+    last: Duration,
+}
+
+impl Default for Range {
+    fn default() -> Self {
+        Self {
+            last: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl Range {
+    fn name(&self) -> String {
+        humantime::format_duration(self.last).to_string()
+    }
+}
+
+/// the state of a check over the range
+#[derive(Debug)]
+pub struct Card {
+    pub name: String,
+    pub kind: checker::Kind,
+    /// whether the last probe succeeded. none when the check has not been probed yet.
+    pub up: Option<bool>,
+    pub last_ts: Option<DateTime<Utc>>,
+    /// the latency of the last probe in milliseconds
+    pub last_latency: Option<f64>,
+    /// the percentage of the range that the check was up, as in the uptime report
+    pub uptime: Option<f64>,
+    pub sparkline: Sparkline,
+    /// the most recent errors in the range, newest first
+    pub errors: Vec<RecentError>,
+}
+
+/// the average latency over the range, scaled to a 100 by 20 viewbox
+#[derive(Debug, Default, PartialEq)]
+pub struct Sparkline {
+    /// the svg path of the line. buckets without latencies break the line.
+    pub path: String,
+    /// the x coordinates of the buckets with errors
+    pub errors: Vec<f64>,
+}
+
+impl Card {
+    pub fn status(&self) -> &'static str {
+        match self.up {
+            Some(true) => "up",
+            Some(false) => "down",
+            None => "pending",
+        }
+    }
+
+    /// the bootstrap color of the status
+    pub fn badge(&self) -> &'static str {
+        match self.up {
+            Some(true) => "success",
+            Some(false) => "danger",
+            None => "secondary",
+        }
+    }
+
+    pub fn latency(&self) -> String {
+        match self.last_latency {
+            Some(latency) => format!("{latency:.1} ms"),
+            None => String::from("no latency"),
+        }
+    }
+
+    pub fn uptime_percent(&self) -> String {
+        match self.uptime {
+            Some(uptime) => format!("{uptime:.2}%"),
+            None => String::from("-"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RecentError {
+    pub ts: DateTime<Utc>,
+    pub err: String,
+}
+
+impl Sparkline {
+    const WIDTH: f64 = 100.0;
+    const HEIGHT: f64 = 20.0;
+
+    fn new(values: &[TimeValue], start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        let span = (end - start).num_milliseconds().max(1) as f64;
+        let max = values
+            .iter()
+            .filter_map(|v| v.avg)
+            .fold(0.0, f64::max)
+            .max(f64::EPSILON);
+        let mut sparkline = Self::default();
+        let mut gap = true;
+        for value in values {
+            let x = (value.ts - start).num_milliseconds().max(0) as f64 / span * Self::WIDTH;
+            if value.errs > 0 {
+                sparkline.errors.push((x * 10.0).round() / 10.0);
+            }
+            let Some(avg) = value.avg else {
+                gap = true;
+                continue;
+            };
+            // leaves a pixel at the top and bottom so that the stroke is not clipped
+            let y = Self::HEIGHT - 1.0 - avg / max * (Self::HEIGHT - 2.0);
+            let cmd = if gap { 'M' } else { 'L' };
+            if !sparkline.path.is_empty() {
+                sparkline.path.push(' ');
+            }
+            sparkline.path += &format!("{cmd}{x:.1} {y:.1}");
+            gap = false;
+        }
+        sparkline
+    }
+}
+
+/// builds a card for each active check
+pub fn cards(
+    conn: &Connection,
+    config: &config::Uptime,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<Card>> {
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT c.name, c.kind, r.epoch_ms, r.us, r.err
+        FROM checks c
+        LEFT JOIN results r ON r.id = (
+            SELECT id FROM results WHERE check_id = c.id ORDER BY epoch_ms DESC LIMIT 1
+        )
+        WHERE c.archived_at IS NULL
+        ORDER BY c.name, c.kind
+        ",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let name: String = row.get("name")?;
+        let kind: String = row.get("kind")?;
+        let epoch_ms: Option<i64> = row.get("epoch_ms")?;
+        let us: Option<i64> = row.get("us")?;
+        let err: Option<String> = row.get("err")?;
+        Ok((name, kind, epoch_ms, us, err))
+    })?;
+    let mut cards = vec![];
+    for row in rows {
+        let (name, kind, epoch_ms, us, err) = row?;
+        let last_ts = epoch_ms.and_then(DateTime::from_timestamp_millis);
+        cards.push(Card {
+            name,
+            kind: checker::Kind::try_from(kind.as_str())?,
+            up: last_ts.map(|_| err.is_none()),
+            last_ts,
+            last_latency: us.map(|us| us as f64 / 1000.0),
+            uptime: None,
+            sparkline: Sparkline::default(),
+            errors: vec![],
+        });
+    }
+    let index: HashMap<(String, &str), usize> = cards
+        .iter()
+        .enumerate()
+        .map(|(idx, card)| ((card.name.clone(), card.kind.as_str()), idx))
+        .collect();
+    let card =
+        |name: &str, kind: checker::Kind| index.get(&(name.to_string(), kind.as_str())).copied();
+
+    for report in uptime::report(conn, config, start, end, false)?.reports {
+        if let Some(idx) = card(&report.name, report.kind) {
+            cards[idx].uptime = Some(report.uptime);
+        }
+    }
+
+    let query = MetricsQuery::default();
+    let filter = CheckFilter::new(&query).map_err(describe)?;
+    let window = (end - start).to_std()?;
+    let step = resolution(window, None, Some(SPARKLINE_POINTS)).map_err(describe)?;
+    let metrics = query_metrics(conn, &query, &filter, start, end, step, &[])?;
+    for series in metrics.series {
+        if let Some(idx) = card(&series.name, series.kind) {
+            cards[idx].sparkline = Sparkline::new(&series.values, start, end);
+        }
+    }
+
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT c.name, c.kind, r.epoch_ms, r.err
+        FROM (
+            SELECT
+                check_id,
+                epoch_ms,
+                err,
+                ROW_NUMBER() OVER (PARTITION BY check_id ORDER BY epoch_ms DESC) AS n
+            FROM results
+            WHERE err IS NOT NULL
+            AND epoch_ms >= :start_time
+            AND epoch_ms <= :end_time
+        ) r
+        JOIN checks c ON c.id = r.check_id
+        WHERE r.n <= :limit
+        ORDER BY r.epoch_ms DESC
+        ",
+    )?;
+    let params = named_params! {
+        ":start_time": start.timestamp_millis(),
+        ":end_time": end.timestamp_millis(),
+        ":limit": RECENT_ERRORS,
+    };
+    let mut rows = stmt.query(params)?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get("name")?;
+        let kind: String = row.get("kind")?;
+        let kind = checker::Kind::try_from(kind.as_str())?;
+        let epoch_ms: i64 = row.get("epoch_ms")?;
+        let err: String = row.get("err")?;
+        if let Some(idx) = card(&name, kind) {
+            cards[idx].errors.push(RecentError {
+                ts: DateTime::from_timestamp_millis(epoch_ms)
+                    .context("could not convert epoch to timestamp")?,
+                err: summarize(&err),
+            });
+        }
+    }
+    Ok(cards)
+}
+
+/// collapses an error onto one line like anyhow's alternate format. errors are stored debug
+/// formatted, with their causes on separate lines and possibly a backtrace.
+pub(super) fn summarize(err: &str) -> String {
+    let err = unquote(err);
+    let causes: Vec<&str> = err
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && *line != "Caused by:")
+        // causes are numbered when there is more than one
+        .map(|line| match line.split_once(": ") {
+            Some((n, cause)) if n.parse::<u32>().is_ok() => cause,
+            _ => line,
+        })
+        .collect();
+    causes.join(": ")
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_index(
+    State(Server { config, db, .. }): State<Server>,
+    axum_extra::extract::Query(range): axum_extra::extract::Query<Range>,
+) -> Result<impl IntoResponse, ServerError> {
+    let end = Utc::now();
+    let start =
+        uptime::ago(end, range.last).map_err(|err| ServerError::BadRequest(format!("{err:#}")))?;
+    let req = incident::ListRequest {
+        limit: INCIDENTS,
+        ..Default::default()
+    };
+    let (cards, incidents) = db
+        .with_conn(move |conn| {
+            let cards = cards(&conn, &config.uptime, start, end)?;
+            let incidents = incident::list(&conn, &req, end)?;
+            Ok((cards, incidents))
+        })
+        .await?;
+    Ok(HtmlTemplate(tmpl::Index {
+        checks: tmpl::Checks {
+            cards,
+            last: range.name(),
+        },
+        incidents: tmpl::Incidents { incidents },
+    }))
+}
+
+/// the check cards over the range
+#[instrument(skip_all)]
+pub(super) async fn handle_checks(
+    State(Server { config, db, .. }): State<Server>,
+    axum_extra::extract::Query(range): axum_extra::extract::Query<Range>,
+) -> Result<impl IntoResponse, ServerError> {
+    let end = Utc::now();
+    let start =
+        uptime::ago(end, range.last).map_err(|err| ServerError::BadRequest(format!("{err:#}")))?;
+    let cards = db
+        .with_conn(move |conn| cards(&conn, &config.uptime, start, end))
+        .await?;
+    Ok(HtmlTemplate(tmpl::Checks {
+        cards,
+        last: range.name(),
+    }))
+}
+
+/// the most recent incidents
+#[instrument(skip_all)]
+pub(super) async fn handle_incidents(
+    State(Server { db, .. }): State<Server>,
+) -> Result<impl IntoResponse, ServerError> {
+    let req = incident::ListRequest {
+        limit: INCIDENTS,
+        ..Default::default()
+    };
+    let incidents = db
+        .with_conn(move |conn| incident::list(&conn, &req, Utc::now()))
+        .await?;
+    Ok(HtmlTemplate(tmpl::Incidents { incidents }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn cards() {
+        let db = db::Db::in_memory().await.unwrap();
+        let conn = db.conn().unwrap();
+        conn.execute_batch(
+            "
+            insert into checks (name, kind) values ('gateway', 'ping'), ('idle', 'http');
+            insert into checks (name, kind, archived_at) values ('old', 'ping', 1);
+            insert into results (check_id, epoch_ms, us, err) values
+                (1, 0, 1000, null),
+                (1, 30000, 3000, null),
+                (1, 60000, null, '\"probe\\n\\nCaused by:\\n    timeout\\n\\nStack backtrace:\"'),
+                (1, 90000, 2000, null),
+                (3, 90000, 2000, null);
+            ",
+        )
+        .unwrap();
+        let start = DateTime::UNIX_EPOCH;
+        let end = start + Duration::from_secs(120);
+        let config = config::Uptime::default();
+        let cards = super::cards(&conn, &config, start, end).unwrap();
+        assert_eq!(cards.len(), 2);
+
+        let gateway = &cards[0];
+        assert_eq!(gateway.name, "gateway");
+        assert_eq!(gateway.up, Some(true));
+        assert_eq!(gateway.last_latency, Some(2.0));
+        assert_eq!(gateway.uptime, Some(50.0));
+        assert_eq!(gateway.errors.len(), 1);
+        assert_eq!(gateway.errors[0].err, "probe: timeout");
+        // the bucket with the error breaks the line
+        assert_eq!(
+            gateway.sparkline,
+            Sparkline {
+                path: String::from("M0.0 13.0 L25.0 1.0 M75.0 7.0"),
+                errors: vec![50.0],
+            }
+        );
+
+        let idle = &cards[1];
+        assert_eq!(
+            (idle.name.as_str(), idle.up, idle.uptime),
+            ("idle", None, None)
+        );
+        assert!(idle.sparkline.path.is_empty());
+    }
+}
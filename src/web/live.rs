@@ -0,0 +1,474 @@
+//! a websocket protocol for live graphs. a client subscribes to checks, optionally asks for a
+//! backfill of recent results from the db, and then receives updates to the bucket of each result
+//! as it is recorded, aggregated at the step it asked for.
+//!
+//! messages are json objects tagged by `type`. the client sends:
+//!
+//! - `{"type": "subscribe", "checks": ["gateway"], "step": "10s"}` adds checks to the
+//!   subscription. no checks subscribes to every check. the step applies to every check.
+//! - `{"type": "unsubscribe", "checks": ["gateway"]}` removes checks. no checks removes all.
+//! - `{"type": "backfill", "last": "15m"}` returns the subscribed checks over the last 15 minutes.
+//!
+//! and the server sends:
+//!
+//! - `{"type": "backfill", "step": 10, "series": [...]}` with series as in `/query`.
+//! - `{"type": "bucket", "name": "gateway", "kind": "ping", "step": 10, "value": {...}}` whenever
+//!   a bucket has changed. the bucket is sent again as more results arrive, so clients should
+//!   replace the value with the same timestamp. buckets which were backfilled at the step of the
+//!   subscription carry on from the backfilled value.
+//! - `{"type": "lagged", "skipped": 3}` when results were dropped because the client fell behind.
+//! - `{"type": "error", "msg": "..."}` when a message could not be handled.
+use super::{
+    describe, query_metrics, resolution, CheckFilter, MetricsQuery, Series, Server, TimeValue,
+};
+use crate::checker::{self, Event};
+use anyhow::{bail, Result};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, instrument};
+
+/// how often changed buckets are sent to the client
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the longest backfill a client may request
+const MAX_BACKFILL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(default)]
+        checks: Vec<String>,
+        #[serde(default, with = "humantime_serde")]
+        step: Option<Duration>,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        checks: Vec<String>,
+    },
+    Backfill {
+        #[serde(with = "humantime_serde")]
+        last: Duration,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerMessage {
+    Backfill {
+        step: u64,
+        series: Vec<Series>,
+    },
+    Bucket {
+        name: String,
+        kind: checker::Kind,
+        step: u64,
+        value: TimeValue,
+    },
+    Lagged {
+        skipped: u64,
+    },
+    Error {
+        msg: String,
+    },
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_ws(State(server): State<Server>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| async move {
+        if let Err(err) = serve(server, socket).await {
+            debug!("websocket closed: {err:#}");
+        }
+    })
+}
+
+async fn serve(server: Server, mut socket: WebSocket) -> Result<()> {
+    let mut events = server.events.subscribe();
+    let mut session = Session::default();
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        let replies = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => session.handle(&server, &text).await,
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(err.into()),
+            },
+            event = events.recv() => match event {
+                Ok(event) => {
+                    session.observe(&event);
+                    continue;
+                }
+                Err(RecvError::Lagged(skipped)) => vec![ServerMessage::Lagged { skipped }],
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = flush.tick() => session.flush(),
+        };
+        for reply in replies {
+            let text = serde_json::to_string(&reply)?;
+            socket.send(Message::Text(text)).await?;
+        }
+    }
+}
+
+/// the state of a single connection
+#[derive(Debug)]
+struct Session {
+    /// subscribes to every check, in addition to the named ones
+    all: bool,
+    checks: BTreeSet<String>,
+    step: Duration,
+    /// the buckets which are still filling up, keyed by check and start in milliseconds
+    buckets: BTreeMap<(String, &'static str, i64), Bucket>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            all: false,
+            checks: BTreeSet::new(),
+            step: Duration::from_secs(1),
+            buckets: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    kind: checker::Kind,
+    count: usize,
+    errs: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    changed: bool,
+}
+
+impl Session {
+    /// handles a message from the client, returning the replies
+    async fn handle(&mut self, server: &Server, text: &str) -> Vec<ServerMessage> {
+        let msg = match serde_json::from_str(text) {
+            Ok(msg) => msg,
+            Err(err) => return error(format!("invalid message: {err}")),
+        };
+        match msg {
+            ClientMessage::Subscribe { checks, step } => {
+                if let Some(step) = step {
+                    if let Err(err) = resolution(Duration::ZERO, Some(step), None) {
+                        return error(format!("{:#}", describe(err)));
+                    }
+                    if step != self.step {
+                        self.buckets.clear();
+                    }
+                    self.step = step;
+                }
+                if checks.is_empty() {
+                    self.all = true;
+                }
+                self.checks.extend(checks);
+                vec![]
+            }
+            ClientMessage::Unsubscribe { checks } => {
+                if checks.is_empty() {
+                    self.all = false;
+                    self.checks.clear();
+                }
+                for check in checks {
+                    self.checks.remove(&check);
+                }
+                self.buckets
+                    .retain(|(name, _, _), _| self.all || self.checks.contains(name));
+                vec![]
+            }
+            ClientMessage::Backfill { last } => match self.backfill(server, last).await {
+                Ok(msg) => vec![msg],
+                Err(err) => error(format!("backfill failed: {err:#}")),
+            },
+        }
+    }
+
+    /// queries the subscribed checks over the last period. the buckets which may still receive
+    /// results are kept, so that the updates to them include the results from before.
+    async fn backfill(&mut self, server: &Server, last: Duration) -> Result<ServerMessage> {
+        if last > MAX_BACKFILL {
+            bail!(
+                "backfill is limited to {}",
+                humantime::format_duration(MAX_BACKFILL)
+            );
+        }
+        if !self.all && self.checks.is_empty() {
+            return Ok(ServerMessage::Backfill {
+                step: self.step.as_secs(),
+                series: vec![],
+            });
+        }
+        let step = resolution(last, Some(self.step), None).map_err(describe)?;
+        let query = MetricsQuery {
+            names: match self.all {
+                true => vec![],
+                false => self.checks.iter().map(|name| escape_glob(name)).collect(),
+            },
+            ..Default::default()
+        };
+        let filter = CheckFilter::new(&query).map_err(describe)?;
+        let end = Utc::now();
+        let start = end - last;
+        let metrics = server
+            .db
+            .with_conn(move |conn| query_metrics(&conn, &query, &filter, start, end, step, &[]))
+            .await?;
+        if step == self.step {
+            let step = step.as_millis() as i64;
+            let oldest = end.timestamp_millis().div_euclid(step) * step - step;
+            for series in &metrics.series {
+                for value in &series.values {
+                    let start = value.ts.timestamp_millis();
+                    if start < oldest || value.count == 0 {
+                        continue;
+                    }
+                    let key = (series.name.clone(), series.kind.as_str(), start);
+                    let ok = value.count - value.errs;
+                    self.buckets.insert(
+                        key,
+                        Bucket {
+                            kind: series.kind,
+                            count: value.count,
+                            errs: value.errs,
+                            sum: value.avg.map_or(0.0, |avg| avg * ok as f64),
+                            min: value.min,
+                            max: value.max,
+                            changed: false,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(ServerMessage::Backfill {
+            step: step.as_secs(),
+            series: metrics.series,
+        })
+    }
+
+    /// adds a result to its bucket if the check is subscribed
+    fn observe(&mut self, event: &Event) {
+        if !self.all && !self.checks.contains(&event.name) {
+            return;
+        }
+        let step = self.step.as_millis() as i64;
+        let start = event.ts.timestamp_millis().div_euclid(step) * step;
+        let key = (event.name.clone(), event.kind.as_str(), start);
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            kind: event.kind,
+            count: 0,
+            errs: 0,
+            sum: 0.0,
+            min: None,
+            max: None,
+            changed: false,
+        });
+        bucket.count += 1;
+        bucket.changed = true;
+        match event.latency {
+            Some(latency) => {
+                bucket.sum += latency;
+                bucket.min = Some(bucket.min.map_or(latency, |min| min.min(latency)));
+                bucket.max = Some(bucket.max.map_or(latency, |max| max.max(latency)));
+            }
+            None => bucket.errs += 1,
+        }
+    }
+
+    /// returns the buckets which changed since the last flush, and forgets the buckets which
+    /// are too old to receive more results
+    fn flush(&mut self) -> Vec<ServerMessage> {
+        let step = self.step.as_secs();
+        let mut out = vec![];
+        for ((name, _, start), bucket) in &mut self.buckets {
+            if !bucket.changed {
+                continue;
+            }
+            bucket.changed = false;
+            let ok = bucket.count - bucket.errs;
+            out.push(ServerMessage::Bucket {
+                name: name.clone(),
+                kind: bucket.kind,
+                step,
+                value: TimeValue {
+                    avg: (ok > 0).then(|| bucket.sum / ok as f64),
+                    min: bucket.min,
+                    max: bucket.max,
+                    count: bucket.count,
+                    errs: bucket.errs,
+                    ..TimeValue::empty(DateTime::from_timestamp_millis(*start).unwrap_or_default())
+                },
+            });
+        }
+        // probes may take a while to finish, so the previous bucket is kept around too
+        let oldest = self
+            .buckets
+            .keys()
+            .map(|(_, _, start)| *start)
+            .max()
+            .unwrap_or_default()
+            - self.step.as_millis() as i64;
+        self.buckets.retain(|(_, _, start), _| *start >= oldest);
+        out
+    }
+}
+
+fn error(msg: String) -> Vec<ServerMessage> {
+    vec![ServerMessage::Error { msg }]
+}
+
+/// escapes a check name so that it only matches itself in a glob
+fn escape_glob(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '*' | '?' | '[' => format!("[{c}]"),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, db};
+
+    fn event(name: &str, ms: i64, latency: Option<f64>) -> Event {
+        Event {
+            name: name.to_string(),
+            kind: checker::Kind::Ping,
+            ts: DateTime::from_timestamp_millis(ms).unwrap(),
+            latency,
+            err: latency.is_none().then(|| String::from("timeout")),
+        }
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let db = db::Db::in_memory().await.unwrap();
+        // the checker archives the checks which are not in its config, so it is created first
+        let config = Config::default();
+        let checker = checker::Checker::new(db.clone(), &config).await.unwrap();
+        let server = Server::new(&config, db.clone(), checker, Default::default()).unwrap();
+        let now = Utc::now().timestamp_millis();
+        db.conn()
+            .unwrap()
+            .execute(
+                "
+                insert into checks (name, kind) values ('gateway', 'ping'), ('isp*', 'ping');
+                ",
+                [],
+            )
+            .unwrap();
+        db.conn()
+            .unwrap()
+            .execute(
+                "insert into results (check_id, epoch_ms, us) values (1, ?1, 1000), (2, ?1, 1000)",
+                [now - 60_000],
+            )
+            .unwrap();
+        let mut session = Session::default();
+
+        let replies = session.handle(&server, r#"{"type": "subscribe", "step": "0.5s"}"#);
+        assert!(matches!(&replies.await[..], [ServerMessage::Error { .. }]));
+        let replies = session.handle(&server, r#"{"type": "publish"}"#);
+        assert!(matches!(&replies.await[..], [ServerMessage::Error { .. }]));
+        let replies = session.handle(
+            &server,
+            r#"{"type": "subscribe", "checks": ["isp*"], "step": "10s"}"#,
+        );
+        assert!(replies.await.is_empty());
+
+        // the name is not treated as a pattern, so "isp*" does not match any other check
+        let replies = session.handle(&server, r#"{"type": "backfill", "last": "5m"}"#);
+        match &replies.await[..] {
+            [ServerMessage::Backfill { step: 10, series }] => {
+                assert_eq!(series.len(), 1);
+                assert_eq!(series[0].name, "isp*");
+                assert_eq!(series[0].values[0].count, 1);
+            }
+            replies => panic!("unexpected replies {replies:?}"),
+        }
+
+        session.observe(&event("gateway", 21_000, Some(1.0)));
+        session.observe(&event("isp*", 21_000, Some(1.0)));
+        session.observe(&event("isp*", 25_000, Some(3.0)));
+        session.observe(&event("isp*", 29_000, None));
+        let replies = session.flush();
+        match &replies[..] {
+            [ServerMessage::Bucket {
+                name,
+                step: 10,
+                value,
+                ..
+            }] => {
+                assert_eq!(name, "isp*");
+                assert_eq!(value.ts.timestamp_millis(), 20_000);
+                assert_eq!((value.count, value.errs), (3, 1));
+                assert_eq!(
+                    (value.avg, value.min, value.max),
+                    (Some(2.0), Some(1.0), Some(3.0))
+                );
+            }
+            replies => panic!("unexpected replies {replies:?}"),
+        }
+        // unchanged buckets are not sent again
+        assert!(session.flush().is_empty());
+
+        // a bucket two steps later means that the first one is complete
+        session.observe(&event("isp*", 41_000, Some(1.0)));
+        assert_eq!(session.flush().len(), 1);
+        assert_eq!(session.buckets.len(), 1);
+
+        session.handle(&server, r#"{"type": "unsubscribe"}"#).await;
+        session.observe(&event("isp*", 42_000, Some(1.0)));
+        assert!(session.flush().is_empty());
+
+        // a bucket which was backfilled carries on from the backfilled results
+        let now = Utc::now().timestamp_millis();
+        db.conn()
+            .unwrap()
+            .execute(
+                "insert into results (check_id, epoch_ms, us) values (1, ?1, 3000)",
+                [now],
+            )
+            .unwrap();
+        let mut session = Session::default();
+        let replies = session.handle(
+            &server,
+            r#"{"type": "subscribe", "checks": ["gateway"], "step": "1m"}"#,
+        );
+        assert!(replies.await.is_empty());
+        let replies = session.handle(&server, r#"{"type": "backfill", "last": "5m"}"#);
+        assert!(matches!(
+            &replies.await[..],
+            [ServerMessage::Backfill { .. }]
+        ));
+        assert!(session.flush().is_empty());
+        session.observe(&event("gateway", now, Some(1.0)));
+        match &session.flush()[..] {
+            [ServerMessage::Bucket { value, .. }] => {
+                assert_eq!((value.count, value.errs), (2, 0));
+                assert_eq!(
+                    (value.avg, value.min, value.max),
+                    (Some(2.0), Some(1.0), Some(3.0))
+                );
+            }
+            replies => panic!("unexpected replies {replies:?}"),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! serves the public status page. it is available on the main listener, and on its own listener
+//! when one is configured, where nothing else is served.
+use super::{assets, tmpl, HtmlTemplate, Server, ServerError};
+use crate::status;
+use axum::{extract::State, response::IntoResponse, routing, Json, Router};
+use chrono::Utc;
+use tracing::instrument;
+
+/// the routes of the status page listener
+pub(super) fn router() -> Router<Server> {
+    Router::new()
+        .route("/", routing::get(handle_status))
+        .route("/status.json", routing::get(handle_status_json))
+        .fallback(assets::handle_asset)
+}
+
+async fn fetch(Server { config, db, .. }: Server) -> Result<status::Status, ServerError> {
+    let status = db
+        .with_conn(move |conn| status::status(&conn, &config, Utc::now()))
+        .await?;
+    Ok(status)
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_status(
+    State(server): State<Server>,
+) -> Result<impl IntoResponse, ServerError> {
+    let status = fetch(server).await?;
+    Ok(HtmlTemplate(tmpl::Status { status }))
+}
+
+#[instrument(skip_all)]
+pub(super) async fn handle_status_json(
+    State(server): State<Server>,
+) -> Result<Json<status::Status>, ServerError> {
+    Ok(Json(fetch(server).await?))
+}